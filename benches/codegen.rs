@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: Unlicense
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// Builds an expression with `terms` additions of small products.
+fn expression(terms: usize) -> String {
+    (0..terms)
+        .map(|i| format!("{} * {}", i % 7, i % 11))
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+fn codegen_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bench_compile");
+    for terms in [10, 100, 1000] {
+        let source = expression(terms);
+        group.bench_with_input(BenchmarkId::from_parameter(terms), &source, |b, source| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| bonsai::driver::bench_compile(source).expect("failed to compile"))
+                    .sum()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, codegen_throughput);
+criterion_main!(benches);
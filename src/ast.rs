@@ -1,7 +1,18 @@
 // SPDX-License-Identifier: Unlicense
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, ops::Range};
+
+/// A byte range of the source.
+pub type Span = Range<usize>;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum LitKind {
     IntLit(i64),
+    /// `true` or `false`. Bools are a type of their own: `sema::check` rejects them where
+    /// an integer is expected, and integers where a bool is expected.
+    BoolLit(bool),
+    /// Only meaningful as a builtin argument, e.g. `env("NAME")`.
+    StrLit(String),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -10,19 +21,734 @@ pub enum BiOpKind {
     Sub,
     Mul,
     Div,
+    Rem,
+    /// Exponentiation, right-associative and binding tighter than the prefix operators:
+    /// `-2 ** 2` is `-(2 ** 2)`. A negative exponent gives 0.
+    Pow,
+    /// Comparisons, evaluating to bools. They bind looser than the bitwise operators and
+    /// cannot be chained: `1 < 2 < 3` is rejected.
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Bitwise and, binding tighter than `^`.
+    BitAnd,
+    /// Bitwise exclusive or, binding tighter than `|`.
+    BitXor,
+    /// Bitwise or, binding looser than the other bitwise operators but tighter than `&&`.
+    BitOr,
+    /// Short-circuiting logical and of two bools. Like conditions, the operands must be
+    /// bools rather than integers.
+    And,
+    /// Short-circuiting logical or, evaluating to a bool; see [`BiOpKind::And`].
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnOpKind {
+    /// Logical not of a bool.
+    Not,
+    /// Negation, binding tighter than `*`: `-2 * 3` is `(-2) * 3`.
+    Neg,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NodeKind {
     Lit(LitKind),
     Paren(Id),
+    UnOp(UnOpKind, Id),
     BiOp(BiOpKind, Id, Id),
+    Call(String, Vec<Id>),
+    /// Top-level statements separated by `;`.
+    Seq(Vec<Id>),
+    /// `let name = value`, or `let mut name = value` when the flag is set; only allowed as
+    /// a statement. `let a = 1, b = 2` parses as one of these per binding.
+    Let(String, bool, Id),
+    /// `name = value`, assigning to a `let mut` binding; only allowed as a statement.
+    Assign(String, Id),
+    /// A name bound by an earlier `let` or a function parameter.
+    Var(String),
+    /// `fn name(params) = body`; only allowed as a statement. The body sees the parameters
+    /// and the functions defined so far, including this one, but no `let` bindings. The
+    /// parameters are integers, and calls have the type of the body.
+    Fn(String, Vec<String>, Id),
+    /// `if cond { then } else { otherwise }`, evaluating only the branch the bool condition
+    /// picks. Both branches have the same type. Without an `else` it evaluates to 0, or
+    /// `false` for a bool branch, when the condition is false.
+    If(Id, Id, Option<Id>),
+    /// `for name in start..end { body }`, running the body statements with `name` bound
+    /// to each integer from `start` up to but excluding `end`, which is evaluated once.
+    /// Only allowed as a statement; the body's statements are not printed.
+    For(String, Id, Id, Vec<Id>),
+    /// `break`, ending the innermost loop around it.
+    Break,
+    /// `continue`, moving the innermost loop around it on to its next iteration.
+    Continue,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     pub kind: NodeKind,
+    /// The extent of the node's tokens, excluding surrounding whitespace and comments.
+    pub span: Span,
 }
 
 pub type Id = id_arena::Id<Node>;
 pub type Arena = id_arena::Arena<Node>;
+
+/// Renders the tree rooted at `root` as an S-expression, e.g. `(Mul (Lit 6) (Lit 7))`.
+pub fn dump(arena: &Arena, root: Id) -> Result<String> {
+    dump_impl(arena, root, false)
+}
+
+/// Like [`dump`], but tags every node with its byte range, e.g.
+/// `(Mul@0..5 (Lit@0..1 6) (Lit@4..5 7))`.
+pub fn dump_with_spans(arena: &Arena, root: Id) -> Result<String> {
+    dump_impl(arena, root, true)
+}
+
+fn dump_impl(arena: &Arena, id: Id, spans: bool) -> Result<String> {
+    let node = arena
+        .get(id)
+        .ok_or(anyhow!("failed to get ast node from arena"))?;
+    let head = |name: &str| match spans {
+        true => format!("{}@{}..{}", name, node.span.start, node.span.end),
+        false => name.to_string(),
+    };
+    let mut out = match &node.kind {
+        NodeKind::Lit(LitKind::IntLit(i)) => format!("({} {}", head("Lit"), i),
+        NodeKind::Lit(LitKind::StrLit(s)) => format!("({} {:?}", head("Lit"), s),
+        NodeKind::Lit(LitKind::BoolLit(b)) => format!("({} {}", head("Lit"), b),
+        NodeKind::Paren(_) => format!("({}", head("Paren")),
+        NodeKind::UnOp(op, _) => format!("({}", head(&format!("{:?}", op))),
+        NodeKind::BiOp(op, _, _) => format!("({}", head(&format!("{:?}", op))),
+        NodeKind::Call(name, _) => format!("({} {}", head("Call"), name),
+        NodeKind::Seq(_) => format!("({}", head("Seq")),
+        NodeKind::Let(name, false, _) => format!("({} {}", head("Let"), name),
+        NodeKind::Let(name, true, _) => format!("({} mut {}", head("Let"), name),
+        NodeKind::Assign(name, _) => format!("({} {}", head("Assign"), name),
+        NodeKind::Var(name) => format!("({} {}", head("Var"), name),
+        NodeKind::Fn(name, params, _) => format!("({} {} ({})", head("Fn"), name, params.join(" ")),
+        NodeKind::If(..) => format!("({}", head("If")),
+        NodeKind::For(name, ..) => format!("({} {}", head("For"), name),
+        NodeKind::Break => format!("({}", head("Break")),
+        NodeKind::Continue => format!("({}", head("Continue")),
+    };
+    for child in children(&node.kind) {
+        out += " ";
+        out += &dump_impl(arena, child, spans)?;
+    }
+    Ok(out + ")")
+}
+
+/// Returns the nodes directly below a node of `kind`, in source order.
+pub fn children(kind: &NodeKind) -> Vec<Id> {
+    match kind {
+        NodeKind::Lit(_) | NodeKind::Var(_) | NodeKind::Break | NodeKind::Continue => vec![],
+        NodeKind::Paren(e)
+        | NodeKind::UnOp(_, e)
+        | NodeKind::Let(_, _, e)
+        | NodeKind::Assign(_, e)
+        | NodeKind::Fn(_, _, e) => vec![*e],
+        NodeKind::BiOp(_, lhs, rhs) => vec![*lhs, *rhs],
+        NodeKind::Call(_, items) | NodeKind::Seq(items) => items.clone(),
+        NodeKind::If(cond, then, otherwise) => {
+            [*cond, *then].into_iter().chain(*otherwise).collect()
+        }
+        NodeKind::For(_, start, end, body) => {
+            [*start, *end].into_iter().chain(body.clone()).collect()
+        }
+    }
+}
+
+fn precedence(op: &BiOpKind) -> u8 {
+    match op {
+        BiOpKind::Or => 1,
+        BiOpKind::And => 2,
+        BiOpKind::Eq | BiOpKind::Ne | BiOpKind::Lt | BiOpKind::Le | BiOpKind::Gt | BiOpKind::Ge => {
+            3
+        }
+        BiOpKind::BitOr => 4,
+        BiOpKind::BitXor => 5,
+        BiOpKind::BitAnd => 6,
+        BiOpKind::Add | BiOpKind::Sub => 7,
+        BiOpKind::Mul | BiOpKind::Div | BiOpKind::Rem => 8,
+        BiOpKind::Pow => 10,
+    }
+}
+
+/// Binds tighter than every binary operator but `**`.
+const PREFIX_PRECEDENCE: u8 = 9;
+
+fn symbol(op: &BiOpKind) -> &'static str {
+    match op {
+        BiOpKind::Add => "+",
+        BiOpKind::Sub => "-",
+        BiOpKind::Mul => "*",
+        BiOpKind::Div => "/",
+        BiOpKind::Rem => "%",
+        BiOpKind::Pow => "**",
+        BiOpKind::Eq => "==",
+        BiOpKind::Ne => "!=",
+        BiOpKind::Lt => "<",
+        BiOpKind::Le => "<=",
+        BiOpKind::Gt => ">",
+        BiOpKind::Ge => ">=",
+        BiOpKind::BitAnd => "&",
+        BiOpKind::BitXor => "^",
+        BiOpKind::BitOr => "|",
+        BiOpKind::And => "&&",
+        BiOpKind::Or => "||",
+    }
+}
+
+/// Whether `op` is one of the comparisons, which do not associate.
+pub fn is_comparison(op: &BiOpKind) -> bool {
+    matches!(
+        op,
+        BiOpKind::Eq | BiOpKind::Ne | BiOpKind::Lt | BiOpKind::Le | BiOpKind::Gt | BiOpKind::Ge
+    )
+}
+
+/// Writes `name` so that it parses as an identifier, prefixing keywords with `r#`.
+fn identifier(name: &str) -> String {
+    if crate::parser::KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Renders the tree rooted at `root` as source that parses back to the same tree, up to
+/// the parentheses added where precedence requires them.
+pub fn format(arena: &Arena, root: Id) -> Result<String> {
+    format_node(arena, root, 0)
+}
+
+/// `min_precedence` is the loosest binary operator that may appear without parentheses.
+fn format_node(arena: &Arena, id: Id, min_precedence: u8) -> Result<String> {
+    let kind = &arena
+        .get(id)
+        .ok_or(anyhow!("failed to get ast node from arena"))?
+        .kind;
+    let list = |items: &[Id], separator: &str| -> Result<String> {
+        Ok(items
+            .iter()
+            .map(|&item| format_node(arena, item, 0))
+            .collect::<Result<Vec<_>>>()?
+            .join(separator))
+    };
+    Ok(match kind {
+        NodeKind::Lit(LitKind::IntLit(i)) if *i < 0 => {
+            anyhow::bail!("negative literal {} cannot be written in source", i)
+        }
+        NodeKind::Lit(LitKind::IntLit(i)) => i.to_string(),
+        NodeKind::Lit(LitKind::BoolLit(b)) => b.to_string(),
+        NodeKind::Lit(LitKind::StrLit(s)) => format!("\"{}\"", s),
+        NodeKind::Paren(e) => format!("({})", format_node(arena, *e, 0)?),
+        NodeKind::UnOp(op, e) => {
+            let symbol = match op {
+                UnOpKind::Not => "!",
+                UnOpKind::Neg => "-",
+            };
+            let text = format!("{}{}", symbol, format_node(arena, *e, PREFIX_PRECEDENCE)?);
+            if PREFIX_PRECEDENCE < min_precedence {
+                format!("({})", text)
+            } else {
+                text
+            }
+        }
+        NodeKind::BiOp(op, lhs, rhs) => {
+            // an equal operator needs parentheses on the side it does not associate to
+            let precedence = precedence(op);
+            let (lhs_precedence, rhs_precedence) = match op {
+                BiOpKind::Pow => (precedence + 1, precedence),
+                _ if is_comparison(op) => (precedence + 1, precedence + 1),
+                _ => (precedence, precedence + 1),
+            };
+            let text = format!(
+                "{} {} {}",
+                format_node(arena, *lhs, lhs_precedence)?,
+                symbol(op),
+                format_node(arena, *rhs, rhs_precedence)?
+            );
+            if precedence < min_precedence {
+                format!("({})", text)
+            } else {
+                text
+            }
+        }
+        NodeKind::Call(name, args) => format!("{}({})", identifier(name), list(args, ", ")?),
+        NodeKind::Seq(stmts) => list(stmts, "; ")?,
+        NodeKind::Let(name, mutable, value) => format!(
+            "let {}{} = {}",
+            if *mutable { "mut " } else { "" },
+            identifier(name),
+            format_node(arena, *value, 0)?
+        ),
+        NodeKind::Assign(name, value) => {
+            format!("{} = {}", identifier(name), format_node(arena, *value, 0)?)
+        }
+        NodeKind::Var(name) => identifier(name),
+        NodeKind::Break => "break".to_string(),
+        NodeKind::Continue => "continue".to_string(),
+        NodeKind::Fn(name, params, body) => format!(
+            "fn {}({}) = {}",
+            identifier(name),
+            params
+                .iter()
+                .map(|param| identifier(param))
+                .collect::<Vec<_>>()
+                .join(", "),
+            format_node(arena, *body, 0)?
+        ),
+        NodeKind::If(cond, then, otherwise) => {
+            let text = format!(
+                "if {} {{ {} }}",
+                format_node(arena, *cond, 0)?,
+                format_node(arena, *then, 0)?
+            );
+            match otherwise.map(|e| (e, &arena[e].kind)) {
+                None => text,
+                Some((e, NodeKind::If(..))) => {
+                    format!("{} else {}", text, format_node(arena, e, 0)?)
+                }
+                Some((e, _)) => format!("{} else {{ {} }}", text, format_node(arena, e, 0)?),
+            }
+        }
+        NodeKind::For(name, start, end, body) => {
+            let body = match body.is_empty() {
+                true => "{}".to_string(),
+                false => format!("{{ {} }}", list(body, "; ")?),
+            };
+            format!(
+                "for {} in {}..{} {}",
+                identifier(name),
+                format_node(arena, *start, 0)?,
+                format_node(arena, *end, 0)?,
+                body
+            )
+        }
+    })
+}
+
+/// Constant-evaluates the tree rooted at `root` with the same wrapping semantics as
+/// `ir::eval`, without lowering it first.
+///
+/// Calls are rejected since builtins only get their meaning during lowering, and so are
+/// loops since the scopes of their bindings are only resolved then.
+pub fn eval(arena: &Arena, root: Id) -> Result<i64> {
+    eval_impl(arena, root, &mut HashMap::new())
+}
+
+/// `bindings` holds the values of the names bound so far.
+fn eval_impl(arena: &Arena, root: Id, bindings: &mut HashMap<String, i64>) -> Result<i64> {
+    let kind = &arena
+        .get(root)
+        .ok_or(anyhow!("failed to get ast from arena"))?
+        .kind;
+    match kind {
+        NodeKind::Lit(LitKind::IntLit(i)) => Ok(*i),
+        NodeKind::Lit(LitKind::BoolLit(b)) => Ok(*b as i64),
+        NodeKind::Lit(LitKind::StrLit(_)) => anyhow::bail!("string literal is not an integer"),
+        NodeKind::Paren(e) => eval_impl(arena, *e, bindings),
+        NodeKind::UnOp(UnOpKind::Not, e) => Ok((eval_impl(arena, *e, bindings)? == 0) as i64),
+        NodeKind::UnOp(UnOpKind::Neg, e) => Ok(eval_impl(arena, *e, bindings)?.wrapping_neg()),
+        NodeKind::BiOp(BiOpKind::And, lhs, rhs) => Ok((eval_impl(arena, *lhs, bindings)? != 0
+            && eval_impl(arena, *rhs, bindings)? != 0)
+            as i64),
+        NodeKind::BiOp(BiOpKind::Or, lhs, rhs) => Ok((eval_impl(arena, *lhs, bindings)? != 0
+            || eval_impl(arena, *rhs, bindings)? != 0)
+            as i64),
+        NodeKind::BiOp(op, lhs, rhs) => {
+            let lhs = eval_impl(arena, *lhs, bindings)?;
+            let rhs = eval_impl(arena, *rhs, bindings)?;
+            match op {
+                BiOpKind::Add => Ok(lhs.wrapping_add(rhs)),
+                BiOpKind::Sub => Ok(lhs.wrapping_sub(rhs)),
+                BiOpKind::Mul => Ok(lhs.wrapping_mul(rhs)),
+                BiOpKind::Div | BiOpKind::Rem if rhs == 0 => anyhow::bail!("division by zero"),
+                BiOpKind::Div => Ok(lhs.wrapping_div(rhs)),
+                BiOpKind::Rem => Ok(lhs.wrapping_rem(rhs)),
+                BiOpKind::Pow => Ok(crate::ir::overflowing_pow(lhs, rhs).0),
+                BiOpKind::Eq => Ok((lhs == rhs) as i64),
+                BiOpKind::Ne => Ok((lhs != rhs) as i64),
+                BiOpKind::Lt => Ok((lhs < rhs) as i64),
+                BiOpKind::Le => Ok((lhs <= rhs) as i64),
+                BiOpKind::Gt => Ok((lhs > rhs) as i64),
+                BiOpKind::Ge => Ok((lhs >= rhs) as i64),
+                BiOpKind::BitAnd => Ok(lhs & rhs),
+                BiOpKind::BitXor => Ok(lhs ^ rhs),
+                BiOpKind::BitOr => Ok(lhs | rhs),
+                BiOpKind::And | BiOpKind::Or => unreachable!(),
+            }
+        }
+        NodeKind::Call(name, _) => anyhow::bail!("{}() cannot be evaluated at compile time", name),
+        // only calls need the definition
+        NodeKind::Fn(..) => Ok(0),
+        NodeKind::For(..) | NodeKind::Break | NodeKind::Continue => {
+            anyhow::bail!("loops cannot be evaluated before lowering")
+        }
+        NodeKind::If(cond, then, otherwise) => {
+            match (eval_impl(arena, *cond, bindings)?, otherwise) {
+                (0, None) => Ok(0),
+                (0, Some(otherwise)) => eval_impl(arena, *otherwise, bindings),
+                _ => eval_impl(arena, *then, bindings),
+            }
+        }
+        NodeKind::Let(name, _, value) => {
+            let value = eval_impl(arena, *value, bindings)?;
+            bindings.insert(name.clone(), value);
+            Ok(0)
+        }
+        NodeKind::Assign(name, value) => {
+            let value = eval_impl(arena, *value, bindings)?;
+            *bindings
+                .get_mut(name)
+                .ok_or(anyhow!("unknown variable '{}'", name))? = value;
+            Ok(0)
+        }
+        NodeKind::Var(name) => bindings
+            .get(name)
+            .copied()
+            .ok_or(anyhow!("unknown variable '{}'", name)),
+        NodeKind::Seq(stmts) => {
+            let mut sum = 0i64;
+            for &stmt in stmts {
+                sum = sum.wrapping_add(eval_impl(arena, stmt, bindings)?);
+            }
+            Ok(sum)
+        }
+    }
+}
+
+/// Generators of arbitrary syntax trees for property tests.
+#[cfg(test)]
+pub(crate) mod arbitrary {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// An AST that owns its children, for generating arbitrary trees.
+    #[derive(Debug, Clone)]
+    pub enum Tree {
+        Lit(LitKind),
+        Paren(Box<Tree>),
+        Not(Box<Tree>),
+        Neg(Box<Tree>),
+        BiOp(BiOpKind, Box<Tree>, Box<Tree>),
+        Call(String, Vec<Tree>),
+    }
+
+    impl Tree {
+        pub fn alloc(self, arena: &mut Arena) -> Id {
+            let kind = match self {
+                Tree::Lit(lit) => NodeKind::Lit(lit),
+                Tree::Paren(e) => NodeKind::Paren(e.alloc(arena)),
+                Tree::Not(e) => NodeKind::UnOp(UnOpKind::Not, e.alloc(arena)),
+                Tree::Neg(e) => NodeKind::UnOp(UnOpKind::Neg, e.alloc(arena)),
+                Tree::BiOp(op, lhs, rhs) => NodeKind::BiOp(op, lhs.alloc(arena), rhs.alloc(arena)),
+                Tree::Call(name, args) => {
+                    NodeKind::Call(name, args.into_iter().map(|a| a.alloc(arena)).collect())
+                }
+            };
+            arena.alloc(Node { kind, span: 0..0 })
+        }
+    }
+
+    fn bi_op() -> impl Strategy<Value = BiOpKind> + Clone {
+        prop_oneof![
+            Just(BiOpKind::Add),
+            Just(BiOpKind::Sub),
+            Just(BiOpKind::Mul),
+            Just(BiOpKind::Div),
+            Just(BiOpKind::Rem),
+            Just(BiOpKind::Pow),
+            Just(BiOpKind::Eq),
+            Just(BiOpKind::Ne),
+            Just(BiOpKind::Lt),
+            Just(BiOpKind::Le),
+            Just(BiOpKind::Gt),
+            Just(BiOpKind::Ge),
+            Just(BiOpKind::BitAnd),
+            Just(BiOpKind::BitXor),
+            Just(BiOpKind::BitOr),
+            Just(BiOpKind::And),
+            Just(BiOpKind::Or),
+        ]
+    }
+
+    /// Any tree the grammar can express, whether or not it lowers to IR.
+    pub fn tree() -> impl Strategy<Value = Tree> {
+        let leaf = prop_oneof![
+            (0..=i64::MAX).prop_map(|i| Tree::Lit(LitKind::IntLit(i))),
+            "[a-z ]{0,8}".prop_map(|s| Tree::Lit(LitKind::StrLit(s))),
+            any::<bool>().prop_map(|b| Tree::Lit(LitKind::BoolLit(b))),
+        ];
+        leaf.prop_recursive(6, 64, 3, |inner| {
+            prop_oneof![
+                inner.clone().prop_map(|e| Tree::Paren(Box::new(e))),
+                inner.clone().prop_map(|e| Tree::Not(Box::new(e))),
+                inner.clone().prop_map(|e| Tree::Neg(Box::new(e))),
+                (bi_op(), inner.clone(), inner.clone()).prop_map(|(op, lhs, rhs)| Tree::BiOp(
+                    op,
+                    Box::new(lhs),
+                    Box::new(rhs)
+                )),
+                // may produce keywords, which have to be written as raw identifiers
+                ("[a-z_][a-z0-9_]{0,5}", prop::collection::vec(inner, 0..3))
+                    .prop_map(|(name, args)| Tree::Call(name, args)),
+            ]
+        })
+    }
+
+    fn int_op() -> impl Strategy<Value = BiOpKind> + Clone {
+        prop_oneof![
+            Just(BiOpKind::Add),
+            Just(BiOpKind::Sub),
+            Just(BiOpKind::Mul),
+            Just(BiOpKind::Div),
+            Just(BiOpKind::Rem),
+            Just(BiOpKind::Pow),
+            Just(BiOpKind::BitAnd),
+            Just(BiOpKind::BitXor),
+            Just(BiOpKind::BitOr),
+        ]
+    }
+
+    fn compare_op() -> impl Strategy<Value = BiOpKind> + Clone {
+        prop_oneof![
+            Just(BiOpKind::Eq),
+            Just(BiOpKind::Ne),
+            Just(BiOpKind::Lt),
+            Just(BiOpKind::Le),
+            Just(BiOpKind::Gt),
+            Just(BiOpKind::Ge),
+        ]
+    }
+
+    fn logic_op() -> impl Strategy<Value = BiOpKind> + Clone {
+        prop_oneof![
+            Just(BiOpKind::Eq),
+            Just(BiOpKind::Ne),
+            Just(BiOpKind::And),
+            Just(BiOpKind::Or),
+        ]
+    }
+
+    fn bi_op_tree((op, lhs, rhs): (BiOpKind, Tree, Tree)) -> Tree {
+        Tree::BiOp(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    /// Trees of integers, booleans, operators and `abs` that pass `sema::check`, lower to
+    /// IR and need no input. They may still divide by zero.
+    pub fn arithmetic_tree() -> impl Strategy<Value = Tree> {
+        // mostly small numbers, so that not every expression overflows
+        let int_leaf = prop_oneof![
+            3 => (0..100i64).prop_map(|i| Tree::Lit(LitKind::IntLit(i))),
+            1 => (0..=i64::MAX).prop_map(|i| Tree::Lit(LitKind::IntLit(i))),
+        ]
+        .boxed();
+        let bool_leaf = any::<bool>()
+            .prop_map(|b| Tree::Lit(LitKind::BoolLit(b)))
+            .boxed();
+        // each round nests the operators one level deeper, keeping bools and integers apart
+        let (mut ints, mut bools) = (int_leaf.clone(), bool_leaf.clone());
+        for _ in 0..4 {
+            let next_ints = prop_oneof![
+                2 => int_leaf.clone(),
+                1 => ints.clone().prop_map(|e| Tree::Paren(Box::new(e))),
+                1 => ints.clone().prop_map(|e| Tree::Neg(Box::new(e))),
+                3 => (int_op(), ints.clone(), ints.clone()).prop_map(bi_op_tree),
+                1 => ints.clone().prop_map(|e| Tree::Call("abs".to_string(), vec![e])),
+            ]
+            .boxed();
+            let next_bools = prop_oneof![
+                1 => bool_leaf.clone(),
+                1 => bools.clone().prop_map(|e| Tree::Paren(Box::new(e))),
+                1 => bools.clone().prop_map(|e| Tree::Not(Box::new(e))),
+                2 => (compare_op(), ints.clone(), ints.clone()).prop_map(bi_op_tree),
+                2 => (logic_op(), bools.clone(), bools.clone()).prop_map(bi_op_tree),
+            ]
+            .boxed();
+            ints = next_ints;
+            bools = next_bools;
+        }
+        prop_oneof![3 => ints, 1 => bools]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use proptest::prelude::*;
+
+    fn eval_source(src: &str) -> Result<i64> {
+        let (arena, root) = parser::parse(src)?;
+        eval(&arena, root)
+    }
+
+    /// The tree as an S-expression without its parentheses.
+    fn shape(arena: &Arena, id: Id) -> String {
+        let shapes = |items: &[Id]| {
+            items
+                .iter()
+                .map(|&item| format!(" {}", shape(arena, item)))
+                .collect::<String>()
+        };
+        match &arena[id].kind {
+            NodeKind::Lit(LitKind::IntLit(i)) => i.to_string(),
+            NodeKind::Lit(LitKind::StrLit(s)) => format!("{:?}", s),
+            NodeKind::Lit(LitKind::BoolLit(b)) => b.to_string(),
+            NodeKind::Paren(e) => shape(arena, *e),
+            NodeKind::UnOp(op, e) => format!("({:?} {})", op, shape(arena, *e)),
+            NodeKind::BiOp(op, lhs, rhs) => {
+                format!("({:?} {} {})", op, shape(arena, *lhs), shape(arena, *rhs))
+            }
+            NodeKind::Call(name, args) => format!("({}{})", name, shapes(args)),
+            NodeKind::Seq(stmts) => format!("(Seq{})", shapes(stmts)),
+            NodeKind::Let(name, mutable, value) => {
+                format!("(Let {} {} {})", mutable, name, shape(arena, *value))
+            }
+            NodeKind::Assign(name, value) => format!("(Assign {} {})", name, shape(arena, *value)),
+            NodeKind::Var(name) => name.clone(),
+            NodeKind::Break => "Break".to_string(),
+            NodeKind::Continue => "Continue".to_string(),
+            NodeKind::Fn(name, params, body) => {
+                format!("(Fn {} {:?} {})", name, params, shape(arena, *body))
+            }
+            NodeKind::If(cond, then, otherwise) => {
+                let otherwise = otherwise.map(|e| format!(" {}", shape(arena, e)));
+                let (cond, then) = (shape(arena, *cond), shape(arena, *then));
+                format!("(If {} {}{})", cond, then, otherwise.unwrap_or_default())
+            }
+            NodeKind::For(name, start, end, body) => {
+                let (start, end) = (shape(arena, *start), shape(arena, *end));
+                format!("(For {} {} {}{})", name, start, end, shapes(body))
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn format_should_round_trip_through_parser(
+            stmts in prop::collection::vec(arbitrary::tree(), 1..4)
+        ) {
+            let mut arena = Arena::new();
+            let mut ids: Vec<Id> = stmts.into_iter().map(|t| t.alloc(&mut arena)).collect();
+            let root = match ids.len() {
+                1 => ids.remove(0),
+                _ => arena.alloc(Node { kind: NodeKind::Seq(ids), span: 0..0 }),
+            };
+            let source = format(&arena, root).unwrap();
+            let (parsed, parsed_root) = parser::parse(&source)
+                .map_err(|e| TestCaseError::fail(format!("{}: {}", source, e)))?;
+            prop_assert_eq!(shape(&parsed, parsed_root), shape(&arena, root), "{}", source);
+        }
+    }
+
+    #[test]
+    fn dump_with_spans_should_show_token_ranges() -> Result<()> {
+        let src = "6 * 7";
+        let (arena, root) = parser::parse(src)?;
+        let dump = dump_with_spans(&arena, root)?;
+        assert_eq!(dump, "(Mul@0..5 (Lit@0..1 6) (Lit@4..5 7))");
+        assert_eq!(&src[0..1], "6");
+        assert_eq!(&src[4..5], "7");
+
+        let (arena, root) = parser::parse("f( 1)")?;
+        assert_eq!(dump_with_spans(&arena, root)?, "(Call@0..5 f (Lit@3..4 1))");
+        assert_eq!(dump(&arena, root)?, "(Call f (Lit 1))");
+        Ok(())
+    }
+
+    #[test]
+    fn format_should_add_only_required_parens() -> Result<()> {
+        for src in [
+            "1 + 2 * 3",
+            "(1 + 2) * 3",
+            "1 - (2 - 3)",
+            "!(1 && 2) || r#if(\"x\", 3 % 4)",
+            "1; 2",
+        ] {
+            let (arena, root) = parser::parse(src)?;
+            assert_eq!(format(&arena, root)?, src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn format_should_write_bindings() -> Result<()> {
+        for src in ["let x = 1 + 2; x * 3", "let r#if = 1; r#if"] {
+            let (arena, root) = parser::parse(src)?;
+            assert_eq!(format(&arena, root)?, src);
+        }
+        assert_eq!(eval_source("let x = 6; let x = x * 7; x")?, 42);
+        assert!(eval_source("y + 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn format_should_write_functions() -> Result<()> {
+        for src in ["fn add(a, b) = a + b; add(1, 2)", "fn r#in() = 1; r#in()"] {
+            let (arena, root) = parser::parse(src)?;
+            assert_eq!(format(&arena, root)?, src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn format_should_write_conditionals() -> Result<()> {
+        for src in [
+            "if a < b { a } else { b }",
+            "if x { 1 } else if y { 2 } else { 3 }",
+            "if x { print(x) }; -if x { 1 } * 2",
+        ] {
+            let (arena, root) = parser::parse(src)?;
+            assert_eq!(format(&arena, root)?, src);
+        }
+        assert_eq!(eval_source("if 2 > 1 { 6 * 7 } else { 1 / 0 }")?, 42);
+        assert_eq!(eval_source("if 0 { 1 / 0 }")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn format_should_write_loops() -> Result<()> {
+        for src in [
+            "let mut x = 0; for i in 0..10 { x = x + i; print(i) }; x",
+            "for i in 1..n + 1 { for j in 0..i {} }",
+            "for i in 0..10 { if i % 2 { continue }; if i > 5 { break }; print(i) }",
+        ] {
+            let (arena, root) = parser::parse(src)?;
+            assert_eq!(format(&arena, root)?, src);
+        }
+        assert!(eval_source("for i in 0..3 { i }").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_unwrap_parens() -> Result<()> {
+        assert_eq!(eval_source("(6) * 7")?, 42);
+        assert_eq!(eval_source("((1 + 2))")?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_match_ir_semantics() -> Result<()> {
+        assert_eq!(eval_source("1; 2; 3")?, 6);
+        assert_eq!(eval_source("(0 - 7) % 3")?, -1);
+        assert!(eval_source("1 / (2 - 2)").is_err());
+        assert!(eval_source("read_int()").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_short_circuit_logical_operators() -> Result<()> {
+        assert_eq!(eval_source("0 && 1 / 0")?, 0);
+        assert_eq!(eval_source("2 || 1 / 0")?, 1);
+        assert_eq!(eval_source("!0 + !5")?, 1);
+        Ok(())
+    }
+}
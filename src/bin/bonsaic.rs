@@ -1,16 +1,286 @@
 // SPDX-License-Identifier: Unlicense
-use std::path::Path;
-use bonsai::driver;
+use std::path::{Path, PathBuf};
+use bonsai::{diagnostic, driver};
 fn main() {
 
-    let args = std::env::args().collect::<Vec<_>>();
-    if args.len() < 2 {
-        eprintln!("please specify input file");
+    let mut args = std::env::args().skip(1);
+    let mut options = driver::CompileOptions::default();
+    let mut color = diagnostic::ColorChoice::default();
+    let mut inputs = vec![];
+    let mut eval = None;
+    let mut check = false;
+    let mut check_codegen = false;
+    let mut watch = false;
+    while let Some(arg) = args.next() {
+        let parsed = if arg == "explain" && inputs.is_empty() && !check {
+            let code = args.next().unwrap_or_default();
+            match diagnostic::explain(&code) {
+                Ok(text) => {
+                    print!("{}", text);
+                    std::process::exit(0);
+                }
+                Err(e) => Err(e),
+            }
+        } else if arg == "check" && inputs.is_empty() && !check {
+            check = true;
+            Ok(())
+        } else if arg == "--codegen" && check {
+            check_codegen = true;
+            Ok(())
+        } else if arg == "--eval" {
+            args.next()
+                .map(|source| eval = Some(source))
+                .ok_or(anyhow::anyhow!("--eval requires a program"))
+        } else if arg == "-o" {
+            args.next()
+                .map(|output| options.output = Some(output.into()))
+                .ok_or(anyhow::anyhow!("-o requires an output path"))
+        } else if let Some(emit) = arg.strip_prefix("--emit=") {
+            emit.parse().map(|emit| options.emit = Some(emit))
+        } else if let Some(toggles) = arg.strip_prefix("--target-feature=") {
+            let merged = match options.target_features.take() {
+                Some(previous) => format!("{},{}", previous, toggles),
+                None => toggles.to_string(),
+            };
+            options.target_features = Some(merged);
+            Ok(())
+        } else if arg == "--list-targets" {
+            print!("{}", driver::list_targets());
+            std::process::exit(0);
+        } else if let Some(pass) = arg.strip_prefix("--after=") {
+            options.after_pass = Some(pass.to_string());
+            Ok(())
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            level
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid optimization level '{}'", level))
+                .map(|level| options.opt_level = Some(level))
+        } else if let Some(depth) = arg.strip_prefix("--max-ir-depth=") {
+            depth
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid ir depth '{}'", depth))
+                .map(|depth| options.max_ir_depth = Some(depth))
+        } else if let Some(size) = arg.strip_prefix("--max-source-size=") {
+            size.parse()
+                .map_err(|_| anyhow::anyhow!("invalid source size '{}'", size))
+                .map(|size| options.max_source_size = Some(size))
+        } else if let Some(jobs) = arg.strip_prefix("--jobs=") {
+            jobs.parse()
+                .ok()
+                .filter(|&jobs| jobs > 0)
+                .ok_or(anyhow::anyhow!("invalid job count '{}'", jobs))
+                .map(|jobs| options.jobs = Some(jobs))
+        } else if arg == "--print-ir-after-codegen" {
+            options.print_ir_after_codegen = true;
+            Ok(())
+        } else if let Some(cc_arg) = arg.strip_prefix("--cc-arg=") {
+            options.cc_args.push(cc_arg.to_string());
+            Ok(())
+        } else if let Some(mode) = arg.strip_prefix("--overflow=") {
+            mode.parse().map(|mode| options.overflow = mode)
+        } else if arg == "--no-main" {
+            options.no_main = true;
+            Ok(())
+        } else if let Some(name) = arg.strip_prefix("--entry-name=") {
+            options.entry_name = Some(name.to_string());
+            Ok(())
+        } else if let Some(dir) = arg.strip_prefix("--cache-dir=") {
+            options.cache_dir = Some(dir.into());
+            Ok(())
+        } else if let Some(path) = arg.strip_prefix("--compile-commands=") {
+            options.compile_commands = Some(path.into());
+            Ok(())
+        } else if let Some(path) = arg.strip_prefix("--emit-depfile=") {
+            options.depfile = Some(path.into());
+            Ok(())
+        } else if arg == "--result-section" {
+            options.result_section = Some(driver::DEFAULT_RESULT_SECTION.to_string());
+            Ok(())
+        } else if let Some(section) = arg.strip_prefix("--result-section=") {
+            options.result_section = Some(section.to_string());
+            Ok(())
+        } else if arg == "--spans" {
+            options.ast_spans = true;
+            Ok(())
+        } else if let Some(triple) = arg.strip_prefix("--target=") {
+            options.target = Some(triple.to_string());
+            Ok(())
+        } else if let Some(dir) = arg.strip_prefix("--object-dir=") {
+            options.object_dir = Some(dir.into());
+            Ok(())
+        } else if arg == "--no-cache" {
+            options.no_cache = true;
+            Ok(())
+        } else if arg == "--watch" {
+            watch = true;
+            Ok(())
+        } else if let Some(template) = arg.strip_prefix("--print-format=") {
+            driver::validate_print_format(template)
+                .map(|_| options.print_format = Some(template.to_string()))
+        } else if arg == "--keep-going" {
+            options.keep_going = true;
+            Ok(())
+        } else if arg == "--dump-timings-json" {
+            options.dump_timings_json = true;
+            Ok(())
+        } else if arg == "--opt-diff" {
+            options.opt_diff = true;
+            Ok(())
+        } else if let Some(kind) = arg.strip_prefix("--werror-on=") {
+            options.werror_on.push(kind.to_string());
+            Ok(())
+        } else if arg == "--buffered-output" {
+            options.buffered_output = true;
+            Ok(())
+        } else if arg == "--unique-objects" {
+            options.unique_objects = true;
+            Ok(())
+        } else if arg == "--skip-verify" {
+            options.skip_verify = true;
+            Ok(())
+        } else if arg == "--dump-cfg" {
+            options.dump_cfg = true;
+            Ok(())
+        } else if arg == "--checked-arithmetic" {
+            options.checked_arithmetic = true;
+            Ok(())
+        } else if let Some(mode) = arg.strip_prefix("--exit-code=") {
+            mode.parse().map(|mode| options.exit_code = mode)
+        } else if arg == "--quiet-runtime" {
+            options.quiet_runtime = true;
+            Ok(())
+        } else if let Some(choice) = arg.strip_prefix("--color=") {
+            choice.parse().map(|choice| color = choice)
+        } else {
+            inputs.push(PathBuf::from(arg));
+            Ok(())
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(source) = eval {
+        match driver::eval_string(&source, &options, |compiled| print_report(compiled, &source, &options, color)) {
+            Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+            Err(e) => {
+                let diag = match e.downcast_ref::<diagnostic::Diagnostic>() {
+                    Some(diag) => diag.clone(),
+                    None => diagnostic::Diagnostic::error(format!("failed to compile:\n{}", e)),
+                };
+                eprint!("{}", diagnostic::render_diagnostic(&diag, &source, color.should_color()));
+                std::process::exit(1);
+            }
+        }
+    }
+    if check && inputs.len() > 1 {
+        let mut failed = false;
+        for source in &inputs {
+            print_lints(source, &options, color);
+            failed |= !report(source, driver::check(source, &options, check_codegen).map(|_| None), color);
+        }
+        std::process::exit(if failed { 1 } else { 0 });
+    }
+    let batch = match inputs.as_slice() {
+        [] => {
+            eprintln!("please specify input file");
+            std::process::exit(1);
+        }
+        [dir] if dir.is_dir() && !check => driver::compile_dir(dir, &options),
+        [_, _, ..] if !check => Ok(driver::compile_many(&inputs, &options)),
+        _ => Ok(vec![]),
+    };
+    match batch {
+        Ok(results) if !results.is_empty() => {
+            let mut failed = false;
+            for (source, result) in results {
+                failed |= !report(&source, show(&source, result, &options, color), color);
+            }
+            std::process::exit(if failed { 1 } else { 0 });
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let source = inputs[0].as_path();
+    if watch {
+        let interval = std::time::Duration::from_millis(250);
+        let watched = driver::watch(source, &options, interval, |result| {
+            report(source, show(source, result, &options, color), color);
+            true
+        });
+        if let Err(e) = watched {
+            eprintln!("{}", e);
+        }
         std::process::exit(1);
     }
-    let source = Path::new(&args[1]);
-    match driver::compile(source) {
-        Ok(v) => println!("successfully compiled to {}", v.to_str().unwrap_or("<unknown>")),
-        Err(v) => eprintln!("failed to compile:\n{}", v)
+    print_lints(source, &options, color);
+    let result = if check {
+        driver::check(source, &options, check_codegen).map(|_| None)
+    } else {
+        show(source, driver::compile_with_report(source, &options), &options, color)
+    };
+    if !report(source, result, color) {
+        std::process::exit(1);
+    }
+}
+
+/// Prints the lint warnings for `source`.
+fn print_lints(source: &Path, options: &driver::CompileOptions, color: diagnostic::ColorChoice) {
+    // escalated warnings and other errors are reported by the compile itself
+    if let Ok(warnings) = driver::lint_file(source, options) {
+        let src = driver::read_file(source).unwrap_or_default();
+        for warning in warnings {
+            eprint!("{}", diagnostic::render_diagnostic(&warning, &src, color.should_color()));
+        }
+    }
+}
+
+/// Prints what a successful compilation reported besides its artifact, e.g. LLVM IR.
+fn show(
+    source: &Path,
+    result: anyhow::Result<(PathBuf, driver::CompileReport)>,
+    options: &driver::CompileOptions,
+    color: diagnostic::ColorChoice,
+) -> anyhow::Result<Option<PathBuf>> {
+    result.map(|(output, compiled)| {
+        let src = driver::read_file(source).unwrap_or_default();
+        print_report(&compiled, &src, options, color);
+        Some(output)
+    })
+}
+
+fn print_report(
+    compiled: &driver::CompileReport,
+    src: &str,
+    options: &driver::CompileOptions,
+    color: diagnostic::ColorChoice,
+) {
+    for warning in &compiled.warnings {
+        eprint!("{}", diagnostic::render_diagnostic(warning, src, color.should_color()));
+    }
+    print!("{}", compiled.llvm_ir);
+    if options.dump_timings_json {
+        println!("{}", compiled.timings.to_json());
+    }
+}
+
+/// Prints the outcome of compiling or checking `source`, returning whether it succeeded.
+fn report(source: &Path, result: anyhow::Result<Option<PathBuf>>, color: diagnostic::ColorChoice) -> bool {
+    match result {
+        Ok(Some(v)) => println!("successfully compiled to {}", v.to_str().unwrap_or("<unknown>")),
+        Ok(None) => println!("no errors found in {}", source.display()),
+        Err(v) => {
+            let diag = match v.downcast_ref::<diagnostic::Diagnostic>() {
+                Some(diag) => diag.clone(),
+                None => diagnostic::Diagnostic::error(format!("failed to compile:\n{}", v)),
+            };
+            let src = driver::read_file(source).unwrap_or_default();
+            eprint!("{}", diagnostic::render_diagnostic(&diag, &src, color.should_color()));
+            return false;
+        }
     }
+    true
 }
@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: Unlicense
+//! Helpers for compiling bonsai sources from a Cargo build script.
+//!
+//! ```no_run
+//! // build.rs
+//! bonsai::build::compile("src/answer.bonsai").unwrap();
+//! ```
+//!
+//! The program is emitted as `i64 bonsai_main()`, which the crate can declare in an
+//! `extern "C"` block and call.
+use crate::driver::{self, CompileOptions, EmitKind};
+use anyhow::{anyhow, Result};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Compiles `source` into an object in `$OUT_DIR` and tells Cargo to link it; see
+/// [`compile_in_build_script`].
+pub fn compile(source: impl AsRef<Path>) -> Result<PathBuf> {
+    let out_dir = std::env::var_os("OUT_DIR")
+        .ok_or(anyhow!("OUT_DIR is not set; call this from a build script"))?;
+    compile_in_build_script(source, out_dir)
+}
+
+/// Compiles `source` into `<out_dir>/<stem>.o` and prints the `cargo:` lines that link
+/// it and rebuild when the source changes. Returns the object's path.
+pub fn compile_in_build_script(
+    source: impl AsRef<Path>,
+    out_dir: impl AsRef<Path>,
+) -> Result<PathBuf> {
+    compile_to(
+        source.as_ref(),
+        out_dir.as_ref(),
+        &mut std::io::stdout().lock(),
+    )
+}
+
+fn compile_to(source: &Path, out_dir: &Path, out: &mut impl Write) -> Result<PathBuf> {
+    let stem = source
+        .file_stem()
+        .ok_or(anyhow!("{} has no file name", source.display()))?;
+    let object = out_dir.join(stem).with_extension("o");
+    let options = CompileOptions {
+        emit: Some(EmitKind::Object),
+        output: Some(object.clone()),
+        no_main: true,
+        ..Default::default()
+    };
+    // printed first so that a broken source is retried once it is fixed
+    writeln!(out, "cargo:rerun-if-changed={}", source.display())?;
+    let object = driver::compile_with_options(source, &options)?;
+    writeln!(out, "cargo:rustc-link-arg={}", object.display())?;
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn compile_in_build_script_should_emit_object_and_cargo_lines() -> Result<()> {
+        let test_dir = env::current_dir()?.join("test-data");
+        let out_dir = test_dir.join("build_script_out");
+        std::fs::create_dir_all(&out_dir)?;
+        let source = test_dir.join("build_script.bonsai");
+        std::fs::write(&source, "6 * 7")?;
+
+        let mut printed = vec![];
+        let object = compile_to(&source, &out_dir, &mut printed)?;
+        assert_eq!(object, out_dir.join("build_script.o"));
+        assert!(object.is_file());
+        assert_eq!(
+            String::from_utf8(printed)?,
+            format!(
+                "cargo:rerun-if-changed={}\ncargo:rustc-link-arg={}\n",
+                source.display(),
+                object.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compile_in_build_script_should_ask_for_rerun_on_errors() -> Result<()> {
+        let test_dir = env::current_dir()?.join("test-data");
+        let source = test_dir.join("build_script_broken.bonsai");
+        std::fs::write(&source, "6 *")?;
+
+        let mut printed = vec![];
+        assert!(compile_to(&source, &test_dir, &mut printed).is_err());
+        assert_eq!(
+            String::from_utf8(printed)?,
+            format!("cargo:rerun-if-changed={}\n", source.display())
+        );
+        Ok(())
+    }
+}
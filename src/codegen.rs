@@ -1,8 +1,10 @@
 // SPDX-License-Identifier: Unlicense
-use crate::ir;
+use crate::{diagnostic::Diagnostic, ir};
 use anyhow::{anyhow, Result};
-use inkwell::{builder::Builder, context::Context, module::Module, targets, values};
-use std::{collections::HashMap, path::Path};
+use inkwell::{
+    basic_block::BasicBlock, builder::Builder, context::Context, module::Module, targets, values,
+};
+use std::{cell::RefCell, collections::HashMap, fmt::Write, path::Path, str::FromStr};
 
 #[derive(Debug, Clone)]
 struct Value<'a>(Option<values::AnyValueEnum<'a>>);
@@ -23,12 +25,98 @@ impl<'a> Value<'a> {
     }
 }
 
+/// How `main` maps the program result onto the 0..=255 exit-code range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExitCodeMode {
+    /// Keep the low 8 bits, as the OS would (300 exits with 44).
+    #[default]
+    Truncate,
+    /// Clamp to 0..=255 (300 exits with 255, negative results with 0).
+    Saturate,
+}
+
+impl FromStr for ExitCodeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "truncate" => Ok(ExitCodeMode::Truncate),
+            "saturate" => Ok(ExitCodeMode::Saturate),
+            _ => Err(anyhow!(
+                "unknown exit code mode '{}', expected one of: truncate, saturate",
+                s
+            )),
+        }
+    }
+}
+
+/// What `+`, `-` and `*` do when the result does not fit in 64 bits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowMode {
+    /// Wrap around in two's complement (`i64::MAX + 1` is `i64::MIN`).
+    #[default]
+    Wrap,
+    /// Print `arithmetic overflow` to stderr and abort.
+    Trap,
+    /// Clamp to `i64::MIN` or `i64::MAX`.
+    Saturate,
+}
+
+impl FromStr for OverflowMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wrap" => Ok(OverflowMode::Wrap),
+            "trap" => Ok(OverflowMode::Trap),
+            "saturate" => Ok(OverflowMode::Saturate),
+            _ => Err(anyhow!(
+                "unknown overflow mode '{}', expected one of: wrap, trap, saturate",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Print only the value followed by a newline, without the `result: ` label.
+    pub quiet_runtime: bool,
+    pub exit_code: ExitCodeMode,
+    /// Write output files without verifying the module first.
+    pub skip_verify: bool,
+    /// Run LLVM's `default<On>` pipeline over the module; `None` leaves it unoptimized.
+    pub opt_level: Option<u8>,
+    /// Fully buffer stdout so it is written in one go at exit. Output is lost if the
+    /// program aborts, e.g. on a failed assertion.
+    pub buffered_output: bool,
+    pub overflow: OverflowMode,
+    /// How each statement's value is printed, e.g. `=> {}\n`; overrides `quiet_runtime`.
+    pub print_format: Option<String>,
+    /// Emit the program as `i64 name()` returning its result, without a `main` and
+    /// without printing the statements, so a host program can call it.
+    pub library_entry: Option<String>,
+    /// Also store a constant program's result in this object section, as 8 little-endian
+    /// bytes in the global `bonsai_result`. Left out unless overflow wraps.
+    pub result_section: Option<String>,
+}
+
 pub struct CodeGen<'a> {
     ir_arena: ir::Arena,
+    options: Options,
     context: &'a Context,
     module: Module<'a>,
     builder: Builder<'a>,
     target_machine: targets::TargetMachine,
+    /// Offsets at which the lines of the source start, for runtime error messages.
+    line_starts: Vec<usize>,
+    /// The stack slots of the `let` bindings and parameters generated so far.
+    variables: RefCell<HashMap<ir::Id, values::PointerValue<'a>>>,
+    /// The LLVM function of each `fn` node.
+    functions: RefCell<HashMap<ir::Id, values::FunctionValue<'a>>>,
+    /// The blocks `continue` and `break` jump to in each loop being generated, innermost
+    /// last.
+    loops: RefCell<Vec<(BasicBlock<'a>, BasicBlock<'a>)>>,
 }
 
 impl<'a> CodeGen<'a> {
@@ -37,16 +125,50 @@ impl<'a> CodeGen<'a> {
         context: &'a Context,
         target_machine: targets::TargetMachine,
         module_name: &str,
+        options: Options,
     ) -> Self {
         let module = context.create_module(module_name);
+        module.set_triple(&target_machine.get_triple());
+        module.set_data_layout(&target_machine.get_target_data().get_data_layout());
         let builder = context.create_builder();
         Self {
             ir_arena,
+            options,
             context,
             module,
             builder,
             target_machine,
+            line_starts: vec![],
+            variables: RefCell::new(HashMap::new()),
+            functions: RefCell::new(HashMap::new()),
+            loops: RefCell::new(vec![]),
+        }
+    }
+
+    /// Lets runtime errors name the line of `source` the failing operation is on.
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        self
+    }
+
+    /// The 1-based line the node `id` was lowered from, if known.
+    fn line_of(&self, id: ir::Id) -> Option<usize> {
+        let span = self.ir_arena[id].span.as_ref()?;
+        if self.line_starts.is_empty() {
+            return None;
         }
+        Some(self.line_starts.partition_point(|&start| start <= span.start))
+    }
+
+    /// Whether the module is built for WebAssembly, where there is no libc to print with.
+    fn is_wasm(&self) -> bool {
+        self.target_machine
+            .get_triple()
+            .as_str()
+            .to_string_lossy()
+            .starts_with("wasm")
     }
 
     fn generate_builtins(&self) -> Result<HashMap<&str, values::FunctionValue>> {
@@ -63,6 +185,10 @@ impl<'a> CodeGen<'a> {
             None,
         );
 
+        if self.is_wasm() {
+            return self.declare_host_builtins();
+        }
+
         let print_int = self.module.add_function(
             "print_int",
             void_ty.fn_type(&[i64_ty.into()], false),
@@ -71,11 +197,14 @@ impl<'a> CodeGen<'a> {
         let print_int_body = self.context.append_basic_block(print_int, "entry");
         self.builder.position_at_end(print_int_body);
 
-        // cf. https://github.com/TheDan64/inkwell/issues/32
-        let format_str = unsafe {
-            self.builder
-                .build_global_string("result: %d\n", "format string")
+        let template = match &self.options.print_format {
+            Some(template) => template.as_str(),
+            None if self.options.quiet_runtime => "{}\n",
+            None => DEFAULT_PRINT_FORMAT,
         };
+        let format = printf_format(template)?;
+        // cf. https://github.com/TheDan64/inkwell/issues/32
+        let format_str = unsafe { self.builder.build_global_string(&format, "format string") };
         let format_str = self.builder.build_cast(
             values::InstructionOpcode::BitCast,
             format_str?.as_pointer_value(),
@@ -91,12 +220,645 @@ impl<'a> CodeGen<'a> {
             .build_call(printf, &[format_str?.into(), val_to_print.into()], "")?;
         self.builder.build_return(None)?;
 
+        let scanf = self.module.add_function(
+            "scanf",
+            self.context.i32_type().fn_type(&[i8_ptr_ty.into()], true),
+            None,
+        );
+
+        let read_int = self
+            .module
+            .add_function("read_int", i64_ty.fn_type(&[], false), None);
+        let read_int_body = self.context.append_basic_block(read_int, "entry");
+        self.builder.position_at_end(read_int_body);
+
+        let slot = self.builder.build_alloca(i64_ty, "value")?;
+        self.builder.build_store(slot, i64_ty.const_zero())?;
+        let scan_format = unsafe { self.builder.build_global_string("%lld", "scan format") }?;
+        self.builder.build_call(
+            scanf,
+            &[scan_format.as_pointer_value().into(), slot.into()],
+            "",
+        )?;
+        let value = self.builder.build_load(i64_ty, slot, "")?;
+        self.builder.build_return(Some(&value))?;
+
+        let i32_ty = self.context.i32_type();
+        self.module.add_function(
+            "dprintf",
+            i32_ty.fn_type(&[i32_ty.into(), i8_ptr_ty.into()], true),
+            None,
+        );
+        self.module
+            .add_function("abort", void_ty.fn_type(&[], false), None);
+
+        let pow_mod = self.generate_pow_mod()?;
+        let ipow = self.generate_ipow()?;
+
         let mut builtins = HashMap::new();
         builtins.insert("print_int", print_int);
+        builtins.insert("read_int", read_int);
+        builtins.insert("pow_mod", pow_mod);
+        builtins.insert("ipow", ipow);
 
         Ok(builtins)
     }
 
+    /// Declares `print_int` and `read_int` without defining them, so a WebAssembly module
+    /// imports them from the host. `print` and failed assertions still call `printf`,
+    /// `dprintf` and `abort`, which the host has to provide as well.
+    fn declare_host_builtins(&self) -> Result<HashMap<&str, values::FunctionValue>> {
+        let i64_ty = self.context.i64_type();
+        let i32_ty = self.context.i32_type();
+        let void_ty = self.context.void_type();
+        let i8_ptr_ty = self
+            .context
+            .i8_type()
+            .ptr_type(inkwell::AddressSpace::default());
+
+        let print_int =
+            self.module
+                .add_function("print_int", void_ty.fn_type(&[i64_ty.into()], false), None);
+        let read_int = self
+            .module
+            .add_function("read_int", i64_ty.fn_type(&[], false), None);
+        self.module.add_function(
+            "dprintf",
+            i32_ty.fn_type(&[i32_ty.into(), i8_ptr_ty.into()], true),
+            None,
+        );
+        self.module
+            .add_function("abort", void_ty.fn_type(&[], false), None);
+        let pow_mod = self.generate_pow_mod()?;
+        let ipow = self.generate_ipow()?;
+
+        let mut builtins = HashMap::new();
+        builtins.insert("print_int", print_int);
+        builtins.insert("read_int", read_int);
+        builtins.insert("pow_mod", pow_mod);
+        builtins.insert("ipow", ipow);
+        Ok(builtins)
+    }
+
+    /// Generates `i64 pow_mod(i64 base, i64 exp, i64 modulus)`, squaring `base` for every
+    /// bit of `exp` and reducing with `srem` after each multiplication.
+    fn generate_pow_mod(&self) -> Result<values::FunctionValue<'a>> {
+        let i64_ty = self.context.i64_type();
+        let i128_ty = self.context.i128_type();
+        let pow_mod = self.module.add_function(
+            "pow_mod",
+            i64_ty.fn_type(&[i64_ty.into(), i64_ty.into(), i64_ty.into()], false),
+            None,
+        );
+        let param = |n| {
+            pow_mod
+                .get_nth_param(n)
+                .map(|p| p.into_int_value())
+                .ok_or(anyhow!("failed to get param {} of pow_mod", n))
+        };
+        let (base, exp, modulus) = (param(0)?, param(1)?, param(2)?);
+        let zero = i64_ty.const_zero();
+        let one = i64_ty.const_int(1, false);
+
+        let entry = self.context.append_basic_block(pow_mod, "entry");
+        self.builder.position_at_end(entry);
+        let positive =
+            self.builder
+                .build_int_compare(inkwell::IntPredicate::SGT, modulus, zero, "")?;
+        self.generate_assert(positive, "pow_mod modulus must be positive")?;
+        let non_negative =
+            self.builder
+                .build_int_compare(inkwell::IntPredicate::SGE, exp, zero, "")?;
+        self.generate_assert(non_negative, "pow_mod exponent must not be negative")?;
+
+        // both factors are below the modulus, so their product fits in 128 bits
+        let wide_modulus = self.builder.build_int_s_extend(modulus, i128_ty, "")?;
+        let mul_mod = |lhs: values::IntValue<'a>, rhs: values::IntValue<'a>| -> Result<_> {
+            let lhs = self.builder.build_int_s_extend(lhs, i128_ty, "")?;
+            let rhs = self.builder.build_int_s_extend(rhs, i128_ty, "")?;
+            let product = self.builder.build_int_mul(lhs, rhs, "")?;
+            let reduced = self.builder.build_int_signed_rem(product, wide_modulus, "")?;
+            Ok(self.builder.build_int_truncate(reduced, i64_ty, "")?)
+        };
+        let initial_result = self.builder.build_int_signed_rem(one, modulus, "")?;
+        let initial_base = self.builder.build_int_signed_rem(base, modulus, "")?;
+        let preheader = self
+            .builder
+            .get_insert_block()
+            .ok_or(anyhow!("builder is not positioned in a block"))?;
+
+        let header = self.context.append_basic_block(pow_mod, "loop");
+        let body = self.context.append_basic_block(pow_mod, "loop.body");
+        let exit = self.context.append_basic_block(pow_mod, "loop.exit");
+        self.builder.build_unconditional_branch(header)?;
+
+        self.builder.position_at_end(header);
+        let result = self.builder.build_phi(i64_ty, "result")?;
+        let square = self.builder.build_phi(i64_ty, "square")?;
+        let bits = self.builder.build_phi(i64_ty, "bits")?;
+        let done = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            bits.as_basic_value().into_int_value(),
+            zero,
+            "",
+        )?;
+        self.builder.build_conditional_branch(done, exit, body)?;
+
+        self.builder.position_at_end(body);
+        let current = result.as_basic_value().into_int_value();
+        let current_square = square.as_basic_value().into_int_value();
+        let current_bits = bits.as_basic_value().into_int_value();
+        let low_bit = self.builder.build_and(current_bits, one, "")?;
+        let odd = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::NE, low_bit, zero, "")?;
+        let multiplied = mul_mod(current, current_square)?;
+        let next_result = self
+            .builder
+            .build_select(odd, multiplied, current, "")?
+            .into_int_value();
+        let next_square = mul_mod(current_square, current_square)?;
+        let next_bits = self.builder.build_right_shift(current_bits, one, false, "")?;
+        self.builder.build_unconditional_branch(header)?;
+
+        result.add_incoming(&[(&initial_result, preheader), (&next_result, body)]);
+        square.add_incoming(&[(&initial_base, preheader), (&next_square, body)]);
+        bits.add_incoming(&[(&exp, preheader), (&next_bits, body)]);
+
+        self.builder.position_at_end(exit);
+        self.builder.build_return(Some(&current))?;
+        Ok(pow_mod)
+    }
+
+    /// Generates `{i64, i1} ipow(i64 base, i64 exp)`, returning the wrapped power and
+    /// whether the exact one overflows, like `llvm.smul.with.overflow`. A negative
+    /// exponent gives 0.
+    fn generate_ipow(&self) -> Result<values::FunctionValue<'a>> {
+        let i64_ty = self.context.i64_type();
+        let bool_ty = self.context.bool_type();
+        let ret_ty = self
+            .context
+            .struct_type(&[i64_ty.into(), bool_ty.into()], false);
+        let ipow = self.module.add_function(
+            "ipow",
+            ret_ty.fn_type(&[i64_ty.into(), i64_ty.into()], false),
+            None,
+        );
+        let param = |n| {
+            ipow.get_nth_param(n)
+                .map(|p| p.into_int_value())
+                .ok_or(anyhow!("failed to get param {} of ipow", n))
+        };
+        let (base, exp) = (param(0)?, param(1)?);
+        let zero = i64_ty.const_zero();
+        let one = i64_ty.const_int(1, false);
+        let no = bool_ty.const_zero();
+        let build_return = |value: values::IntValue<'a>, overflowed: values::IntValue<'a>| {
+            let ret = self
+                .builder
+                .build_insert_value(ret_ty.get_undef(), value, 0, "")?
+                .into_struct_value();
+            let ret = self.builder.build_insert_value(ret, overflowed, 1, "")?;
+            self.builder.build_return(Some(&ret.into_struct_value()))?;
+            anyhow::Ok(())
+        };
+
+        let entry = self.context.append_basic_block(ipow, "entry");
+        let negative = self.context.append_basic_block(ipow, "negative");
+        let header = self.context.append_basic_block(ipow, "loop");
+        let body = self.context.append_basic_block(ipow, "loop.body");
+        let exit = self.context.append_basic_block(ipow, "loop.exit");
+        self.builder.position_at_end(entry);
+        let is_negative =
+            self.builder
+                .build_int_compare(inkwell::IntPredicate::SLT, exp, zero, "")?;
+        self.builder
+            .build_conditional_branch(is_negative, negative, header)?;
+
+        self.builder.position_at_end(negative);
+        build_return(zero, no)?;
+
+        self.builder.position_at_end(header);
+        let result = self.builder.build_phi(i64_ty, "result")?;
+        let square = self.builder.build_phi(i64_ty, "square")?;
+        let bits = self.builder.build_phi(i64_ty, "bits")?;
+        let overflowed = self.builder.build_phi(bool_ty, "overflowed")?;
+        let done = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            bits.as_basic_value().into_int_value(),
+            zero,
+            "",
+        )?;
+        self.builder.build_conditional_branch(done, exit, body)?;
+
+        self.builder.position_at_end(body);
+        let current = result.as_basic_value().into_int_value();
+        let current_square = square.as_basic_value().into_int_value();
+        let current_bits = bits.as_basic_value().into_int_value();
+        let low_bit = self.builder.build_and(current_bits, one, "")?;
+        let odd = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::NE, low_bit, zero, "")?;
+        let (product, product_overflowed) =
+            self.call_with_overflow("llvm.smul.with.overflow", current, current_square)?;
+        let next_result = self
+            .builder
+            .build_select(odd, product, current, "")?
+            .into_int_value();
+        let next_bits = self.builder.build_right_shift(current_bits, one, false, "")?;
+        // the last square is never multiplied in, so its overflow does not count
+        let (next_square, square_overflowed) =
+            self.call_with_overflow("llvm.smul.with.overflow", current_square, current_square)?;
+        let more_bits = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::NE, next_bits, zero, "")?;
+        let product_overflowed = self.builder.build_and(odd, product_overflowed, "")?;
+        let square_overflowed = self.builder.build_and(more_bits, square_overflowed, "")?;
+        let next_overflowed = self.builder.build_or(
+            overflowed.as_basic_value().into_int_value(),
+            self.builder
+                .build_or(product_overflowed, square_overflowed, "")?,
+            "",
+        )?;
+        self.builder.build_unconditional_branch(header)?;
+
+        result.add_incoming(&[(&one, entry), (&next_result, body)]);
+        square.add_incoming(&[(&base, entry), (&next_square, body)]);
+        bits.add_incoming(&[(&exp, entry), (&next_bits, body)]);
+        overflowed.add_incoming(&[(&no, entry), (&next_overflowed, body)]);
+
+        self.builder.position_at_end(exit);
+        build_return(current, overflowed.as_basic_value().into_int_value())?;
+        Ok(ipow)
+    }
+
+    fn builtin(&self, name: &str) -> Result<values::FunctionValue<'a>> {
+        self.module
+            .get_function(name)
+            .ok_or(anyhow!("builtin function {} not found", name))
+    }
+
+    fn current_function(&self) -> Result<values::FunctionValue<'a>> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or(anyhow!("builder is not positioned in a function"))
+    }
+
+    /// Generates the node `id` as an i64, widening a bool to 0 or 1.
+    fn generate_int(&self, id: ir::Id) -> Result<values::IntValue<'a>> {
+        let value = self.generate_impl(id)?.into_int_value()?;
+        if value.get_type().get_bit_width() == 1 {
+            return Ok(self
+                .builder
+                .build_int_z_extend(value, self.context.i64_type(), "")?);
+        }
+        Ok(value)
+    }
+
+    /// Generates the node `id` as an i1 that is set when the value is a true bool or a
+    /// non-zero integer.
+    fn generate_condition(&self, id: ir::Id) -> Result<values::IntValue<'a>> {
+        let value = self.generate_impl(id)?.into_int_value()?;
+        if value.get_type().get_bit_width() == 1 {
+            return Ok(value);
+        }
+        Ok(self.builder.build_int_compare(
+            inkwell::IntPredicate::NE,
+            value,
+            value.get_type().const_zero(),
+            "",
+        )?)
+    }
+
+    /// Allocates an i64 stack slot at the start of the current function's entry block,
+    /// where `mem2reg` can promote it to a register.
+    fn build_entry_alloca(&self, name: &str) -> Result<values::PointerValue<'a>> {
+        let entry = self
+            .current_function()?
+            .get_first_basic_block()
+            .ok_or(anyhow!("function has no entry block"))?;
+        let builder = self.context.create_builder();
+        match entry.get_first_instruction() {
+            Some(first) => builder.position_before(&first),
+            None => builder.position_at_end(entry),
+        }
+        Ok(builder.build_alloca(self.context.i64_type(), name)?)
+    }
+
+    /// Branches to a block that prints `message` to stderr and aborts when `cond` is zero.
+    fn generate_assert(&self, cond: values::IntValue<'a>, message: &str) -> Result<()> {
+        let function = self.current_function()?;
+        let fail = self.context.append_basic_block(function, "assert.fail");
+        let cont = self.context.append_basic_block(function, "assert.cont");
+
+        let is_false = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            cond,
+            cond.get_type().const_zero(),
+            "",
+        )?;
+        self.builder
+            .build_conditional_branch(is_false, fail, cont)?;
+
+        self.builder.position_at_end(fail);
+        let format_str = unsafe { self.builder.build_global_string("%s\n", "assert format") }?;
+        let message_str = unsafe { self.builder.build_global_string(message, "assert message") }?;
+        let stderr_fd = self.context.i32_type().const_int(2, false);
+        self.builder.build_call(
+            self.builtin("dprintf")?,
+            &[
+                stderr_fd.into(),
+                format_str.as_pointer_value().into(),
+                message_str.as_pointer_value().into(),
+            ],
+            "",
+        )?;
+        self.builder.build_call(self.builtin("abort")?, &[], "")?;
+        self.builder.build_unreachable()?;
+
+        self.builder.position_at_end(cont);
+        Ok(())
+    }
+
+    /// Generates `lhs && rhs` (`is_and`) or `lhs || rhs` as an i1, branching around the
+    /// right operand when the left one decides the result.
+    fn generate_short_circuit(&self, args: &[ir::Id], is_and: bool) -> Result<values::IntValue<'a>> {
+        let function = self.current_function()?;
+        let bool_ty = self.context.bool_type();
+
+        let lhs = self.generate_condition(args[0])?;
+        let lhs_end = self
+            .builder
+            .get_insert_block()
+            .ok_or(anyhow!("builder is not positioned in a block"))?;
+        let (rhs_name, end_name) = if is_and {
+            ("and.rhs", "and.end")
+        } else {
+            ("or.rhs", "or.end")
+        };
+        let rhs_block = self.context.append_basic_block(function, rhs_name);
+        let end_block = self.context.append_basic_block(function, end_name);
+        if is_and {
+            self.builder.build_conditional_branch(lhs, rhs_block, end_block)?;
+        } else {
+            self.builder.build_conditional_branch(lhs, end_block, rhs_block)?;
+        }
+
+        self.builder.position_at_end(rhs_block);
+        let rhs = self.generate_condition(args[1])?;
+        let rhs_end = self
+            .builder
+            .get_insert_block()
+            .ok_or(anyhow!("builder is not positioned in a block"))?;
+        self.builder.build_unconditional_branch(end_block)?;
+
+        self.builder.position_at_end(end_block);
+        let phi = self.builder.build_phi(bool_ty, "")?;
+        let short_circuited = bool_ty.const_int(!is_and as u64, false);
+        phi.add_incoming(&[(&short_circuited, lhs_end), (&rhs, rhs_end)]);
+        Ok(phi.as_basic_value().into_int_value())
+    }
+
+    /// Generates `if cond { then } else { otherwise }`, branching to a block for each
+    /// operand and joining their values with a phi. The result is an i1 if both branches
+    /// are bools and an i64 otherwise.
+    fn generate_if(&self, args: &[ir::Id]) -> Result<values::IntValue<'a>> {
+        let function = self.current_function()?;
+        let cond = self.generate_condition(args[0])?;
+        let is_bool = ir::is_bool(&self.ir_arena, args[1]) && ir::is_bool(&self.ir_arena, args[2]);
+        let then_block = self.context.append_basic_block(function, "if.then");
+        let else_block = self.context.append_basic_block(function, "if.else");
+        let end_block = self.context.append_basic_block(function, "if.end");
+        self.builder
+            .build_conditional_branch(cond, then_block, else_block)?;
+
+        // a branch may itself branch, so it ends in whatever block its code ends in
+        let mut incoming = vec![];
+        for (block, arg) in [(then_block, args[1]), (else_block, args[2])] {
+            self.builder.position_at_end(block);
+            let value = match is_bool {
+                true => self.generate_impl(arg)?.into_int_value()?,
+                false => self.generate_int(arg)?,
+            };
+            let end = self
+                .builder
+                .get_insert_block()
+                .ok_or(anyhow!("builder is not positioned in a block"))?;
+            self.builder.build_unconditional_branch(end_block)?;
+            incoming.push((value, end));
+        }
+
+        self.builder.position_at_end(end_block);
+        let ty = match is_bool {
+            true => self.context.bool_type(),
+            false => self.context.i64_type(),
+        };
+        let phi = self.builder.build_phi(ty, "")?;
+        for (value, block) in &incoming {
+            phi.add_incoming(&[(value, *block)]);
+        }
+        Ok(phi.as_basic_value().into_int_value())
+    }
+
+    /// Generates a loop running `operands[2..]` and then the step `operands[1]` while the
+    /// condition `operands[0]` holds. `continue` in the body jumps to the step and `break`
+    /// past the loop.
+    fn generate_loop(&self, operands: &[ir::Id]) -> Result<()> {
+        let function = self.current_function()?;
+        let cond_block = self.context.append_basic_block(function, "loop.cond");
+        let body_block = self.context.append_basic_block(function, "loop.body");
+        let step_block = self.context.append_basic_block(function, "loop.step");
+        let end_block = self.context.append_basic_block(function, "loop.end");
+        self.builder.build_unconditional_branch(cond_block)?;
+
+        self.builder.position_at_end(cond_block);
+        let cond = self.generate_condition(operands[0])?;
+        self.builder
+            .build_conditional_branch(cond, body_block, end_block)?;
+
+        self.builder.position_at_end(body_block);
+        self.loops.borrow_mut().push((step_block, end_block));
+        for &stmt in &operands[2..] {
+            self.generate_int(stmt)?;
+        }
+        self.loops.borrow_mut().pop();
+        self.builder.build_unconditional_branch(step_block)?;
+
+        self.builder.position_at_end(step_block);
+        self.generate_int(operands[1])?;
+        self.builder.build_unconditional_branch(cond_block)?;
+
+        self.builder.position_at_end(end_block);
+        Ok(())
+    }
+
+    /// Generates `lhs op rhs` for `+`, `-` or `*` for the node `id`, handling overflow as
+    /// `options.overflow` says.
+    fn generate_arithmetic(
+        &self,
+        id: ir::Id,
+        op: &ir::OpKind,
+        lhs: values::IntValue<'a>,
+        rhs: values::IntValue<'a>,
+    ) -> Result<values::IntValue<'a>> {
+        if self.options.overflow == OverflowMode::Wrap {
+            return Ok(match op {
+                ir::OpKind::IAdd => self.builder.build_int_add(lhs, rhs, "")?,
+                ir::OpKind::ISub => self.builder.build_int_sub(lhs, rhs, "")?,
+                _ => self.builder.build_int_mul(lhs, rhs, "")?,
+            });
+        }
+
+        let intrinsic_name = match op {
+            ir::OpKind::IAdd => "llvm.sadd.with.overflow",
+            ir::OpKind::ISub => "llvm.ssub.with.overflow",
+            _ => "llvm.smul.with.overflow",
+        };
+        let (value, overflowed) = self.call_with_overflow(intrinsic_name, lhs, rhs)?;
+        // the exact result has the sign of `lhs`, or of `lhs ^ rhs` for a product
+        let sign = match op {
+            ir::OpKind::IMul => self.builder.build_xor(lhs, rhs, "")?,
+            _ => lhs,
+        };
+        self.handle_overflow(id, value, overflowed, sign)
+    }
+
+    /// Calls one of the `llvm.*.with.overflow` intrinsics on i64 operands, returning the
+    /// wrapped result and the overflow bit.
+    fn call_with_overflow(
+        &self,
+        intrinsic_name: &str,
+        lhs: values::IntValue<'a>,
+        rhs: values::IntValue<'a>,
+    ) -> Result<(values::IntValue<'a>, values::IntValue<'a>)> {
+        let i64_ty = self.context.i64_type();
+        let intrinsic = inkwell::intrinsics::Intrinsic::find(intrinsic_name)
+            .and_then(|intrinsic| intrinsic.get_declaration(&self.module, &[i64_ty.into()]))
+            .ok_or(anyhow!("intrinsic {} not found", intrinsic_name))?;
+        let result = self
+            .builder
+            .build_call(intrinsic, &[lhs.into(), rhs.into()], "")?
+            .try_as_basic_value()
+            .left()
+            .ok_or(anyhow!("{} returned no value", intrinsic_name))?
+            .into_struct_value();
+        let value = self.builder.build_extract_value(result, 0, "")?.into_int_value();
+        let overflowed = self.builder.build_extract_value(result, 1, "")?.into_int_value();
+        Ok((value, overflowed))
+    }
+
+    /// Turns the wrapped `value` of the node `id` into its result under a non-wrapping
+    /// `options.overflow`; `sign` is negative exactly when the exact result is.
+    fn handle_overflow(
+        &self,
+        id: ir::Id,
+        value: values::IntValue<'a>,
+        overflowed: values::IntValue<'a>,
+        sign: values::IntValue<'a>,
+    ) -> Result<values::IntValue<'a>> {
+        let i64_ty = self.context.i64_type();
+        if self.options.overflow == OverflowMode::Trap {
+            let fits = self.builder.build_not(overflowed, "")?;
+            let message = match self.line_of(id) {
+                Some(line) => format!("arithmetic overflow at line {}", line),
+                None => "arithmetic overflow".to_string(),
+            };
+            self.generate_assert(fits, &message)?;
+            return Ok(value);
+        }
+        let negative = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            sign,
+            i64_ty.const_zero(),
+            "",
+        )?;
+        let bound = self.builder.build_select(
+            negative,
+            i64_ty.const_int(i64::MIN as u64, true),
+            i64_ty.const_int(i64::MAX as u64, true),
+            "",
+        )?;
+        Ok(self
+            .builder
+            .build_select(overflowed, bound.into_int_value(), value, "")?
+            .into_int_value())
+    }
+
+    /// Generates `base ** exp` for the node `id` by calling `ipow`.
+    fn generate_power(
+        &self,
+        id: ir::Id,
+        base: values::IntValue<'a>,
+        exp: values::IntValue<'a>,
+    ) -> Result<values::IntValue<'a>> {
+        let result = self
+            .builder
+            .build_call(self.builtin("ipow")?, &[base.into(), exp.into()], "")?
+            .try_as_basic_value()
+            .left()
+            .ok_or(anyhow!("ipow returned no value"))?
+            .into_struct_value();
+        let value = self.builder.build_extract_value(result, 0, "")?.into_int_value();
+        if self.options.overflow == OverflowMode::Wrap {
+            return Ok(value);
+        }
+        let overflowed = self.builder.build_extract_value(result, 1, "")?.into_int_value();
+        // the exact power is negative only for a negative base and an odd exponent
+        let i64_ty = self.context.i64_type();
+        let low_bit = self.builder.build_and(exp, i64_ty.const_int(1, false), "")?;
+        let odd = self.builder.build_int_compare(
+            inkwell::IntPredicate::NE,
+            low_bit,
+            i64_ty.const_zero(),
+            "",
+        )?;
+        let sign = self
+            .builder
+            .build_select(odd, base, i64_ty.const_zero(), "")?
+            .into_int_value();
+        self.handle_overflow(id, value, overflowed, sign)
+    }
+
+    /// Generates `lhs / rhs` or `lhs % rhs` with the semantics `ir::eval` gives them,
+    /// aborting on division by zero.
+    fn generate_division(
+        &self,
+        op: &ir::OpKind,
+        lhs: values::IntValue<'a>,
+        rhs: values::IntValue<'a>,
+    ) -> Result<values::IntValue<'a>> {
+        let i64_ty = self.context.i64_type();
+        let nonzero =
+            self.builder
+                .build_int_compare(inkwell::IntPredicate::NE, rhs, i64_ty.const_zero(), "")?;
+        self.generate_assert(nonzero, "division by zero")?;
+
+        // sdiv and srem are undefined for i64::MIN / -1; dividing by 1 instead gives the
+        // wrapped quotient i64::MIN and the remainder 0
+        let is_min = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            lhs,
+            i64_ty.const_int(i64::MIN as u64, true),
+            "",
+        )?;
+        let is_minus_one = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            rhs,
+            i64_ty.const_all_ones(),
+            "",
+        )?;
+        let overflows = self.builder.build_and(is_min, is_minus_one, "")?;
+        let divisor = self
+            .builder
+            .build_select(overflows, i64_ty.const_int(1, false), rhs, "")?
+            .into_int_value();
+        Ok(match op {
+            ir::OpKind::IDiv => self.builder.build_int_signed_div(lhs, divisor, "")?,
+            _ => self.builder.build_int_signed_rem(lhs, divisor, "")?,
+        })
+    }
+
     fn generate_impl(&self, id: ir::Id) -> Result<Value> {
         let kind = &self
             .ir_arena
@@ -108,48 +870,285 @@ impl<'a> CodeGen<'a> {
             &ir::Kind::IntValue(i) => Ok(Value::from_int_value(
                 self.context.i64_type().const_int(i as u64, true),
             )),
+            &ir::Kind::BoolValue(b) => Ok(Value::from_int_value(
+                self.context.bool_type().const_int(b as u64, false),
+            )),
+            ir::Kind::Seq(_) => Err(anyhow!("statement sequences are only allowed at the top level")),
+            ir::Kind::Let(name, value) => {
+                let value = self.generate_int(*value)?;
+                let slot = self.build_entry_alloca(name)?;
+                self.builder.build_store(slot, value)?;
+                self.variables.borrow_mut().insert(id, slot);
+                Ok(Value::from_int_value(self.context.i64_type().const_zero()))
+            }
+            ir::Kind::Var(binding) => {
+                let slot = *self
+                    .variables
+                    .borrow()
+                    .get(binding)
+                    .ok_or(anyhow!("variable used before its let"))?;
+                let value = self.builder.build_load(self.context.i64_type(), slot, "")?;
+                Ok(Value::from_int_value(value.into_int_value()))
+            }
+            ir::Kind::Assign(binding, value) => {
+                let value = self.generate_int(*value)?;
+                let slot = *self
+                    .variables
+                    .borrow()
+                    .get(binding)
+                    .ok_or(anyhow!("variable assigned before its let"))?;
+                self.builder.build_store(slot, value)?;
+                Ok(Value::from_int_value(self.context.i64_type().const_zero()))
+            }
+            // defined up front by generate_functions
+            ir::Kind::Fn(..) => Ok(Value::from_int_value(self.context.i64_type().const_zero())),
+            ir::Kind::Param(name) => Err(anyhow!("parameter {} used outside of a variable", name)),
+            ir::Kind::Call(function, args) => {
+                let function = *self
+                    .functions
+                    .borrow()
+                    .get(function)
+                    .ok_or(anyhow!("call of an undefined function"))?;
+                let mut call_args = vec![];
+                for &arg in args {
+                    call_args.push(self.generate_int(arg)?.into());
+                }
+                let value = self
+                    .builder
+                    .build_call(function, &call_args, "")?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or(anyhow!("function returned no value"))?;
+                Ok(Value::from_int_value(value.into_int_value()))
+            }
+            ir::Kind::Loop(operands) => {
+                self.generate_loop(operands)?;
+                Ok(Value::from_int_value(self.context.i64_type().const_zero()))
+            }
+            ir::Kind::Break | ir::Kind::Continue => {
+                let (continue_block, break_block) = *self
+                    .loops
+                    .borrow()
+                    .last()
+                    .ok_or(anyhow!("break or continue outside of a loop"))?;
+                let target = match kind {
+                    ir::Kind::Break => break_block,
+                    _ => continue_block,
+                };
+                self.builder.build_unconditional_branch(target)?;
+                // whatever follows in the iteration is unreachable, but still needs a block
+                let rest = self
+                    .context
+                    .append_basic_block(self.current_function()?, "loop.unreachable");
+                self.builder.position_at_end(rest);
+                Ok(Value::from_int_value(self.context.i64_type().const_zero()))
+            }
             ir::Kind::Op(op, args) => {
 
                 let ret = match op {
-                    ir::OpKind::IAdd => Value::from_int_value(
-                        self.builder.build_int_add(
-                            self.generate_impl(args[0])?.into_int_value()?,
-                            self.generate_impl(args[1])?.into_int_value()?, 
-                        ""
-                        )?
-                    ),
-                    ir::OpKind::ISub => Value::from_int_value(
-                        self.builder.build_int_sub(
-                            self.generate_impl(args[0])?.into_int_value()?,
-                            self.generate_impl(args[1])?.into_int_value()?, 
-                        ""
-                        )?
-                    ),
-                    ir::OpKind::IMul => Value::from_int_value(
-                        self.builder.build_int_mul(
-                            self.generate_impl(args[0])?.into_int_value()?,
-                            self.generate_impl(args[1])?.into_int_value()?, 
-                        ""
+                    ir::OpKind::IAdd | ir::OpKind::ISub | ir::OpKind::IMul => Value::from_int_value(
+                        self.generate_arithmetic(
+                            id,
+                            op,
+                            self.generate_int(args[0])?,
+                            self.generate_int(args[1])?,
                         )?
                     ),
-                    ir::OpKind::IDiv => Value::from_int_value(
-                        self.builder.build_int_signed_div(
-                            self.generate_impl(args[0])?.into_int_value()?,
-                            self.generate_impl(args[1])?.into_int_value()?, 
-                        ""
+                    ir::OpKind::IDiv | ir::OpKind::IRem => Value::from_int_value(
+                        self.generate_division(
+                            op,
+                            self.generate_int(args[0])?,
+                            self.generate_int(args[1])?,
                         )?
                     ),
+                    ir::OpKind::IPow => Value::from_int_value(self.generate_power(
+                        id,
+                        self.generate_int(args[0])?,
+                        self.generate_int(args[1])?,
+                    )?),
+                    ir::OpKind::ICmp(predicate) => {
+                        let predicate = match predicate {
+                            ir::Predicate::Eq => inkwell::IntPredicate::EQ,
+                            ir::Predicate::Ne => inkwell::IntPredicate::NE,
+                            ir::Predicate::Lt => inkwell::IntPredicate::SLT,
+                            ir::Predicate::Le => inkwell::IntPredicate::SLE,
+                            ir::Predicate::Gt => inkwell::IntPredicate::SGT,
+                            ir::Predicate::Ge => inkwell::IntPredicate::SGE,
+                        };
+                        let lhs = self.generate_int(args[0])?;
+                        let rhs = self.generate_int(args[1])?;
+                        Value::from_int_value(
+                            self.builder.build_int_compare(predicate, lhs, rhs, "")?,
+                        )
+                    }
+                    ir::OpKind::And | ir::OpKind::Xor | ir::OpKind::Or => {
+                        let lhs = self.generate_int(args[0])?;
+                        let rhs = self.generate_int(args[1])?;
+                        Value::from_int_value(match op {
+                            ir::OpKind::And => self.builder.build_and(lhs, rhs, "")?,
+                            ir::OpKind::Xor => self.builder.build_xor(lhs, rhs, "")?,
+                            _ => self.builder.build_or(lhs, rhs, "")?,
+                        })
+                    }
+                    ir::OpKind::Abs => {
+                        // negating i64::MIN overflows, which the subtraction handles
+                        // according to the overflow mode
+                        let value = self.generate_int(args[0])?;
+                        let zero = value.get_type().const_zero();
+                        let negated = self.generate_arithmetic(id, &ir::OpKind::ISub, zero, value)?;
+                        let negative = self.builder.build_int_compare(
+                            inkwell::IntPredicate::SLT,
+                            value,
+                            zero,
+                            "",
+                        )?;
+                        Value::from_int_value(
+                            self.builder
+                                .build_select(negative, negated, value, "")?
+                                .into_int_value(),
+                        )
+                    }
+                    ir::OpKind::LNot => {
+                        let cond = self.generate_condition(args[0])?;
+                        Value::from_int_value(self.builder.build_not(cond, "")?)
+                    }
+                    ir::OpKind::LAnd => Value::from_int_value(self.generate_short_circuit(args, true)?),
+                    ir::OpKind::LOr => Value::from_int_value(self.generate_short_circuit(args, false)?),
+                    ir::OpKind::If => Value::from_int_value(self.generate_if(args)?),
+                    ir::OpKind::PowMod => {
+                        let mut call_args = vec![];
+                        for &arg in args {
+                            call_args.push(self.generate_int(arg)?.into());
+                        }
+                        let call = self
+                            .builder
+                            .build_call(self.builtin("pow_mod")?, &call_args, "")?;
+                        let value = call
+                            .try_as_basic_value()
+                            .left()
+                            .ok_or(anyhow!("pow_mod returned no value"))?;
+                        Value::from_int_value(value.into_int_value())
+                    }
+                    ir::OpKind::ReadInt => {
+                        let call = self.builder.build_call(self.builtin("read_int")?, &[], "")?;
+                        let value = call
+                            .try_as_basic_value()
+                            .left()
+                            .ok_or(anyhow!("read_int returned no value"))?;
+                        Value::from_int_value(value.into_int_value())
+                    }
+                    ir::OpKind::DebugPrint(label) => {
+                        let value = self.generate_int(args[0])?;
+                        let format_str = unsafe {
+                            self.builder
+                                .build_global_string("%s = %lld\n", "debug format")
+                        }?;
+                        let label_str =
+                            unsafe { self.builder.build_global_string(label, "debug label") }?;
+                        self.builder.build_call(
+                            self.builtin("dprintf")?,
+                            &[
+                                self.context.i32_type().const_int(2, false).into(),
+                                format_str.as_pointer_value().into(),
+                                label_str.as_pointer_value().into(),
+                                value.into(),
+                            ],
+                            "",
+                        )?;
+                        Value::from_int_value(value)
+                    }
+                    ir::OpKind::Print | ir::OpKind::PrintUnsigned => {
+                        let conversion = match op {
+                            ir::OpKind::PrintUnsigned => "%llu",
+                            _ => "%lld",
+                        };
+                        let format = vec![conversion; args.len()].join(" ") + "\n";
+                        let format_str =
+                            unsafe { self.builder.build_global_string(&format, "print format") }?;
+                        let mut call_args = vec![format_str.as_pointer_value().into()];
+                        for &arg in args {
+                            call_args.push(self.generate_int(arg)?.into());
+                        }
+                        self.builder
+                            .build_call(self.builtin("printf")?, &call_args, "")?;
+                        Value::from_int_value(self.context.i64_type().const_zero())
+                    }
+                    ir::OpKind::Assert(message) => {
+                        let cond = self.generate_int(args[0])?;
+                        self.generate_assert(cond, message)?;
+                        Value::from_int_value(self.context.i64_type().const_zero())
+                    }
                 };
                 Ok(ret)
             }
         }
     }
 
+    /// Generates an internal `i64 fn.name(i64, ...)` function for every `fn` statement
+    /// under `root`; the prefix keeps them from clashing with `main`, the builtins and the
+    /// C library.
+    ///
+    /// All of them are declared before any is defined, so that a body can call its own
+    /// function.
+    fn generate_functions(&self, root: ir::Id) -> Result<()> {
+        let i64_ty = self.context.i64_type();
+        let stmts = match &self.ir_arena[root].kind {
+            ir::Kind::Seq(stmts) => stmts.clone(),
+            _ => vec![root],
+        };
+        let definitions = stmts
+            .into_iter()
+            .filter_map(|stmt| match &self.ir_arena[stmt].kind {
+                ir::Kind::Fn(name, operands) => Some((stmt, name, operands)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for &(id, name, operands) in &definitions {
+            let param_types = vec![i64_ty.into(); operands.len() - 1];
+            let function = self.module.add_function(
+                &format!("fn.{}", name),
+                i64_ty.fn_type(&param_types, false),
+                Some(inkwell::module::Linkage::Internal),
+            );
+            self.functions.borrow_mut().insert(id, function);
+        }
+
+        for (id, _, operands) in definitions {
+            let function = self.functions.borrow()[&id];
+            let body = self.context.append_basic_block(function, "entry");
+            self.builder.position_at_end(body);
+            let (&body, params) = operands
+                .split_last()
+                .ok_or(anyhow!("function has no body"))?;
+            // parameters get stack slots like `let` bindings, so variables load them alike
+            for (&param, value) in params.iter().zip(function.get_param_iter()) {
+                let ir::Kind::Param(name) = &self.ir_arena[param].kind else {
+                    anyhow::bail!("function operand is not a parameter");
+                };
+                let slot = self.build_entry_alloca(name)?;
+                self.builder.build_store(slot, value)?;
+                self.variables.borrow_mut().insert(param, slot);
+            }
+            let value = self.generate_int(body)?;
+            self.builder.build_return(Some(&value))?;
+        }
+        Ok(())
+    }
+
     pub fn generate(&self, root: ir::Id) -> Result<()> {
         let builtins = self.generate_builtins()?;
         let print_int = builtins
             .get("print_int")
             .ok_or(anyhow!("builtin function not found"))?;
+        self.generate_functions(root)?;
+
+        if let Some(section) = &self.options.result_section {
+            self.generate_result_section(section, root)?;
+        }
+        if let Some(name) = &self.options.library_entry {
+            return self.generate_library_entry(name, root);
+        }
 
         let ptr_sized_int_ty = self
             .context
@@ -160,52 +1159,682 @@ impl<'a> CodeGen<'a> {
             .add_function("main", ptr_sized_int_ty.fn_type(&[], false), None);
         let main_body = self.context.append_basic_block(main, "entry");
         self.builder.position_at_end(main_body);
+        if self.options.buffered_output {
+            self.generate_full_buffering()?;
+        }
 
-        let val = { self.generate_impl(root)?.into_int_value()? };
-        let arg = &[val.into()];
+        // every top-level statement is printed, and main returns the sum of their values;
+        // bindings, definitions, loops and print() calls have no value worth printing
+        let stmts = match &self.ir_arena[root].kind {
+            ir::Kind::Seq(stmts) => stmts.clone(),
+            _ => vec![root],
+        };
+        let mut sum = self.context.i64_type().const_zero();
+        for stmt in stmts {
+            let val = { self.generate_int(stmt)? };
+            if matches!(
+                self.ir_arena[stmt].kind,
+                ir::Kind::Let(..)
+                    | ir::Kind::Assign(..)
+                    | ir::Kind::Fn(..)
+                    | ir::Kind::Loop(_)
+                    | ir::Kind::Op(ir::OpKind::Print | ir::OpKind::PrintUnsigned, _)
+            ) {
+                continue;
+            }
+            let arg = &[val.into()];
 
-        self.builder.build_call(*print_int, arg, "")?;
+            self.builder.build_call(*print_int, arg, "")?;
+            sum = self.builder.build_int_add(sum, val, "")?;
+        }
 
-        self.builder.build_return(Some(&val))?;
+        let exit_code = self.generate_exit_code(sum)?;
+        let exit_code = self
+            .builder
+            .build_int_cast(exit_code, ptr_sized_int_ty, "")?;
+        self.builder.build_return(Some(&exit_code))?;
 
         Ok(())
     }
 
-    pub fn write_to_file(&self, file: &Path) -> Result<()> {
+    /// Stores the program's result in `section` if it is known without running it.
+    fn generate_result_section(&self, section: &str, root: ir::Id) -> Result<()> {
+        // ir::eval always wraps, so under other modes it may disagree with the program
+        if self.options.overflow != OverflowMode::Wrap || ir::reads_input(&self.ir_arena, root)? {
+            return Ok(());
+        }
+        // e.g. a failing assertion has no result
+        let Ok(result) = ir::eval(&self.ir_arena, root) else {
+            return Ok(());
+        };
+        let i8_ty = self.context.i8_type();
+        let bytes: Vec<_> = result
+            .to_le_bytes()
+            .iter()
+            .map(|&b| i8_ty.const_int(b as u64, false))
+            .collect();
+        let global = self
+            .module
+            .add_global(i8_ty.array_type(8), None, "bonsai_result");
+        global.set_initializer(&i8_ty.const_array(&bytes));
+        global.set_constant(true);
+        global.set_section(Some(section));
+        Ok(())
+    }
+
+    /// Generates `i64 name()` returning the sum of the top-level statements.
+    fn generate_library_entry(&self, name: &str, root: ir::Id) -> Result<()> {
+        let i64_ty = self.context.i64_type();
+        let function = self.module.add_function(name, i64_ty.fn_type(&[], false), None);
+        let body = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(body);
+
+        let stmts = match &self.ir_arena[root].kind {
+            ir::Kind::Seq(stmts) => stmts.clone(),
+            _ => vec![root],
+        };
+        let mut sum = i64_ty.const_zero();
+        for stmt in stmts {
+            let val = self.generate_int(stmt)?;
+            sum = self.builder.build_int_add(sum, val, "")?;
+        }
+        self.builder.build_return(Some(&sum))?;
+        Ok(())
+    }
+
+    fn generate_exit_code(&self, sum: values::IntValue<'a>) -> Result<values::IntValue<'a>> {
+        let i64_type = self.context.i64_type();
+        let max = i64_type.const_int(255, false);
+        Ok(match self.options.exit_code {
+            ExitCodeMode::Truncate => self.builder.build_and(sum, max, "")?,
+            ExitCodeMode::Saturate => {
+                let zero = i64_type.const_zero();
+                let too_big =
+                    self.builder
+                        .build_int_compare(inkwell::IntPredicate::SGT, sum, max, "")?;
+                let clamped = self
+                    .builder
+                    .build_select(too_big, max, sum, "")?
+                    .into_int_value();
+                let negative =
+                    self.builder
+                        .build_int_compare(inkwell::IntPredicate::SLT, sum, zero, "")?;
+                self.builder
+                    .build_select(negative, zero, clamped, "")?
+                    .into_int_value()
+            }
+        })
+    }
+
+    /// Emits `setvbuf(stdout, NULL, _IOFBF, BUFFER_SIZE)` at the current position.
+    fn generate_full_buffering(&self) -> Result<()> {
+        const IOFBF: u64 = 0;
+        const BUFFER_SIZE: u64 = 1 << 16;
+
+        let ptr_ty = self.context.i8_type().ptr_type(inkwell::AddressSpace::default());
+        let i32_ty = self.context.i32_type();
+        let i64_ty = self.context.i64_type();
+        let setvbuf = self.module.add_function(
+            "setvbuf",
+            i32_ty.fn_type(
+                &[ptr_ty.into(), ptr_ty.into(), i32_ty.into(), i64_ty.into()],
+                false,
+            ),
+            None,
+        );
+        let stdout_name = if cfg!(target_os = "macos") {
+            "__stdoutp"
+        } else {
+            "stdout"
+        };
+        let stdout_global = self.module.add_global(ptr_ty, None, stdout_name);
+        stdout_global.set_linkage(inkwell::module::Linkage::External);
+        let stdout = self
+            .builder
+            .build_load(ptr_ty, stdout_global.as_pointer_value(), "stdout")?;
+        self.builder.build_call(
+            setvbuf,
+            &[
+                stdout.into(),
+                ptr_ty.const_null().into(),
+                i32_ty.const_int(IOFBF, false).into(),
+                i64_ty.const_int(BUFFER_SIZE, false).into(),
+            ],
+            "",
+        )?;
+        Ok(())
+    }
+
+    /// Renders the control-flow graph of `function` as a Graphviz digraph.
+    pub fn cfg_dot(&self, function: &str) -> Result<String> {
+        let function_value = self
+            .module
+            .get_function(function)
+            .ok_or(anyhow!("function {} not found", function))?;
+        let mut out = format!("digraph \"{}\" {{\n", function);
+        for block in function_value.get_basic_blocks() {
+            let name = block.get_name().to_string_lossy();
+            writeln!(out, "    \"{}\";", name)?;
+            let Some(terminator) = block.get_terminator() else {
+                continue;
+            };
+            for i in 0..terminator.get_num_operands() {
+                if let Some(target) = terminator.get_operand(i).and_then(|op| op.right()) {
+                    writeln!(
+                        out,
+                        "    \"{}\" -> \"{}\";",
+                        name,
+                        target.get_name().to_string_lossy()
+                    )?;
+                }
+            }
+        }
+        out.push_str("}\n");
+        Ok(out)
+    }
+
+    /// Runs the optimization pipeline selected by `Options::opt_level`, if any.
+    pub fn optimize(&self) -> Result<()> {
+        let Some(level) = self.options.opt_level else {
+            return Ok(());
+        };
+        optimization_level(level)?;
+        self.module
+            .run_passes(
+                &format!("default<O{}>", level),
+                &self.target_machine,
+                inkwell::passes::PassBuilderOptions::create(),
+            )
+            .map_err(|e| anyhow!("failed to optimize module: {}", e))
+    }
+
+    /// Runs [`CodeGen::optimize`] and returns a line diff of the IR it changed.
+    pub fn optimize_with_diff(&self) -> Result<String> {
+        let before = self.llvm_ir();
+        self.optimize()?;
+        Ok(line_diff(&before, &self.llvm_ir()))
+    }
+
+    /// Returns the number of functions, including declarations, in the module.
+    pub fn function_count(&self) -> usize {
+        self.module.get_functions().count()
+    }
+
+    /// Gives up the generated module, e.g. to link it with another one before emitting.
+    ///
+    /// The module still borrows the `Context` the `CodeGen` was created with, so it
+    /// cannot outlive it and can only be combined with modules from the same context.
+    pub fn into_module(self) -> Module<'a> {
+        self.module
+    }
+
+    /// Returns the module as textual LLVM IR.
+    pub fn llvm_ir(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
+    pub fn verify(&self) -> Result<()> {
         self.module
             .verify()
-            .map_err(|e| anyhow!("module verification failed: {}", e))?;
+            .map_err(|e| anyhow!("module verification failed: {}", e))
+    }
+
+    fn verify_before_write(&self) -> Result<()> {
+        if self.options.skip_verify {
+            return Ok(());
+        }
+        self.verify()
+    }
+
+    /// Warnings about how the module will be written, for the caller to render.
+    pub fn warnings(&self) -> Vec<Diagnostic> {
+        let mut warnings = vec![];
+        if self.options.skip_verify {
+            warnings.push(Diagnostic::warning(format!(
+                "skipping verification of module {}",
+                self.module.get_name().to_string_lossy()
+            )));
+        }
+        warnings
+    }
+
+    pub fn write_to_file(&self, file: &Path) -> Result<()> {
+        self.verify_before_write()?;
         self.target_machine
             .write_to_file(&self.module, targets::FileType::Object, file)
             .map_err(|e| anyhow!("failed to write object file: {}", e))
     }
+
+    pub fn write_assembly_to_file(&self, file: &Path) -> Result<()> {
+        self.verify_before_write()?;
+        self.target_machine
+            .write_to_file(&self.module, targets::FileType::Assembly, file)
+            .map_err(|e| anyhow!("failed to write assembly file: {}", e))
+    }
+
+    pub fn write_llvm_ir_to_file(&self, file: &Path) -> Result<()> {
+        self.verify_before_write()?;
+        self.module
+            .print_to_file(file)
+            .map_err(|e| anyhow!("failed to write llvm ir file: {}", e))
+    }
+
+    pub fn write_bitcode_to_file(&self, file: &Path) -> Result<()> {
+        self.verify_before_write()?;
+        if !self.module.write_bitcode_to_path(file) {
+            anyhow::bail!("failed to write bitcode file: {}", file.display());
+        }
+        Ok(())
+    }
 }
 
-pub fn get_host_target_machine() -> Result<targets::TargetMachine> {
+/// Settings for the machine the code is generated for.
+#[derive(Debug, Clone, Default)]
+pub struct TargetOptions {
+    /// Comma-separated `+feature`/`-feature` toggles applied on top of the host features.
+    pub target_features: Option<String>,
+    /// `0..=3`, as in `-O2`; `None` uses LLVM's default code generation level.
+    pub opt_level: Option<u8>,
+    /// Generate position-independent code, as shared libraries require.
+    pub pic: bool,
+    /// Target triple to generate code for, e.g. `wasm32-unknown-unknown`; the host when
+    /// `None`. Host CPU features are only used for the host.
+    pub triple: Option<String>,
+}
+
+/// Maps `-O0`..`-O3` onto LLVM's optimization levels.
+pub fn optimization_level(level: u8) -> Result<inkwell::OptimizationLevel> {
+    use inkwell::OptimizationLevel;
+
+    match level {
+        0 => Ok(OptimizationLevel::None),
+        1 => Ok(OptimizationLevel::Less),
+        2 => Ok(OptimizationLevel::Default),
+        3 => Ok(OptimizationLevel::Aggressive),
+        _ => Err(anyhow!("invalid optimization level {}, expected 0 to 3", level)),
+    }
+}
+
+/// Applies `+feature`/`-feature` toggles to a comma-separated LLVM feature string.
+///
+/// A toggle overrides any existing entry for the same feature; new features are appended.
+pub fn merge_target_features(base: &str, toggles: &str) -> Result<String> {
+    let mut features = base
+        .split(',')
+        .filter(|f| !f.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    for toggle in toggles.split(',').filter(|t| !t.is_empty()) {
+        let name = toggle
+            .strip_prefix('+')
+            .or_else(|| toggle.strip_prefix('-'))
+            .ok_or(anyhow!(
+                "target feature '{}' must start with '+' or '-'",
+                toggle
+            ))?;
+        features.retain(|f| &f[1..] != name);
+        features.push(toggle.to_string());
+    }
+    Ok(features.join(","))
+}
+
+/// Returns the name and description of every target registered with LLVM.
+pub fn available_targets() -> Vec<(String, String)> {
     use targets::*;
 
-    Target::initialize_native(&InitializationConfig::default())
-        .map_err(|e| anyhow!("failed to initialize native target: {}", e))?;
+    Target::initialize_all(&InitializationConfig::default());
 
-    let triple = TargetMachine::get_default_triple();
-    let target =
-        Target::from_triple(&triple).map_err(|e| anyhow!("failed to get target: {}", e))?;
+    let mut found = Vec::new();
+    let mut next = Target::get_first();
+    while let Some(target) = next {
+        found.push((
+            target.get_name().to_string_lossy().into_owned(),
+            target.get_description().to_string_lossy().into_owned(),
+        ));
+        next = target.get_next();
+    }
+    found
+}
 
-    let cpu = TargetMachine::get_host_cpu_name();
-    let features = TargetMachine::get_host_cpu_features();
+pub fn get_host_target_machine() -> Result<targets::TargetMachine> {
+    get_target_machine(&TargetOptions::default())
+}
 
-    let opt_level = inkwell::OptimizationLevel::Default;
-    let reloc_mode = RelocMode::Default;
+pub fn get_target_machine(options: &TargetOptions) -> Result<targets::TargetMachine> {
+    use targets::*;
+
+    let (triple, cpu, base_features) = match &options.triple {
+        Some(triple) => {
+            Target::initialize_all(&InitializationConfig::default());
+            (
+                TargetTriple::create(triple),
+                "generic".to_string(),
+                String::new(),
+            )
+        }
+        None => {
+            let triple = TargetMachine::get_default_triple();
+            let cpu = TargetMachine::get_host_cpu_name().to_str()?.to_string();
+            Target::initialize_native(&InitializationConfig::default()).map_err(|e| {
+                anyhow!(
+                    "failed to initialize the native target for host '{}' (cpu '{}'): {}\n\
+                     the host is likely unsupported by, or missing from, the LLVM this \
+                     compiler was built with; --list-targets shows what is available",
+                    triple.as_str().to_string_lossy(),
+                    cpu,
+                    e
+                )
+            })?;
+            let features = TargetMachine::get_host_cpu_features().to_str()?.to_string();
+            (triple, cpu, features)
+        }
+    };
+    let target = Target::from_triple(&triple).map_err(|e| {
+        anyhow!(
+            "no LLVM target for triple '{}': {}\n\
+             the triple may be misspelled or its target not built into this compiler; \
+             --list-targets shows what is available",
+            triple.as_str().to_string_lossy(),
+            e.to_string().trim_end()
+        )
+    })?;
+
+    let features = match &options.target_features {
+        Some(toggles) => merge_target_features(&base_features, toggles)?,
+        None => base_features,
+    };
+
+    let opt_level = match options.opt_level {
+        Some(level) => optimization_level(level)?,
+        None => inkwell::OptimizationLevel::Default,
+    };
+    let reloc_mode = if options.pic {
+        RelocMode::PIC
+    } else {
+        RelocMode::Default
+    };
     let code_model = CodeModel::Default;
 
     target
         .create_target_machine(
             &triple,
-            cpu.to_str()?,
-            features.to_str()?,
+            &cpu,
+            &features,
             opt_level,
             reloc_mode,
             code_model,
         )
-        .ok_or(anyhow!("failed to get target machine"))
+        .ok_or(anyhow!(
+            "failed to create a target machine for '{}' (cpu '{}', features '{}')",
+            triple.as_str().to_string_lossy(),
+            cpu,
+            features
+        ))
+}
+
+/// The template statements are printed with unless `Options::print_format` is set.
+pub const DEFAULT_PRINT_FORMAT: &str = "result: {}\n";
+
+/// Translates a print template such as `=> {}\n` into a `printf` format string.
+///
+/// The single `{}` becomes the value; `\n`, `\t` and `\\` are escapes and a stray `%`
+/// is printed literally.
+pub fn printf_format(template: &str) -> Result<String> {
+    if template.matches("{}").count() != 1 {
+        anyhow::bail!(
+            "print format '{}' must contain exactly one '{{}}'",
+            template
+        );
+    }
+    let mut format = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.next_if_eq(&'}').is_some() => format.push_str("%lld"),
+            '%' => format.push_str("%%"),
+            '\\' => match chars.next() {
+                Some('n') => format.push('\n'),
+                Some('t') => format.push('\t'),
+                Some('\\') => format.push('\\'),
+                Some(other) => anyhow::bail!("unknown escape '\\{}' in print format", other),
+                None => anyhow::bail!("print format ends with a lone '\\'"),
+            },
+            c => format.push(c),
+        }
+    }
+    Ok(format)
+}
+
+/// Lists the lines only in `before` prefixed with `-` and those only in `after` with `+`,
+/// in the order of a longest common subsequence of the two.
+pub fn line_diff(before: &str, after: &str) -> String {
+    let old: Vec<&str> = before.lines().collect();
+    let new: Vec<&str> = after.lines().collect();
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            out.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{irgen, parser};
+
+    /// Lowers `src` and generates it into a module named `test`, for a host target
+    /// machine at the optimization level of `options`.
+    fn codegen_for<'a>(context: &'a Context, src: &str, options: Options) -> Result<CodeGen<'a>> {
+        let (ast_arena, ast_root) = parser::parse(src)?;
+        let (ir_arena, ir_root) = irgen::generate(ast_arena, ast_root, src)?;
+        let target_machine = get_target_machine(&TargetOptions {
+            opt_level: options.opt_level,
+            ..Default::default()
+        })?;
+        let codegen = CodeGen::new(ir_arena, context, target_machine, "test", options);
+        codegen.generate(ir_root)?;
+        Ok(codegen)
+    }
+
+    #[test]
+    fn cfg_dot_should_include_branching_blocks() -> Result<()> {
+        let context = Context::create();
+        let codegen = codegen_for(&context, "assert(true); 2", Options::default())?;
+        let dot = codegen.cfg_dot("main")?;
+        assert!(dot.starts_with("digraph \"main\" {"));
+        assert!(dot.contains("\"entry\" -> \"assert.fail\";"));
+        assert!(dot.contains("\"entry\" -> \"assert.cont\";"));
+        assert!(dot.contains("\"assert.cont\";"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_target_machine_should_name_unknown_triple() {
+        let options = TargetOptions {
+            triple: Some("bogus-unknown-none".to_string()),
+            ..Default::default()
+        };
+        let err = get_target_machine(&options).unwrap_err().to_string();
+        assert!(err.contains("no LLVM target for triple 'bogus-unknown-none'"), "{}", err);
+        assert!(err.contains("--list-targets"), "{}", err);
+    }
+
+    #[test]
+    fn merge_target_features_should_toggle_host_features() -> Result<()> {
+        let merged = merge_target_features("+sse2,-avx2,+sse4.1", "+avx2,-sse4.1")?;
+        assert_eq!(merged, "+sse2,+avx2,-sse4.1");
+        Ok(())
+    }
+
+    #[test]
+    fn merge_target_features_should_extend_real_host_features() -> Result<()> {
+        let host = targets::TargetMachine::get_host_cpu_features();
+        let merged = merge_target_features(host.to_str()?, "+avx2")?;
+        assert!(merged.split(',').any(|f| f == "+avx2"));
+        assert!(!merged.split(',').any(|f| f == "-avx2"));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_target_features_should_require_a_sign() {
+        assert!(merge_target_features("+sse2", "avx2").is_err());
+    }
+
+    #[test]
+    fn verify_should_reject_broken_module() -> Result<()> {
+        let context = Context::create();
+        let target_machine = get_host_target_machine()?;
+        let codegen = CodeGen::new(
+            ir::Arena::new(),
+            &context,
+            target_machine,
+            "broken",
+            Options::default(),
+        );
+        // a block without a terminator is invalid
+        let function = codegen.module.add_function(
+            "broken",
+            context.i64_type().fn_type(&[], false),
+            None,
+        );
+        context.append_basic_block(function, "entry");
+        assert!(codegen.verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn optimize_should_turn_multiplication_by_a_power_of_two_into_a_shift() -> Result<()> {
+        let context = Context::create();
+        let options = Options {
+            opt_level: Some(2),
+            ..Default::default()
+        };
+        // a constant product is already folded by the IR builder, so one operand is unknown
+        let codegen = codegen_for(&context, "read_int() * 8", options)?;
+        assert!(codegen.llvm_ir().contains(" mul "));
+
+        codegen.optimize()?;
+        let optimized = codegen.llvm_ir();
+        assert!(optimized.contains(" shl "), "{}", optimized);
+        assert!(!optimized.contains(" mul "), "{}", optimized);
+        Ok(())
+    }
+
+    #[test]
+    fn into_module_should_hand_over_generated_module() -> Result<()> {
+        let context = Context::create();
+        let codegen = codegen_for(&context, "6 * read_int()", Options::default())?;
+        let module = codegen.into_module();
+        assert_eq!(module.get_name().to_str()?, "test");
+        let ir = module.print_to_string().to_string();
+        assert!(ir.contains("@main()"), "{}", ir);
+        assert!(module.get_function("read_int").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn library_entry_should_replace_main() -> Result<()> {
+        let context = Context::create();
+        let options = Options {
+            library_entry: Some("answer".to_string()),
+            ..Default::default()
+        };
+        let codegen = codegen_for(&context, "print(1); 6 * 7", options)?;
+        codegen.verify()?;
+        assert!(codegen.module.get_function("main").is_none());
+        let answer = codegen
+            .module
+            .get_function("answer")
+            .ok_or(anyhow!("answer was not emitted"))?;
+        assert_eq!(answer.count_params(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn printf_format_should_translate_templates() -> Result<()> {
+        assert_eq!(printf_format(DEFAULT_PRINT_FORMAT)?, "result: %lld\n");
+        assert_eq!(printf_format("{}\\n")?, "%lld\n");
+        assert_eq!(printf_format("=> {} (100%)\\t")?, "=> %lld (100%%)\t");
+        for template in ["no value\n", "{} and {}\n"] {
+            let err = printf_format(template).unwrap_err();
+            assert!(err.to_string().contains("exactly one '{}'"), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn line_diff_should_list_removed_and_added_lines() {
+        assert_eq!(line_diff("a\nb\nc\n", "a\nx\nc\nd\n"), "-b\n+x\n+d\n");
+        assert_eq!(line_diff("a\n", "a\n"), "");
+    }
+
+    #[test]
+    fn optimize_with_diff_should_show_strength_reduced_multiplication() -> Result<()> {
+        let context = Context::create();
+        let options = Options {
+            opt_level: Some(2),
+            ..Default::default()
+        };
+        let codegen = codegen_for(&context, "read_int() * 8", options)?;
+        let diff = codegen.optimize_with_diff()?;
+        assert!(
+            diff.lines().any(|l| l.starts_with('-') && l.contains(" mul ")),
+            "{}",
+            diff
+        );
+        assert!(
+            diff.lines().any(|l| l.starts_with('+') && l.contains(" shl ")),
+            "{}",
+            diff
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_verify_should_write_broken_module() -> Result<()> {
+        let context = Context::create();
+        let target_machine = get_host_target_machine()?;
+        let options = Options {
+            skip_verify: true,
+            ..Default::default()
+        };
+        let codegen = CodeGen::new(ir::Arena::new(), &context, target_machine, "broken", options);
+        let function = codegen.module.add_function(
+            "broken",
+            context.i64_type().fn_type(&[], false),
+            None,
+        );
+        context.append_basic_block(function, "entry");
+
+        std::fs::create_dir_all("test-data")?;
+        let output = Path::new("test-data/skip_verify.ll");
+        codegen.write_llvm_ir_to_file(output)?;
+        assert!(std::fs::read_to_string(output)?.contains("define i64 @broken()"));
+        let warnings = codegen.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "skipping verification of module broken");
+        Ok(())
+    }
 }
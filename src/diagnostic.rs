@@ -0,0 +1,571 @@
+// SPDX-License-Identifier: Unlicense
+use anyhow::{anyhow, Result};
+use std::{fmt, io::IsTerminal, ops::Range, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn label(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        }
+    }
+}
+
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// Records that the source from `offset` onwards was generated from `line` of `file`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineDirective {
+    pub offset: usize,
+    pub line: usize,
+    pub file: String,
+}
+
+/// A human-facing source position, after applying any `#line` directives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A secondary span explaining part of a diagnostic, e.g. the type of an operand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+/// A message about the source, optionally pointing at a byte range of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Range<usize>>,
+    /// Where `span` starts, if it differs from its physical position in the source.
+    pub location: Option<Location>,
+    /// A stable identifier such as `E0010`, explained by [`explain`].
+    pub code: Option<&'static str>,
+    /// The machine-readable warning kind, e.g. `redundant-paren`.
+    pub kind: Option<&'static str>,
+    /// Secondary spans, underlined with `-` next to the `^` of `span`.
+    pub labels: Vec<Label>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            span: None,
+            location: None,
+            code: None,
+            kind: None,
+            labels: vec![],
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+            span: None,
+            location: None,
+            code: None,
+            kind: None,
+            labels: vec![],
+        }
+    }
+
+    pub fn with_span(mut self, span: Range<usize>) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_location(mut self, location: Location) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_kind(mut self, kind: &'static str) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn with_label(mut self, span: Range<usize>, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+}
+
+/// Error codes and their long-form explanations, as shown by `bonsaic explain`.
+pub const CODES: &[(&str, &str)] = &[
+    (
+        "E0001",
+        "The source does not match the grammar.\n\
+         \n\
+         The parser reports the tokens it expected at the first position it could not\n\
+         get past, e.g. for `6 *` it expects an operand after the `*`:\n\
+         \n\
+         \x20   6 * 7\n",
+    ),
+    (
+        "E0002",
+        "An expression is nested more deeply than the parser allows.\n\
+         \n\
+         The grammar is recursive, so parentheses are limited to 256 levels to keep the\n\
+         compiler from overflowing its stack. Split the expression into smaller parts.\n",
+    ),
+    (
+        "E0003",
+        "A reserved keyword was used as an identifier.\n\
+         \n\
+         Words such as `let` and `if` are reserved. Prefix the name with `r#` to use it\n\
+         anyway:\n\
+         \n\
+         \x20   r#let(1)\n",
+    ),
+    (
+        "E0004",
+        "A parenthesized list of expressions was written, but there are no tuples.\n\
+         \n\
+         Parentheses only group a single expression:\n\
+         \n\
+         \x20   (1 + 2) * 3\n",
+    ),
+    (
+        "E0005",
+        "A name was given a second `define`.\n\
+         \n\
+         Each name can be defined once. Pick a different name for the second definition:\n\
+         \n\
+         \x20   define WIDTH 80\n\
+         \x20   define HEIGHT 24\n",
+    ),
+    (
+        "E0006",
+        "A defined name was used before the line that defines it.\n\
+         \n\
+         Defines are expanded from top to bottom, so move the `define` above its uses:\n\
+         \n\
+         \x20   define WIDTH 80\n\
+         \x20   WIDTH * 2\n",
+    ),
+    (
+        "E0007",
+        "Comparison operators were chained.\n\
+         \n\
+         A comparison evaluates to a bool, so `1 < 2 < 3` would compare that bool with 3.\n\
+         Combine the comparisons with `&&` instead:\n\
+         \n\
+         \x20   1 < 2 && 2 < 3\n",
+    ),
+    (
+        "E0008",
+        "A variable bound without `mut` was assigned to.\n\
+         \n\
+         Bindings are immutable unless declared with `let mut`:\n\
+         \n\
+         \x20   let mut total = 1\n\
+         \x20   total = total * 2\n",
+    ),
+    (
+        "E0009",
+        "`break` or `continue` was used outside of a loop.\n\
+         \n\
+         Both jump within the innermost `for` loop around them, so they can only appear in\n\
+         the body of one, e.g. in a conditional there:\n\
+         \n\
+         \x20   for i in 0..10 { if i > 5 { break }; print(i) }\n",
+    ),
+    (
+        "E0010",
+        "A constant expression divides by zero.\n\
+         \n\
+         With checked arithmetic enabled, division or remainder by a constant zero is\n\
+         rejected at compile time instead of trapping when the program runs:\n\
+         \n\
+         \x20   1 / (2 - 2)\n",
+    ),
+    (
+        "E0011",
+        "A constant expression overflows 64 bits.\n\
+         \n\
+         With checked arithmetic enabled, an operation on constants whose result does not\n\
+         fit in a signed 64-bit integer is rejected instead of wrapping:\n\
+         \n\
+         \x20   9223372036854775807 + 1\n",
+    ),
+    (
+        "E0012",
+        "A constant argument of `pow_mod` is out of range.\n\
+         \n\
+         The modulus must be positive and the exponent must not be negative:\n\
+         \n\
+         \x20   pow_mod(2, 10, 1000)\n",
+    ),
+    (
+        "E0013",
+        "A `let` binds the same name twice.\n\
+         \n\
+         The bindings of one `let` are separated by commas, and each needs its own name.\n\
+         Later bindings can use earlier ones:\n\
+         \n\
+         \x20   let width = 80, area = width * 24\n",
+    ),
+    (
+        "E0014",
+        "A bool was used where an integer is expected, or the other way around.\n\
+         \n\
+         Arithmetic and bitwise operators and the bounds of a `for` loop take integers;\n\
+         conditions, `!`, `&&` and `||` take bools. Compare an integer to get a bool:\n\
+         \n\
+         \x20   if count != 0 { print(count) }\n",
+    ),
+];
+
+/// Returns the long-form explanation of the error `code`, e.g. `E0010`.
+pub fn explain(code: &str) -> Result<&'static str> {
+    CODES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|&(_, text)| text)
+        .ok_or(anyhow!("unknown error code '{}'", code))
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    Always,
+    #[default]
+    Auto,
+    Never,
+}
+
+impl ColorChoice {
+    /// Decides whether output written to stderr should be colored.
+    ///
+    /// `Auto` colors only when stderr is a terminal and `NO_COLOR` is not set.
+    pub fn should_color(&self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+            }
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(ColorChoice::Always),
+            "auto" => Ok(ColorChoice::Auto),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(anyhow!(
+                "unknown color choice '{}', expected one of: always, auto, never",
+                s
+            )),
+        }
+    }
+}
+
+/// Converts a byte offset into a 1-based (line, column) pair.
+///
+/// Columns count Unicode scalar values, so `é` advances the column by one even though
+/// it is two bytes long. An offset inside a character is treated as that character.
+pub fn position(source: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Resolves a byte offset to a location, honoring the last directive on a line before
+/// it. A remapped line number that does not fit in `usize` leaves the location physical.
+pub fn locate(source: &str, offset: usize, directives: &[LineDirective]) -> Location {
+    let (line, column) = position(source, offset);
+    // the directive names the line following it, so it does not apply to its own line
+    let remapped = directives
+        .iter()
+        .rev()
+        .map(|d| (d, position(source, d.offset).0))
+        .find(|&(d, directive_line)| d.offset <= offset && directive_line < line)
+        .and_then(|(directive, directive_line)| {
+            let line = directive.line.checked_add(line - directive_line - 1)?;
+            Some((directive.file.clone(), line))
+        });
+    match remapped {
+        Some((file, line)) => Location {
+            file: Some(file),
+            line,
+            column,
+        },
+        None => Location {
+            file: None,
+            line,
+            column,
+        },
+    }
+}
+
+pub fn render_diagnostic(diag: &Diagnostic, source: &str, color: bool) -> String {
+    let paint = |style: &str, text: &str| {
+        if color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    };
+
+    let label = match diag.code {
+        Some(code) => format!("{}[{}]", diag.severity.label(), code),
+        None => diag.severity.label().to_string(),
+    };
+    let mut out = format!(
+        "{}{}\n",
+        paint(diag.severity.color(), &label),
+        paint(BOLD, &format!(": {}", diag.message))
+    );
+
+    if let Some(span) = &diag.span {
+        let (physical_line, column) = position(source, span.start);
+        let location = diag
+            .location
+            .clone()
+            .unwrap_or_else(|| locate(source, span.start, &[]));
+        let line = location.line;
+        let text = source.lines().nth(physical_line - 1).unwrap_or("");
+        let gutter = " ".repeat(line.to_string().len());
+        let width = |span: &Range<usize>| {
+            source
+                .get(span.clone())
+                .map_or(1, |s| s.lines().next().unwrap_or("").chars().count().max(1))
+        };
+        match &location.file {
+            Some(file) => out += &format!("{}--> {}:{}:{}\n", gutter, file, line, location.column),
+            None => out += &format!("{}--> {}:{}\n", gutter, line, location.column),
+        }
+        out += &format!("{} |\n", gutter);
+        out += &format!("{} | {}\n", line, text);
+
+        // labels on the same line share the underline; the others are listed below it
+        let (mut inline, other): (Vec<_>, Vec<_>) = diag
+            .labels
+            .iter()
+            .partition(|label| position(source, label.span.start).0 == physical_line);
+        inline.sort_by_key(|label| label.span.start);
+        let mut marks = vec![(' ', ""); column - 1];
+        let mut mark = |start: usize, width: usize, mark: (char, &'static str)| {
+            if marks.len() < start + width {
+                marks.resize(start + width, (' ', ""));
+            }
+            marks[start..start + width].fill(mark);
+        };
+        for label in &inline {
+            let start = position(source, label.span.start).1 - 1;
+            mark(start, width(&label.span), ('-', BLUE));
+        }
+        mark(column - 1, width(span), ('^', diag.severity.color()));
+        let render_marks = |marks: &[(char, &'static str)]| {
+            marks
+                .iter()
+                .map(|&(c, style)| match style {
+                    "" => c.to_string(),
+                    style => paint(style, &c.to_string()),
+                })
+                .collect::<String>()
+        };
+        let mut underline = render_marks(&marks);
+        // the rightmost label, if it ends the line, is written right after its underline
+        if let Some(last) = inline.last() {
+            if last.span.start > span.start {
+                underline += &format!(" {}", paint(BLUE, &last.message));
+                inline.pop();
+            }
+        }
+        out += &format!("{} | {}\n", gutter, underline.trim_end());
+        if !inline.is_empty() {
+            let starts: Vec<usize> = inline
+                .iter()
+                .map(|label| position(source, label.span.start).1 - 1)
+                .collect();
+            let connectors = |count: usize| {
+                let mut line = vec![(' ', ""); starts[count - 1] + 1];
+                for &start in &starts[..count] {
+                    line[start] = ('|', BLUE);
+                }
+                line
+            };
+            out += &format!("{} | {}\n", gutter, render_marks(&connectors(starts.len())));
+            for (i, label) in inline.iter().enumerate().rev() {
+                let mut prefix = if i == 0 { vec![] } else { connectors(i) };
+                prefix.resize(starts[i], (' ', ""));
+                let message = paint(BLUE, &label.message);
+                out += &format!("{} | {}{}\n", gutter, render_marks(&prefix), message);
+            }
+        }
+        for label in other {
+            let (label_line, label_column) = position(source, label.span.start);
+            out += &format!(
+                "{} = {}:{}: {}\n",
+                gutter, label_line, label_column, label.message
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_should_count_lines_and_columns() {
+        let src = "1 +\n2 *\n  3";
+        assert_eq!(position(src, 0), (1, 1));
+        assert_eq!(position(src, 4), (2, 1));
+        assert_eq!(position(src, 10), (3, 3));
+    }
+
+    #[test]
+    fn position_should_count_characters_not_bytes() {
+        let src = "/* héllo */ 6 *";
+        assert_eq!(position(src, src.len()), (1, 16));
+        // inside the two-byte 'é'
+        assert_eq!(position(src, 5), (1, 5));
+    }
+
+    #[test]
+    fn locate_should_apply_directives_from_the_next_line() {
+        let directive = |line| LineDirective {
+            offset: 11,
+            line,
+            file: "a".to_string(),
+        };
+        let src = "#line 5 \"a\" )\n)\n)";
+        let location = |offset, line| locate(src, offset, &[directive(line)]);
+        assert_eq!(
+            location(12, 5),
+            Location {
+                file: None,
+                line: 1,
+                column: 13
+            }
+        );
+        assert_eq!(
+            location(14, 5),
+            Location {
+                file: Some("a".to_string()),
+                line: 5,
+                column: 1
+            }
+        );
+        assert_eq!(location(12, 0).line, 1);
+
+        // a line past usize::MAX stays physical instead of overflowing
+        assert_eq!(location(14, usize::MAX).line, usize::MAX);
+        assert_eq!(
+            location(16, usize::MAX),
+            Location {
+                file: None,
+                line: 3,
+                column: 1
+            }
+        );
+    }
+
+    #[test]
+    fn render_should_point_at_span() {
+        let diag = Diagnostic::error("something went wrong").with_span(4..5);
+        let out = render_diagnostic(&diag, "6 * x", false);
+        assert!(out.contains("error: something went wrong"));
+        assert!(out.contains("1 | 6 * x"));
+        assert!(out.contains("  |     ^"));
+    }
+
+    #[test]
+    fn render_should_underline_labeled_operands() {
+        let diag = Diagnostic::error("mismatched types")
+            .with_span(2..3)
+            .with_label(0..1, "this is i64")
+            .with_label(4..8, "this is bool");
+        let out = render_diagnostic(&diag, "1 + true", false);
+        assert!(out.contains("1 | 1 + true\n"), "{}", out);
+        assert!(out.contains("  | - ^ ---- this is bool\n"), "{}", out);
+        assert!(out.contains("  | |\n  | this is i64\n"), "{}", out);
+    }
+
+    #[test]
+    fn render_should_show_error_code() {
+        let diag = Diagnostic::error("division by zero").with_code("E0010");
+        let out = render_diagnostic(&diag, "1 / 0", false);
+        assert!(out.starts_with("error[E0010]: division by zero"));
+    }
+
+    #[test]
+    fn explain_should_describe_known_codes() -> Result<()> {
+        assert!(explain("E0010")?.contains("divides by zero"));
+        for (code, _) in CODES {
+            assert!(!explain(code)?.is_empty());
+        }
+        let err = explain("E9999").unwrap_err();
+        assert_eq!(err.to_string(), "unknown error code 'E9999'");
+        Ok(())
+    }
+
+    #[test]
+    fn color_never_should_not_emit_escape_sequences() -> Result<()> {
+        let color: ColorChoice = "never".parse()?;
+        let diag = Diagnostic::error("bad").with_span(0..1);
+        let out = render_diagnostic(&diag, "x", color.should_color());
+        assert!(!out.contains('\x1b'));
+        Ok(())
+    }
+
+    #[test]
+    fn color_always_should_emit_escape_sequences() -> Result<()> {
+        let color: ColorChoice = "always".parse()?;
+        let diag = Diagnostic::warning("odd").with_span(0..1);
+        let out = render_diagnostic(&diag, "x", color.should_color());
+        assert!(out.contains("\x1b[1;33m"));
+        Ok(())
+    }
+}
@@ -1,41 +1,719 @@
 // SPDX-License-Identifier: Unlicense
-use std::{path::{Path, PathBuf}, io::Read};
+use std::{path::{Path, PathBuf}, io::Read, str::FromStr, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex}, time::{Duration, Instant}};
 
-use crate::{codegen, irgen, parser};
+use crate::{ast, codegen, diagnostic::{Diagnostic, Severity}, fold, ir, irgen, lint, parser, pass::Pass};
 use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+pub use crate::codegen::{ExitCodeMode, OverflowMode};
+
+/// The artifact produced by `--emit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmitKind {
+    Object,
+    Assembly,
+    LlvmIr,
+    Bitcode,
+    Ast,
+    Ir,
+    Dot,
+    Tokens,
+    Summary,
+    Dylib,
+}
+
+impl EmitKind {
+    const NAMES: &'static [(&'static str, EmitKind)] = &[
+        ("object", EmitKind::Object),
+        ("obj", EmitKind::Object),
+        ("asm", EmitKind::Assembly),
+        ("llvm-ir", EmitKind::LlvmIr),
+        ("llvm-bc", EmitKind::Bitcode),
+        ("ast", EmitKind::Ast),
+        ("ir", EmitKind::Ir),
+        ("dot", EmitKind::Dot),
+        ("tokens", EmitKind::Tokens),
+        ("summary", EmitKind::Summary),
+        ("dylib", EmitKind::Dylib),
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EmitKind::Object => "o",
+            EmitKind::Assembly => "s",
+            EmitKind::LlvmIr => "ll",
+            EmitKind::Bitcode => "bc",
+            EmitKind::Ast => "ast",
+            EmitKind::Ir => "ir",
+            EmitKind::Dot => "dot",
+            EmitKind::Tokens => "tokens",
+            EmitKind::Summary => "summary",
+            EmitKind::Dylib if cfg!(windows) => "dll",
+            EmitKind::Dylib if cfg!(target_os = "macos") => "dylib",
+            EmitKind::Dylib => "so",
+        }
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|&(_, kind)| kind)
+            .ok_or_else(|| {
+                let names = Self::NAMES.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+                anyhow!(
+                    "unknown emit kind '{}', expected one of: {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Stop after producing this artifact instead of linking an executable.
+    pub emit: Option<EmitKind>,
+    /// Where to write the final artifact; derived from the source name when `None`.
+    pub output: Option<PathBuf>,
+    /// Make the compiled program print bare numbers, one per line.
+    pub quiet_runtime: bool,
+    /// Reject constant expressions that overflow instead of leaving them to wrap at runtime.
+    pub checked_arithmetic: bool,
+    /// `+feature`/`-feature` toggles merged into the host CPU features.
+    pub target_features: Option<String>,
+    /// Also write the control-flow graph of `main` next to the output as `<stem>.cfg.dot`.
+    pub dump_cfg: bool,
+    /// How a result outside 0..=255 becomes the process exit code.
+    pub exit_code: ExitCodeMode,
+    /// Write artifacts without running the LLVM module verifier first.
+    pub skip_verify: bool,
+    /// Stop running passes after the one with this name; `none` runs no passes at all.
+    pub after_pass: Option<String>,
+    /// Give the intermediate object file a per-compilation name and delete it after
+    /// linking, so concurrent builds of same-named sources don't clobber each other.
+    pub unique_objects: bool,
+    /// Optimization level `0..=3` for the LLVM pipeline and code generator.
+    pub opt_level: Option<u8>,
+    /// Print the LLVM IR to stdout once the optimization pipeline has run.
+    pub print_ir_after_codegen: bool,
+    /// Print the LLVM IR before optimization and a line diff of what the pipeline changed.
+    pub opt_diff: bool,
+    /// Reject programs whose IR is nested deeper than this before generating code.
+    pub max_ir_depth: Option<usize>,
+    /// Make the compiled program buffer stdout and write it all at exit.
+    pub buffered_output: bool,
+    /// Warning kinds, e.g. `redundant-paren`, reported as errors instead.
+    pub werror_on: Vec<String>,
+    /// Reject source files larger than this many bytes; [`MAX_SOURCE_SIZE`] when `None`.
+    pub max_source_size: Option<u64>,
+    /// Have the CLI print the time spent in each phase to stdout as JSON after compiling.
+    /// The library always returns them in [`CompileReport::timings`].
+    pub dump_timings_json: bool,
+    /// Keep compiling the remaining files of a batch after one of them fails.
+    pub keep_going: bool,
+    /// Arguments appended, in order, to the linker command line.
+    pub cc_args: Vec<String>,
+    /// What `+`, `-` and `*` do when their result overflows at runtime.
+    pub overflow: OverflowMode,
+    /// Template each statement's value is printed with, e.g. `=> {}\n`.
+    pub print_format: Option<String>,
+    /// Emit the program as a function returning its result instead of as `main`, for
+    /// linking into a host program. Nothing is printed.
+    pub no_main: bool,
+    /// Name of the function emitted with `no_main`; [`DEFAULT_ENTRY_NAME`] when `None`.
+    pub entry_name: Option<String>,
+    /// Reuse object files from this directory, keyed by a hash of the source and options,
+    /// instead of generating code again.
+    pub cache_dir: Option<PathBuf>,
+    /// Ignore `cache_dir`.
+    pub no_cache: bool,
+    /// Append an entry describing how the object was built to this `compile_commands.json`.
+    pub compile_commands: Option<PathBuf>,
+    /// Write a Makefile rule listing the final artifact's inputs to this path.
+    pub depfile: Option<PathBuf>,
+    /// How many files [`compile_many`] compiles at once; the number of CPUs when `None`.
+    /// `Some(1)` compiles on the calling thread only.
+    pub jobs: Option<usize>,
+    /// Store a constant result in this object section, e.g. [`DEFAULT_RESULT_SECTION`].
+    pub result_section: Option<String>,
+    /// Tag every node of an emitted AST with its byte range.
+    pub ast_spans: bool,
+    /// Target triple to compile for; the host when `None`. A WebAssembly target produces
+    /// a `.wasm` object instead of a linked executable.
+    pub target: Option<String>,
+    /// Where intermediate and emitted artifacts go unless `output` names them; next to
+    /// the source when `None`. Created if missing.
+    pub object_dir: Option<PathBuf>,
+}
+
+impl CompileOptions {
+    fn read_source(&self, source: &Path) -> Result<String> {
+        read_file_with_limit(source, self.max_source_size.unwrap_or(MAX_SOURCE_SIZE))
+    }
+
+    fn builtin_passes(&self) -> Vec<Box<dyn Pass>> {
+        let mut passes: Vec<Box<dyn Pass>> = vec![Box::new(fold::ConstantFolding {
+            checked: self.checked_arithmetic,
+            overflow: self.overflow,
+        })];
+        // combining constants skips intermediate results that other modes would check
+        if self.overflow == OverflowMode::Wrap && !self.checked_arithmetic {
+            passes.push(Box::new(fold::Reassociation));
+        }
+        passes
+    }
+
+    fn target_options(&self) -> codegen::TargetOptions {
+        codegen::TargetOptions {
+            target_features: self.target_features.clone(),
+            opt_level: self.opt_level,
+            pic: false,
+            triple: self.target.clone(),
+        }
+    }
+
+    fn targets_wasm(&self) -> bool {
+        self.target.as_deref().is_some_and(|t| t.starts_with("wasm"))
+    }
+
+    fn codegen_options(&self) -> codegen::Options {
+        codegen::Options {
+            quiet_runtime: self.quiet_runtime,
+            exit_code: self.exit_code,
+            skip_verify: self.skip_verify,
+            opt_level: self.opt_level,
+            buffered_output: self.buffered_output,
+            overflow: self.overflow,
+            print_format: self.print_format.clone(),
+            library_entry: self.no_main.then(|| {
+                self.entry_name
+                    .clone()
+                    .unwrap_or(DEFAULT_ENTRY_NAME.to_string())
+            }),
+            result_section: self.result_section.clone(),
+        }
+    }
+}
+
+/// Lists the targets LLVM was built with, one `name - description` per line.
+pub fn list_targets() -> String {
+    let mut targets = codegen::available_targets();
+    targets.sort();
+    targets
+        .into_iter()
+        .map(|(name, description)| format!("{} - {}\n", name, description))
+        .collect()
+}
+
+/// Checks that `template` is a valid `CompileOptions::print_format`.
+pub fn validate_print_format(template: &str) -> Result<()> {
+    codegen::printf_format(template).map(|_| ())
+}
+
+/// The section `--result-section` stores a constant result in.
+pub const DEFAULT_RESULT_SECTION: &str = ".bonsai_result";
+
+/// The function a program is emitted as with `CompileOptions::no_main`.
+pub const DEFAULT_ENTRY_NAME: &str = "bonsai_main";
+
+/// Largest source file, in bytes, read unless `CompileOptions::max_source_size` says otherwise.
+pub const MAX_SOURCE_SIZE: u64 = 16 << 20;
 
 pub fn read_file(source: &Path) -> Result<String> {
-    let mut buf = String::new();
+    read_file_with_limit(source, MAX_SOURCE_SIZE)
+}
+
+/// Reads `source`, failing before reading anything if it is larger than `limit` bytes.
+pub fn read_file_with_limit(source: &Path, limit: u64) -> Result<String> {
     let mut f = std::fs::File::open(source)?;
-    f.read_to_string(&mut buf)?;
+    if f.metadata()?.len() > limit {
+        return Err(anyhow!("source file exceeds maximum size of {} bytes", limit));
+    }
+    let mut buf = String::new();
+    // the file may grow between the check and the read
+    f.take(limit + 1).read_to_string(&mut buf)?;
+    if buf.len() as u64 > limit {
+        return Err(anyhow!("source file exceeds maximum size of {} bytes", limit));
+    }
     Ok(buf)
 }
 
-pub fn generate_object_from_string(name: &str, source: &str, out_dir: Option<PathBuf>) -> Result<PathBuf> {
+/// Lints the AST, failing with the first warning whose kind is in `options.werror_on`.
+/// The remaining warnings are returned.
+fn apply_lints(
+    ast_arena: &ast::Arena,
+    ast_root: ast::Id,
+    options: &CompileOptions,
+) -> Result<Vec<Diagnostic>> {
+    if let Some(kind) = options
+        .werror_on
+        .iter()
+        .find(|kind| !lint::KINDS.contains(&kind.as_str()))
+    {
+        anyhow::bail!(
+            "unknown warning kind '{}', expected one of: {}",
+            kind,
+            lint::KINDS.join(", ")
+        );
+    }
+    let warnings = lint::lint(ast_arena, ast_root)?;
+    let escalated = |diag: &Diagnostic| {
+        diag.kind
+            .is_some_and(|kind| options.werror_on.iter().any(|k| k == kind))
+    };
+    if let Some(diag) = warnings.iter().find(|diag| escalated(diag)) {
+        let mut diag = diag.clone();
+        diag.severity = Severity::Error;
+        return Err(diag.into());
+    }
+    Ok(warnings)
+}
+
+/// Returns the warnings for the source file at `path`, or an error if one of them was
+/// escalated through `options.werror_on`.
+pub fn lint_file(path: &Path, options: &CompileOptions) -> Result<Vec<Diagnostic>> {
+    let source = options.read_source(path)?;
+    let (ast_arena, ast_root) = parser::parse(&source)?;
+    apply_lints(&ast_arena, ast_root, options)
+}
+
+/// Lowers the AST to IR and runs the built-in passes followed by `passes`.
+fn lower(
+    source: &str,
+    ast_arena: ast::Arena,
+    ast_root: ast::Id,
+    options: &CompileOptions,
+    passes: &[Box<dyn Pass>],
+) -> Result<(ir::Arena, ir::Id)> {
+    let (mut ir_arena, ir_root) = irgen::generate(ast_arena, ast_root, source)?;
+    let ir_root = run_passes(&mut ir_arena, ir_root, options, passes)?;
+    Ok((ir_arena, ir_root))
+}
+
+fn check_ir_depth(ir_arena: &ir::Arena, ir_root: ir::Id, options: &CompileOptions) -> Result<()> {
+    let Some(max) = options.max_ir_depth else {
+        return Ok(());
+    };
+    let depth = ir::depth(ir_arena, ir_root)?;
+    if depth > max {
+        anyhow::bail!("ir depth {} exceeds the maximum of {}", depth, max);
+    }
+    Ok(())
+}
+
+fn run_passes(
+    ir_arena: &mut ir::Arena,
+    mut ir_root: ir::Id,
+    options: &CompileOptions,
+    passes: &[Box<dyn Pass>],
+) -> Result<ir::Id> {
+    let builtin_passes = options.builtin_passes();
+    let all_passes = builtin_passes.iter().chain(passes).collect::<Vec<_>>();
+    let count = match options.after_pass.as_deref() {
+        None => all_passes.len(),
+        Some("none") => 0,
+        Some(name) => {
+            let position = all_passes.iter().position(|pass| pass.name() == name);
+            match position {
+                Some(position) => position + 1,
+                None => {
+                    let names = all_passes.iter().map(|pass| pass.name()).collect::<Vec<_>>();
+                    anyhow::bail!(
+                        "unknown pass '{}', expected one of: none, {}",
+                        name,
+                        names.join(", ")
+                    )
+                }
+            }
+        }
+    };
+    for pass in &all_passes[..count] {
+        ir_root = pass.run(ir_arena, ir_root)?;
+        ir::validate(ir_arena, ir_root)
+            .map_err(|e| anyhow!("pass {} produced invalid ir: {}", pass.name(), e))?;
+    }
+    Ok(ir_root)
+}
+
+/// Writes the `emit` artifact for `source` to exactly `output`.
+pub fn emit_to_path(
+    name: &str,
+    source: &str,
+    output: &Path,
+    emit: EmitKind,
+    options: &CompileOptions,
+) -> Result<()> {
+    emit_impl(name, source, output, emit, options, &[], &mut CompileReport::default())
+}
+
+/// How long each phase of a compilation took.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Timings {
+    pub parse: Duration,
+    /// Lowering to IR, including the IR passes.
+    pub irgen: Duration,
+    /// Generating, optimizing and writing the LLVM module.
+    pub codegen: Duration,
+    pub link: Duration,
+}
+
+impl Timings {
+    /// Renders the timings in milliseconds as a JSON object, e.g. `{"parse_ms":0.125,...}`.
+    pub fn to_json(&self) -> String {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        format!(
+            "{{\"parse_ms\":{:.3},\"irgen_ms\":{:.3},\"codegen_ms\":{:.3},\"link_ms\":{:.3}}}",
+            ms(self.parse),
+            ms(self.irgen),
+            ms(self.codegen),
+            ms(self.link)
+        )
+    }
+}
+
+/// What a compilation produced besides its artifacts, for the caller to show.
+#[derive(Debug, Default, Clone)]
+pub struct CompileReport {
+    pub timings: Timings,
+    /// The LLVM IR that `print_ir_after_codegen` and `opt_diff` ask for.
+    pub llvm_ir: String,
+    /// Warnings from code generation, e.g. that `skip_verify` left the module unchecked.
+    pub warnings: Vec<Diagnostic>,
+}
+
+fn emit_impl(
+    name: &str,
+    source: &str,
+    output: &Path,
+    emit: EmitKind,
+    options: &CompileOptions,
+    passes: &[Box<dyn Pass>],
+    report: &mut CompileReport,
+) -> Result<()> {
+    if emit == EmitKind::Tokens {
+        let tokens = parser::tokenize(source)?;
+        std::fs::write(output, tokens.join("\n") + "\n")?;
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let (ast_arena, ast_root) = parser::parse(source)?;
+    report.timings.parse = start.elapsed();
+    apply_lints(&ast_arena, ast_root, options)?;
+    if emit == EmitKind::Ast {
+        let dump = match options.ast_spans {
+            true => ast::dump_with_spans(&ast_arena, ast_root)?,
+            false => ast::dump(&ast_arena, ast_root)?,
+        };
+        std::fs::write(output, dump + "\n")?;
+        return Ok(());
+    }
+    if emit == EmitKind::Summary {
+        let summary = summarize(name, source, output, ast_arena, ast_root, options, passes)?;
+        std::fs::write(output, summary)?;
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let (ir_arena, ir_root) = lower(source, ast_arena, ast_root, options, passes)?;
+    report.timings.irgen = start.elapsed();
+    match emit {
+        EmitKind::Ir => {
+            std::fs::write(output, ir::dump(&ir_arena, ir_root)?)?;
+            return Ok(());
+        }
+        EmitKind::Dot => {
+            std::fs::write(output, ir::to_dot(&ir_arena, ir_root)?)?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    check_ir_depth(&ir_arena, ir_root, options)?;
+
+    let start = Instant::now();
+    let context = inkwell::context::Context::create();
+    let target_options = codegen::TargetOptions {
+        pic: emit == EmitKind::Dylib,
+        ..options.target_options()
+    };
+    let target_machine = codegen::get_target_machine(&target_options)?;
+    let codegen = codegen::CodeGen::new(
+        ir_arena,
+        &context,
+        target_machine,
+        name,
+        options.codegen_options(),
+    )
+    .with_source(source);
+    codegen.generate(ir_root)?;
+    if options.opt_diff {
+        report.llvm_ir += &codegen.llvm_ir();
+        report.llvm_ir += &codegen.optimize_with_diff()?;
+    } else {
+        codegen.optimize()?;
+    }
+    if options.print_ir_after_codegen {
+        report.llvm_ir += &codegen.llvm_ir();
+    }
+    if options.dump_cfg {
+        std::fs::write(output.with_extension("cfg.dot"), codegen.cfg_dot("main")?)?;
+    }
+    report.warnings.extend(codegen.warnings());
+    let written = match emit {
+        EmitKind::Object => codegen.write_to_file(output),
+        EmitKind::Assembly => codegen.write_assembly_to_file(output),
+        EmitKind::LlvmIr => codegen.write_llvm_ir_to_file(output),
+        EmitKind::Bitcode => codegen.write_bitcode_to_file(output),
+        EmitKind::Dylib => {
+            let object = output.with_extension("o");
+            codegen.write_to_file(&object)?;
+            let mut args = vec!["-shared".to_string()];
+            args.extend(options.cc_args.iter().cloned());
+            let linked = link_impl(&object, output, &args);
+            let _ = std::fs::remove_file(&object);
+            linked.map(|_| ())
+        }
+        EmitKind::Ast
+        | EmitKind::Ir
+        | EmitKind::Dot
+        | EmitKind::Tokens
+        | EmitKind::Summary => unreachable!(),
+    };
+    report.timings.codegen = start.elapsed();
+    written
+}
+
+/// Compiles `source` in memory and reports sizes and counts from each stage.
+fn summarize(
+    name: &str,
+    source: &str,
+    output: &Path,
+    ast_arena: ast::Arena,
+    ast_root: ast::Id,
+    options: &CompileOptions,
+    passes: &[Box<dyn Pass>],
+) -> Result<String> {
+    let ast_nodes = ast_arena.len();
+    let (mut ir_arena, ir_root) = irgen::generate(ast_arena, ast_root, source)?;
+    let ir_nodes_before = ir::node_count(&ir_arena, ir_root)?;
+    let ir_root = run_passes(&mut ir_arena, ir_root, options, passes)?;
+    let ir_nodes_after = ir::node_count(&ir_arena, ir_root)?;
+    check_ir_depth(&ir_arena, ir_root, options)?;
+
+    let context = inkwell::context::Context::create();
+    let target_machine = codegen::get_target_machine(&options.target_options())?;
+    let codegen = codegen::CodeGen::new(
+        ir_arena,
+        &context,
+        target_machine,
+        name,
+        options.codegen_options(),
+    );
+    codegen.generate(ir_root)?;
+    codegen.optimize()?;
+    let verification = match codegen.verify() {
+        Ok(()) => "passed".to_string(),
+        Err(e) => format!("failed ({})", e),
+    };
+
+    Ok(format!(
+        "input: {} bytes\n\
+         ast nodes: {}\n\
+         ir nodes: {} before passes, {} after\n\
+         llvm functions: {}\n\
+         verification: {}\n\
+         output: {}\n",
+        source.len(),
+        ast_nodes,
+        ir_nodes_before,
+        ir_nodes_after,
+        codegen.function_count(),
+        verification,
+        output.display()
+    ))
+}
+
+/// Runs the front end on `source` and, with `with_codegen` set, also builds and verifies
+/// the LLVM module. Nothing is written to disk.
+pub fn check_string(
+    name: &str,
+    source: &str,
+    options: &CompileOptions,
+    with_codegen: bool,
+) -> Result<()> {
     let (ast_arena, ast_root) = parser::parse(source)?;
-    let (ir_arena, ir_root) = irgen::generate(ast_arena, ast_root)?;
+    apply_lints(&ast_arena, ast_root, options)?;
+    let (ir_arena, ir_root) = lower(source, ast_arena, ast_root, options, &[])?;
+    if !with_codegen {
+        return Ok(());
+    }
+    check_ir_depth(&ir_arena, ir_root, options)?;
+
     let context = inkwell::context::Context::create();
-    let target_machine = codegen::get_host_target_machine()?;
-    let codegen = codegen::CodeGen::new(ir_arena, &context, target_machine, name);
+    let target_machine = codegen::get_target_machine(&options.target_options())?;
+    let codegen = codegen::CodeGen::new(
+        ir_arena,
+        &context,
+        target_machine,
+        name,
+        options.codegen_options(),
+    );
     codegen.generate(ir_root)?;
+    codegen.verify()
+}
+
+/// Returns the result `source` computes if it is known at compile time.
+///
+/// `None` means the program reads input, or traps, e.g. on a failed assertion.
+pub fn static_result(source: &str) -> Result<Option<i64>> {
+    let options = CompileOptions::default();
+    let (ast_arena, ast_root) = parser::parse(source)?;
+    let (ir_arena, ir_root) = lower(source, ast_arena, ast_root, &options, &[])?;
+    if ir::reads_input(&ir_arena, ir_root)? {
+        return Ok(None);
+    }
+    Ok(ir::eval(&ir_arena, ir_root).ok())
+}
+
+/// Measures parsing, lowering and code generation of `source`, without writing any files.
+pub fn bench_compile(source: &str) -> Result<Duration> {
+    let start = Instant::now();
+    check_string("bench", source, &CompileOptions::default(), true)?;
+    Ok(start.elapsed())
+}
+
+pub fn check(source: &Path, options: &CompileOptions, with_codegen: bool) -> Result<()> {
+    let src = options.read_source(source)?;
+    let mod_name = source.file_stem().and_then(|n| n.to_str()).unwrap_or("a");
+    check_string(mod_name, &src, options, with_codegen)
+}
+
+/// Writes the `emit` artifact for `source` to `<out_dir>/<name>.<ext>`.
+pub fn emit_from_string(
+    name: &str,
+    source: &str,
+    out_dir: Option<PathBuf>,
+    emit: EmitKind,
+    options: &CompileOptions,
+) -> Result<PathBuf> {
     let mut output = out_dir.unwrap_or(std::env::current_dir()?);
     output.push(name);
-    output.set_extension("o");
-    codegen.write_to_file(&output.as_path())?;
+    output.set_extension(emit.extension());
+    emit_to_path(name, source, &output, emit, options)?;
     Ok(output)
 }
 
+pub fn generate_object_from_string(name: &str, source: &str, out_dir: Option<PathBuf>) -> Result<PathBuf> {
+    emit_from_string(name, source, out_dir, EmitKind::Object, &CompileOptions::default())
+}
+
+/// Compiles and links `source` into the executable `exe` without a source file on disk.
+pub fn compile_string(name: &str, source: &str, exe: &Path, options: &CompileOptions) -> Result<CompileReport> {
+    let object = exe.with_file_name(format!(".{}.{}.o", name, unique_suffix()));
+    let mut report = CompileReport::default();
+    let emitted = emit_impl(name, source, &object, EmitKind::Object, options, &[], &mut report);
+    let linked = emitted.and_then(|_| link_impl(&object, exe, &options.cc_args));
+    let _ = std::fs::remove_file(&object);
+    linked.map(|_| report)
+}
+
+/// Compiles `source` as the module `eval` into a temporary executable and runs it with
+/// the compiler's stdin, stdout and stderr.
+///
+/// `on_compile` is called with the report of the compilation before the program runs.
+pub fn eval_string(
+    source: &str,
+    options: &CompileOptions,
+    on_compile: impl FnOnce(&CompileReport),
+) -> Result<std::process::ExitStatus> {
+    let exe = std::env::temp_dir()
+        .join(format!("eval-{}{}", unique_suffix(), std::env::consts::EXE_SUFFIX));
+    on_compile(&compile_string("eval", source, &exe, options)?);
+    let status = std::process::Command::new(&exe).status();
+    let _ = std::fs::remove_file(&exe);
+    Ok(status?)
+}
+
+/// Returns `<pid>-<n>`, distinct for every call across threads and live processes.
+fn unique_suffix() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!("{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
 pub fn execute_linker(source: &Path) -> Result<PathBuf> {
-    let cc = std::env::var("CC").unwrap_or("gcc".into());
     let ext = if cfg!(windows) { "exe" } else { "" };
 
     let mut output_path = PathBuf::from(source);
     output_path.set_extension(ext);
-    
-    let compiling = std::process::Command::new(cc)
+    link(source, &output_path)?;
+    Ok(output_path)
+}
+
+/// What the linker printed while producing `exe`.
+#[derive(Debug, Clone)]
+pub struct LinkResult {
+    pub exe: PathBuf,
+    pub stdout: String,
+    pub stderr: String,
+    pub status: i32,
+}
+
+/// Links the object file `source` into the executable `output_path`.
+///
+/// The linker writes to a temporary file next to `output_path`, which is renamed into
+/// place only on success, so a failed link never leaves a partial executable behind.
+pub fn link(source: &Path, output_path: &Path) -> Result<()> {
+    link_with_output(source, output_path).map(|_| ())
+}
+
+/// Like [`link`], but also returns the linker's output, e.g. to surface warnings.
+pub fn link_with_output(source: &Path, output_path: &Path) -> Result<LinkResult> {
+    link_impl(source, output_path, &[])
+}
+
+fn link_impl(source: &Path, output_path: &Path, extra_args: &[String]) -> Result<LinkResult> {
+    let file_name = output_path
+        .file_name()
+        .ok_or(anyhow!("invalid output path {}", output_path.display()))?;
+    let temp_path = output_path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        unique_suffix()
+    ));
+
+    match run_linker(source, &temp_path, extra_args) {
+        Ok(mut result) => {
+            std::fs::rename(&temp_path, output_path)?;
+            result.exe = output_path.to_path_buf();
+            Ok(result)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Builds the `$CC` invocation linking `source` into `output_path`, with `extra_args`
+/// appended in order after the standard arguments.
+fn linker_command(source: &Path, output_path: &Path, extra_args: &[String]) -> std::process::Command {
+    let cc = std::env::var("CC").unwrap_or("gcc".into());
+    let mut command = std::process::Command::new(cc);
+    command
         .args(vec![source.as_os_str() , std::ffi::OsStr::new("-o"), output_path.as_os_str()])
-        .output()?;
+        .args(extra_args);
+    command
+}
 
+fn run_linker(source: &Path, output_path: &Path, extra_args: &[String]) -> Result<LinkResult> {
+    let compiling = linker_command(source, output_path, extra_args).output()?;
+
+    let stdout = String::from_utf8(compiling.stdout)?;
     let stderr = String::from_utf8(compiling.stderr)?;
     let status = compiling
         .status
@@ -49,47 +727,1952 @@ pub fn execute_linker(source: &Path) -> Result<PathBuf> {
         ));
     }
 
-    Ok(output_path)
+    Ok(LinkResult {
+        exe: output_path.to_path_buf(),
+        stdout,
+        stderr,
+        status,
+    })
 }
 
 pub fn compile(source: &Path) -> Result<PathBuf> {
-    let src = read_file(source)?;
-    let out_dir = PathBuf::from(source.parent().unwrap_or(&source));
+    compile_with_options(source, &CompileOptions::default())
+}
+
+pub fn compile_with_options(source: &Path, options: &CompileOptions) -> Result<PathBuf> {
+    compile_impl(source, options, &[])
+}
+
+/// Compiles each of `sources`, returning the result for every file attempted in input order.
+///
+/// Stops after the first failure unless `options.keep_going` is set. Up to `options.jobs`
+/// files are compiled at once on separate threads. An inkwell `Context` is not `Send`, so
+/// every compilation creates its own on the thread running it; nothing LLVM-owned is
+/// shared between files.
+pub fn compile_many(
+    sources: &[PathBuf],
+    options: &CompileOptions,
+) -> Vec<(PathBuf, Result<(PathBuf, CompileReport)>)> {
+    let jobs = options
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, sources.len().max(1));
+    let results: Vec<Option<Result<(PathBuf, CompileReport)>>> = if jobs == 1 {
+        let mut results = vec![];
+        for source in sources {
+            let result = compile_with_report(source, options);
+            let failed = result.is_err();
+            results.push(Some(result));
+            if failed && !options.keep_going {
+                break;
+            }
+        }
+        results
+    } else {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let results = Mutex::new((0..sources.len()).map(|_| None).collect::<Vec<_>>());
+        std::thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| {
+                    // files are claimed in order, so the attempted ones are always a prefix
+                    while options.keep_going || !failed.load(Ordering::SeqCst) {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(source) = sources.get(i) else { break };
+                        let result = compile_with_report(source, options);
+                        if result.is_err() {
+                            failed.store(true, Ordering::SeqCst);
+                        }
+                        results.lock().unwrap()[i] = Some(result);
+                    }
+                });
+            }
+        });
+        results.into_inner().unwrap()
+    };
+
+    let mut attempted = vec![];
+    for (source, result) in sources.iter().zip(results) {
+        let Some(result) = result else { break };
+        let failed = result.is_err();
+        attempted.push((source.clone(), result));
+        // files after the first failure may have been compiled concurrently; drop them
+        if failed && !options.keep_going {
+            break;
+        }
+    }
+    attempted
+}
+
+/// Compiles every `.bonsai` file directly inside `dir` with [`compile_many`], in name order.
+///
+/// A directory without any is an error rather than an empty batch.
+pub fn compile_dir(
+    dir: &Path,
+    options: &CompileOptions,
+) -> Result<Vec<(PathBuf, Result<(PathBuf, CompileReport)>)>> {
+    let mut sources = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "bonsai") {
+            sources.push(path);
+        }
+    }
+    if sources.is_empty() {
+        anyhow::bail!("no sources found in {}", dir.display());
+    }
+    sources.sort();
+    Ok(compile_many(&sources, options))
+}
+
+/// Compiles `source`, then recompiles it whenever its contents change, checking every
+/// `interval`.
+///
+/// `on_compile` is called with each result and returns whether to keep watching; errors
+/// are passed to it rather than ending the loop.
+pub fn watch(
+    source: &Path,
+    options: &CompileOptions,
+    interval: Duration,
+    mut on_compile: impl FnMut(Result<(PathBuf, CompileReport)>) -> bool,
+) -> Result<()> {
+    // comparing contents rather than mtimes also catches edits within the mtime resolution
+    let mut last = std::fs::read(source)?;
+    if !on_compile(compile_with_report(source, options)) {
+        return Ok(());
+    }
+    loop {
+        std::thread::sleep(interval);
+        let Ok(current) = std::fs::read(source) else {
+            // the file may be briefly missing while an editor replaces it
+            continue;
+        };
+        if current != last {
+            last = current;
+            if !on_compile(compile_with_report(source, options)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Compiles `source` into an executable, running `passes` over the IR after the built-in
+/// passes and before codegen.
+pub fn compile_with_passes(source: &Path, passes: &[Box<dyn Pass>]) -> Result<PathBuf> {
+    compile_impl(source, &CompileOptions::default(), passes)
+}
+
+/// Returns the directory derived outputs of `source` go to: its parent, or the current
+/// directory for a bare file name.
+fn output_dir(source: &Path) -> Result<PathBuf> {
+    match source.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => Ok(parent.to_path_buf()),
+        _ => Ok(std::env::current_dir()?),
+    }
+}
+
+/// Compiles and links `source`, returning what the linker printed.
+///
+/// Fails if `options.emit` is set, since nothing is linked then.
+pub fn compile_with_link_result(source: &Path, options: &CompileOptions) -> Result<LinkResult> {
+    match compile_and_link(source, options, &[])? {
+        Compiled::Emitted(path) => Err(anyhow!("{} was emitted without linking", path.display())),
+        Compiled::Linked(result) => Ok(result),
+    }
+}
+
+enum Compiled {
+    Emitted(PathBuf),
+    Linked(LinkResult),
+}
+
+fn compile_impl(source: &Path, options: &CompileOptions, passes: &[Box<dyn Pass>]) -> Result<PathBuf> {
+    match compile_and_link(source, options, passes)? {
+        Compiled::Emitted(path) => Ok(path),
+        Compiled::Linked(result) => Ok(result.exe),
+    }
+}
+
+/// Compiles and links `source` like [`compile_with_options`], also returning how long
+/// each phase took.
+pub fn compile_with_timings(source: &Path, options: &CompileOptions) -> Result<(PathBuf, Timings)> {
+    let (exe, report) = compile_with_report(source, options)?;
+    Ok((exe, report.timings))
+}
+
+/// Compiles and links `source` like [`compile_with_options`], also returning what the
+/// compilation reported besides the artifact.
+pub fn compile_with_report(source: &Path, options: &CompileOptions) -> Result<(PathBuf, CompileReport)> {
+    let mut report = CompileReport::default();
+    let exe = match compile_and_link_timed(source, options, &[], &mut report)? {
+        Compiled::Emitted(path) => path,
+        Compiled::Linked(result) => result.exe,
+    };
+    Ok((exe, report))
+}
+
+fn compile_and_link(source: &Path, options: &CompileOptions, passes: &[Box<dyn Pass>]) -> Result<Compiled> {
+    compile_and_link_timed(source, options, passes, &mut CompileReport::default())
+}
+
+/// Returns the hex SHA-256 of the compiler version, `mod_name`, `source` and the options
+/// that affect the generated object.
+fn cache_key(mod_name: &str, source: &str, options: &CompileOptions) -> String {
+    let relevant = CompileOptions {
+        output: None,
+        cache_dir: None,
+        no_cache: false,
+        compile_commands: None,
+        depfile: None,
+        jobs: None,
+        object_dir: None,
+        ..options.clone()
+    };
+    let relevant = format!("{:?}", relevant);
+    let env = env_inputs(source).join("\n");
+    let mut hasher = Sha256::new();
+    for part in [env!("CARGO_PKG_VERSION"), mod_name, relevant.as_str(), env.as_str(), source] {
+        hasher.update(part.len().to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The environment variables `source` reads with `env()`, with their current values.
+/// They are substituted at compile time, so a cached object depends on them as well.
+fn env_inputs(source: &str) -> Vec<String> {
+    // a source that does not parse never reaches the cache
+    let Ok((arena, _)) = parser::parse(source) else {
+        return vec![];
+    };
+    let mut inputs: Vec<String> = arena
+        .iter()
+        .filter_map(|(_, node)| match &node.kind {
+            ast::NodeKind::Call(name, args) if name == "env" => match args.as_slice() {
+                [arg] => match &arena[*arg].kind {
+                    ast::NodeKind::Lit(ast::LitKind::StrLit(var)) => {
+                        Some(format!("{}={:?}", var, std::env::var(var).ok()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    inputs.sort();
+    inputs.dedup();
+    inputs
+}
+
+/// Whether compiling with `options` writes or prints more than the object file.
+fn has_side_outputs(options: &CompileOptions) -> bool {
+    options.dump_cfg || options.print_ir_after_codegen || options.opt_diff
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A `compile_commands.json` entry saying `output` was built from `input` in `directory`
+/// by running `arguments`.
+pub fn compile_command_entry(
+    directory: &Path,
+    input: &Path,
+    output: &Path,
+    arguments: &[String],
+) -> String {
+    let path = |p: &Path| json_string(&p.to_string_lossy());
+    let arguments: Vec<String> = arguments.iter().map(|a| json_string(a)).collect();
+    format!(
+        "{{\"directory\":{},\"file\":{},\"output\":{},\"arguments\":[{}]}}",
+        path(directory),
+        path(input),
+        path(output),
+        arguments.join(",")
+    )
+}
+
+/// The command-line name of `mode`, or `None` for the default mode.
+fn mode_name<T: std::fmt::Debug + PartialEq>(mode: T, default: T) -> Option<String> {
+    (mode != default).then(|| format!("{:?}", mode).to_lowercase())
+}
+
+/// The `bonsaic` command line that builds `output` from `source` alone with `options`,
+/// leaving out the options that don't change the artifact.
+fn compile_arguments(
+    source: &Path,
+    output: &Path,
+    emit: EmitKind,
+    options: &CompileOptions,
+) -> Vec<String> {
+    let emit = EmitKind::NAMES
+        .iter()
+        .find(|(_, kind)| *kind == emit)
+        .map_or("object", |(name, _)| name);
+    let mut arguments = vec!["bonsaic".to_string(), format!("--emit={}", emit)];
+    let valued = [
+        ("-O", options.opt_level.map(|level| level.to_string())),
+        ("--target=", options.target.clone()),
+        ("--target-feature=", options.target_features.clone()),
+        ("--after=", options.after_pass.clone()),
+        ("--max-ir-depth=", options.max_ir_depth.map(|depth| depth.to_string())),
+        ("--overflow=", mode_name(options.overflow, OverflowMode::Wrap)),
+        ("--exit-code=", mode_name(options.exit_code, ExitCodeMode::Truncate)),
+        ("--print-format=", options.print_format.clone()),
+        ("--entry-name=", options.entry_name.clone()),
+        ("--result-section=", options.result_section.clone()),
+    ];
+    for (flag, value) in valued {
+        if let Some(value) = value {
+            arguments.push(format!("{}{}", flag, value));
+        }
+    }
+    let toggles = [
+        ("--quiet-runtime", options.quiet_runtime),
+        ("--checked-arithmetic", options.checked_arithmetic),
+        ("--skip-verify", options.skip_verify),
+        ("--buffered-output", options.buffered_output),
+        ("--no-main", options.no_main),
+    ];
+    for (flag, enabled) in toggles {
+        if enabled {
+            arguments.push(flag.to_string());
+        }
+    }
+    arguments.push("-o".to_string());
+    arguments.push(output.to_string_lossy().into_owned());
+    arguments.push(source.to_string_lossy().into_owned());
+    arguments
+}
+
+/// Appends `entry` to the JSON array in `path`, creating the file if it doesn't exist.
+pub fn append_compile_command(path: &Path, entry: &str) -> Result<()> {
+    // parallel batch builds append to the same file
+    static APPENDING: Mutex<()> = Mutex::new(());
+    let _guard = APPENDING.lock().unwrap_or_else(|e| e.into_inner());
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let existing = existing.trim();
+    let json = if existing.is_empty() || existing == "[]" {
+        format!("[\n  {}\n]\n", entry)
+    } else {
+        let entries = existing
+            .strip_suffix(']')
+            .ok_or(anyhow!("{} is not a json array", path.display()))?;
+        format!("{},\n  {}\n]\n", entries.trim_end(), entry)
+    };
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+/// Escapes `path` for use in a Makefile rule.
+fn make_escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+        .replace('$', "$$")
+}
+
+/// A Makefile rule saying `target` depends on `prerequisites`, as written by `-MD`.
+pub fn depfile_rule(target: &Path, prerequisites: &[&Path]) -> String {
+    let prerequisites: Vec<String> = prerequisites.iter().map(|p| make_escape(p)).collect();
+    format!("{}: {}\n", make_escape(target), prerequisites.join(" "))
+}
+
+fn compile_and_link_timed(
+    source: &Path,
+    options: &CompileOptions,
+    passes: &[Box<dyn Pass>],
+    report: &mut CompileReport,
+) -> Result<Compiled> {
+    let src = options.read_source(source)?;
+    let out_dir = output_dir(source)?;
     let mod_name = source.file_stem().and_then(|n| n.to_str()).unwrap_or("a");
-    let obj = generate_object_from_string(mod_name, src.as_str(), Some(out_dir))?;
-    let exe = execute_linker(obj.as_path())?;
-    Ok(exe)
+    let emit = options.emit.unwrap_or(EmitKind::Object);
+    let object_dir = options.object_dir.clone().unwrap_or(out_dir.clone());
+    let output = match (&options.output, options.emit) {
+        // an explicit output path for an emitted artifact is honored verbatim
+        (Some(path), Some(_)) => path.clone(),
+        // there is no native linker for WebAssembly, so the object is the result
+        (path, None) if options.targets_wasm() => match path {
+            Some(path) => path.clone(),
+            None => out_dir.join(mod_name).with_extension("wasm"),
+        },
+        (_, None) if options.unique_objects => object_dir
+            .join(format!("{}.{}", mod_name, unique_suffix()))
+            .with_extension(emit.extension()),
+        _ => object_dir.join(mod_name).with_extension(emit.extension()),
+    };
+    if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let cached = match &options.cache_dir {
+        // custom passes and side outputs are not part of the key
+        Some(dir) if emit == EmitKind::Object && passes.is_empty() && !options.no_cache => {
+            Some(dir.join(cache_key(mod_name, &src, options)).with_extension("o"))
+        }
+        _ => None,
+    };
+    match &cached {
+        Some(cached) if cached.is_file() && !has_side_outputs(options) => {
+            std::fs::copy(cached, &output)?;
+        }
+        _ => {
+            emit_impl(mod_name, src.as_str(), &output, emit, options, passes, report)?;
+            if let Some(cached) = &cached {
+                std::fs::create_dir_all(cached.parent().unwrap_or(Path::new(".")))?;
+                std::fs::copy(&output, cached)?;
+            }
+        }
+    }
+    if let Some(commands) = &options.compile_commands {
+        let directory = std::env::current_dir()?;
+        let arguments = compile_arguments(source, &output, emit, options);
+        let entry = compile_command_entry(&directory, source, &output, &arguments);
+        append_compile_command(commands, &entry)?;
+    }
+    let exe = match &options.output {
+        Some(exe) => exe.clone(),
+        None => out_dir
+            .join(mod_name)
+            .with_extension(if cfg!(windows) { "exe" } else { "" }),
+    };
+    let emitted = options.emit.is_some() || options.targets_wasm();
+    if !emitted {
+        if let Some(dir) = exe.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    if let Some(depfile) = &options.depfile {
+        let target = if emitted { &output } else { &exe };
+        std::fs::write(depfile, depfile_rule(target, &[source]))?;
+    }
+    if emitted {
+        return Ok(Compiled::Emitted(output));
+    }
+    let start = Instant::now();
+    let linked = link_impl(&output, &exe, &options.cc_args);
+    report.timings.link = start.elapsed();
+    if options.unique_objects {
+        let _ = std::fs::remove_file(&output);
+    }
+    linked.map(Compiled::Linked)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{env, fs::File, io::Write, path::Path, process::{Command, Output}};
+    use std::{cell::Cell, env, fs::File, io::Write, path::Path, process::{Command, Output, Stdio}, rc::Rc};
     use anyhow::Result;
     use super::*;
 
     fn compile_and_run(name: &str, src: &str) -> Result<Output> {
+        compile_and_run_with_options(name, src, &CompileOptions::default())
+    }
+
+    fn compile_source(name: &str, src: &str, options: &CompileOptions) -> Result<PathBuf> {
         let test_dir = env::current_dir()?.join("test-data");
         let src_file = test_dir.join(format!("{name}.bonsai"));
         let mut f = File::options()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&src_file)?;
 
         f.write_all(src.as_bytes())?;
-        let exe = compile(Path::new(&src_file))?;
+        compile_with_options(Path::new(&src_file), options)
+    }
+
+    fn compile_and_run_with_options(name: &str, src: &str, options: &CompileOptions) -> Result<Output> {
+        let exe = compile_source(name, src, options)?;
         let output = Command::new(exe).output()?;
         Ok(output)
     }
 
+    fn compile_and_run_with_input(
+        name: &str,
+        src: &str,
+        input: &str,
+        options: &CompileOptions,
+    ) -> Result<Output> {
+        let exe = compile_source(name, src, options)?;
+        let mut child = Command::new(exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .ok_or(anyhow!("failed to open stdin"))?
+            .write_all(input.as_bytes())?;
+        Ok(child.wait_with_output()?)
+    }
+
     #[test]
-    fn compiler_should_compile_basic_expression() -> Result<()> {
-        let src = r#"
-        6 * 7
-        "#;
+    fn compiler_should_compile_negation() -> Result<()> {
+        let output = compile_and_run("negation", "-(6 * 7) + 100; -2 * 3; --5")?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), ["result: 58", "result: -6", "result: 5"]);
+        Ok(())
+    }
 
-        let output = compile_and_run("basic_expression", src)?;
+    #[test]
+    fn compiler_should_compile_let_bindings() -> Result<()> {
+        // bindings are not printed
+        let src = "let x = 6 * 7; x; let y = read_int(); x + y; let y = y * y; y";
+        let output =
+            compile_and_run_with_input("let_bindings", src, "3\n", &CompileOptions::default())?;
         let stdout = String::from_utf8(output.stdout)?;
-        assert!(stdout.trim() == "result: 42");
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 42", "result: 45", "result: 9"]
+        );
+        assert_eq!(output.status.code(), Some(42 + 45 + 9));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_newline_separated_statements() -> Result<()> {
+        let src = "let mut x = 6\nx = x * 7 // answer\nx\n  - 2\n";
+        let output = compile_and_run("newline_statements", src)?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 40");
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_functions() -> Result<()> {
+        // definitions are not printed, and may share names with C library functions
+        let src = "fn exit(x) = x * x\nfn even(n) = n == 0 || !even(n - 1)\n\
+                   exit(read_int()) + 1\neven(10); even(7)";
+        let output =
+            compile_and_run_with_input("functions", src, "5\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 26", "result: 1", "result: 0"]
+        );
+        assert_eq!(output.status.code(), Some(27));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_conditionals() -> Result<()> {
+        // only the picked branch runs, so nothing is divided by zero
+        let src = "fn fib(n) = if n < 2 { n } else { fib(n - 1) + fib(n - 2) }\n\
+                   let n = read_int()\n\
+                   if n > 0 { fib(n) } else { 1 / n }\n\
+                   if n > 100 { print(n) } else if n > 5 { 7 }";
+        let output =
+            compile_and_run_with_input("conditionals", src, "10\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 55", "result: 7"]
+        );
+        assert_eq!(output.status.code(), Some(62));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_loops() -> Result<()> {
+        let src = "let n = read_int()\n\
+                   let mut sum = 0\n\
+                   for i in 0..n {\n\
+                     print(i)\n\
+                     for j in i..n { sum = sum + j }\n\
+                   }\n\
+                   for i in n..0 { print(i) }\n\
+                   sum";
+        let output = compile_and_run_with_input("loops", src, "3\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["0", "1", "2", "result: 8"]
+        );
+        assert_eq!(output.status.code(), Some(8));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_break_and_continue() -> Result<()> {
+        let src = "let n = read_int()\n\
+                   let mut found = 0\n\
+                   for i in 0..n {\n\
+                     if i % 2 == 0 { continue }\n\
+                     for j in 0..n { if j > 1 { break }; print(i, j) }\n\
+                     found = i\n\
+                     if i * i > n { break }\n\
+                   }\n\
+                   found";
+        let output =
+            compile_and_run_with_input("break_continue", src, "10\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["1 0", "1 1", "3 0", "3 1", "5 0", "5 1", "result: 5"]
+        );
+        assert_eq!(output.status.code(), Some(5));
+
+        let err = compile_source("stray_break", "1; break", &CompileOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("`break` outside of a loop"));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_bools_as_zero_or_one() -> Result<()> {
+        let src = "true; false; let t = true; t == !false; if read_int() > 0 { true } else { false }";
+        let output = compile_and_run_with_input("bools", src, "5\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 1", "result: 0", "result: 1", "result: 1"]
+        );
+        assert_eq!(output.status.code(), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_assignments() -> Result<()> {
+        let src = "let mut total = read_int(); total = total * 2; total = total + 1; total";
+        let output =
+            compile_and_run_with_input("assignments", src, "20\n", &CompileOptions::default())?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 41");
+
+        let err = compile_source("immutable", "let x = 1; x = 2", &CompileOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("cannot assign twice to immutable variable 'x'"));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_comparisons() -> Result<()> {
+        let src = "(3 < 5) == (10 == 10); \
+                   print(read_int() < read_int(), read_int() <= read_int(), read_int() != read_int())";
+        let output = compile_and_run_with_input(
+            "comparisons",
+            src,
+            "-1 0 7 7 -3 -3\n",
+            &CompileOptions::default(),
+        )?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["1 1 0", "result: 1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn comparisons_should_compute_bools_at_runtime() -> Result<()> {
+        // both branches are comparisons, so the conditional is a bool as well
+        let src = "let a = read_int(); let b = read_int()\n\
+                   if a < b { b - a < 10 } else { a - b < 10 }\n\
+                   (a > b) == (b < a); a == b || a != b && a < b";
+        let output = compile_and_run_with_input(
+            "bool_comparisons",
+            src,
+            "3 30\n",
+            &CompileOptions::default(),
+        )?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 0", "result: 1", "result: 1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_power() -> Result<()> {
+        // the middle two are left to the generated ipow helper
+        let src =
+            "2 ** 10; 2 ** 3 ** 2; read_int() ** read_int(); read_int() ** read_int(); 2 ** -1";
+        let output =
+            compile_and_run_with_input("power", src, "-3 3 2 -1\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 1024", "result: 512", "result: -27", "result: 0", "result: 0"]
+        );
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn power_should_follow_overflow_mode_at_runtime() -> Result<()> {
+        let cases = [
+            (OverflowMode::Wrap, "2 64", Some("result: 0")),
+            (OverflowMode::Trap, "2 64", None),
+            (OverflowMode::Trap, "-2 63", Some("result: -9223372036854775808")),
+            (OverflowMode::Saturate, "2 64", Some("result: 9223372036854775807")),
+            (OverflowMode::Saturate, "-2 65", Some("result: -9223372036854775808")),
+        ];
+        for (i, (overflow, input, expected)) in cases.into_iter().enumerate() {
+            let options = CompileOptions {
+                overflow,
+                ..Default::default()
+            };
+            let name = format!("power_{}", i);
+            let output =
+                compile_and_run_with_input(&name, "read_int() ** read_int()", input, &options)?;
+            match expected {
+                Some(stdout) => assert_eq!(String::from_utf8(output.stdout)?.trim(), stdout),
+                None => assert_eq!(
+                    String::from_utf8(output.stderr)?,
+                    "arithmetic overflow at line 1\n"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_bitwise_operators() -> Result<()> {
+        let src = "12 & 10 | 1; (3 ^ 5) * 2; read_int() & read_int() ^ read_int() | 64";
+        let output =
+            compile_and_run_with_input("bitwise", src, "12 10 -1\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 9", "result: 12", "result: -9"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn remainder_should_take_the_sign_of_the_dividend() -> Result<()> {
+        // C-style truncated remainder, both when folded and when left to codegen
+        let src = "10 % 3; -7 % 3; 7 % -3; 1 + 10 % 3; read_int() % read_int()";
+        let output = compile_and_run_with_input(
+            "remainder_sign",
+            src,
+            "-7 3\n",
+            &CompileOptions::default(),
+        )?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(
+            stdout.lines().collect::<Vec<_>>(),
+            ["result: 1", "result: -1", "result: 1", "result: 2", "result: -1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_compile_basic_expression() -> Result<()> {
+        let src = r#"
+        6 * 7
+        "#;
+
+        let output = compile_and_run("basic_expression", src)?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.trim() == "result: 42");
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_print_bare_result_in_quiet_runtime_mode() -> Result<()> {
+        let options = CompileOptions {
+            quiet_runtime: true,
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("quiet_runtime", "6 * 7", &options)?;
+        assert_eq!(String::from_utf8(output.stdout)?, "42\n");
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_read_int_from_stdin() -> Result<()> {
+        let output = compile_and_run_with_input(
+            "read_int",
+            "read_int() * 2",
+            "21\n",
+            &CompileOptions::default(),
+        )?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(stdout.trim(), "result: 42");
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_wrap_unfolded_overflow_at_runtime() -> Result<()> {
+        let output = compile_and_run("overflow_wraps", "9223372036854775807 + 1")?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(stdout.trim(), "result: -9223372036854775808");
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_honor_explicit_output_path_verbatim() -> Result<()> {
+        let output = env::current_dir()?.join("test-data").join("explicit_output.txt");
+        let _ = std::fs::remove_file(&output);
+        let options = CompileOptions {
+            emit: Some(EmitKind::LlvmIr),
+            output: Some(output.clone()),
+            ..Default::default()
+        };
+        let written = compile_source("explicit_output", "6 * 7", &options)?;
+        assert_eq!(written, output);
+        assert!(std::fs::read_to_string(&output)?.contains("define"));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_derive_output_path_from_emit_kind() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::LlvmIr),
+            ..Default::default()
+        };
+        let written = compile_source("derived_output", "6 * 7", &options)?;
+        assert_eq!(written, env::current_dir()?.join("test-data").join("derived_output.ll"));
+        assert!(written.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn check_should_verify_codegen_without_writing_files() -> Result<()> {
+        let out_dir = env::current_dir()?.join("test-data");
+        let _ = std::fs::remove_file(out_dir.join("check_only.o"));
+        check_string("check_only", "6 * 7", &CompileOptions::default(), true)?;
+        assert!(!out_dir.join("check_only.o").exists());
+        assert!(check_string("check_only", "6 *", &CompileOptions::default(), true).is_err());
+        Ok(())
+    }
+
+    struct CountingIdentity(Rc<Cell<usize>>);
+
+    impl Pass for CountingIdentity {
+        fn run(&self, _: &mut ir::Arena, root: ir::Id) -> Result<ir::Id> {
+            self.0.set(self.0.get() + 1);
+            Ok(root)
+        }
+    }
+
+    struct DoubleConstants;
+
+    impl Pass for DoubleConstants {
+        fn run(&self, arena: &mut ir::Arena, root: ir::Id) -> Result<ir::Id> {
+            for (_, node) in arena.iter_mut() {
+                if let ir::Kind::IntValue(i) = &mut node.kind {
+                    *i *= 2;
+                }
+            }
+            Ok(root)
+        }
+    }
+
+    #[test]
+    fn compiler_should_run_custom_passes() -> Result<()> {
+        let runs = Rc::new(Cell::new(0));
+        let passes: Vec<Box<dyn Pass>> = vec![
+            Box::new(CountingIdentity(runs.clone())),
+            Box::new(DoubleConstants),
+        ];
+        let src_file = env::current_dir()?.join("test-data").join("custom_passes.bonsai");
+        std::fs::write(&src_file, "6 * 7")?;
+        let exe = compile_with_passes(&src_file, &passes)?;
+        let output = Command::new(exe).output()?;
+        // the built-in folding runs first, so the folded 42 is doubled
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 84");
+        assert_eq!(runs.get(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn read_file_should_reject_oversized_source() -> Result<()> {
+        let src_file = env::current_dir()?.join("test-data").join("oversized.bonsai");
+        std::fs::create_dir_all(src_file.parent().unwrap())?;
+        std::fs::write(&src_file, "1 + 2 + 3 + 4")?;
+        let err = read_file_with_limit(&src_file, 8).unwrap_err();
+        assert_eq!(err.to_string(), "source file exceeds maximum size of 8 bytes");
+        assert_eq!(read_file_with_limit(&src_file, 13)?, "1 + 2 + 3 + 4");
+
+        let options = CompileOptions {
+            max_source_size: Some(4),
+            ..Default::default()
+        };
+        assert!(check(&src_file, &options, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compile_commands_should_record_input_and_output() -> Result<()> {
+        let commands = env::current_dir()?.join("test-data/compile_commands.json");
+        let _ = std::fs::remove_file(&commands);
+        let options = CompileOptions {
+            emit: Some(EmitKind::Object),
+            compile_commands: Some(commands.clone()),
+            ..Default::default()
+        };
+        let object = compile_source("compile_commands", "1 + 1", &options)?;
+        compile_source("compile_commands", "2 \"quoted\"", &options).unwrap_err();
+        compile_source("compile_commands", "2", &options)?;
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&commands)?)?;
+        let entries = json.as_array().ok_or(anyhow!("not a json array: {}", json))?;
+        assert_eq!(entries.len(), 2);
+        let source = env::current_dir()?.join("test-data/compile_commands.bonsai");
+        assert_eq!(entries[0]["file"], source.to_string_lossy().as_ref());
+        assert_eq!(entries[0]["output"], object.to_string_lossy().as_ref());
+        // the entry describes this file alone, not the whole command line it came from
+        let arguments: [&str; 5] = [
+            "bonsaic",
+            "--emit=object",
+            "-o",
+            &object.to_string_lossy(),
+            &source.to_string_lossy(),
+        ];
+        assert_eq!(entries[0]["arguments"], serde_json::json!(arguments));
+        Ok(())
+    }
+
+    #[test]
+    fn depfile_should_list_the_source_as_prerequisite() -> Result<()> {
+        let depfile = env::current_dir()?.join("test-data/depfile.d");
+        let options = CompileOptions {
+            emit: Some(EmitKind::Object),
+            depfile: Some(depfile.clone()),
+            ..Default::default()
+        };
+        let object = compile_source("depfile", "1 + 1", &options)?;
+        let source = env::current_dir()?.join("test-data/depfile.bonsai");
+        assert_eq!(
+            std::fs::read_to_string(&depfile)?,
+            format!("{}: {}\n", object.display(), source.display())
+        );
+        assert_eq!(
+            depfile_rule(Path::new("my out.o"), &[Path::new("$a.bonsai")]),
+            "my\\ out.o: $$a.bonsai\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compile_command_entry_should_escape_strings() -> Result<()> {
+        let args = ["bonsaic".to_string(), "--print-format=\"{}\"\n".to_string()];
+        let entry = compile_command_entry(Path::new("C:\\src"), Path::new("a.bonsai"), Path::new("a.o"), &args);
+        let json: serde_json::Value = serde_json::from_str(&entry)?;
+        assert_eq!(json["directory"], "C:\\src");
+        assert_eq!(json["arguments"][1], args[1].as_str());
+        Ok(())
+    }
+
+    #[test]
+    fn timings_should_serialize_every_phase_as_json() -> Result<()> {
+        let src_file = env::current_dir()?.join("test-data").join("timings.bonsai");
+        std::fs::create_dir_all(src_file.parent().unwrap())?;
+        std::fs::write(&src_file, "6 * 7")?;
+        let (_, timings) = compile_with_timings(&src_file, &CompileOptions::default())?;
+        let json = timings.to_json();
+        let fields = json
+            .strip_prefix('{')
+            .and_then(|json| json.strip_suffix('}'))
+            .ok_or(anyhow!("not a json object: {}", json))?;
+        let mut keys = vec![];
+        for field in fields.split(',') {
+            let (key, value) = field.split_once(':').ok_or(anyhow!("bad field: {}", field))?;
+            assert!(value.parse::<f64>()? >= 0.0, "{}", json);
+            keys.push(key.trim_matches('"'));
+        }
+        assert_eq!(keys, ["parse_ms", "irgen_ms", "codegen_ms", "link_ms"]);
+        assert!(timings.link > Duration::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn report_should_hold_requested_llvm_ir() -> Result<()> {
+        let src_file = env::current_dir()?.join("test-data").join("report_ir.bonsai");
+        std::fs::create_dir_all(src_file.parent().unwrap())?;
+        std::fs::write(&src_file, "read_int() * 8")?;
+        let (_, report) = compile_with_report(&src_file, &CompileOptions::default())?;
+        assert_eq!(report.llvm_ir, "");
+
+        let options = CompileOptions {
+            opt_level: Some(2),
+            opt_diff: true,
+            print_ir_after_codegen: true,
+            ..Default::default()
+        };
+        let (_, report) = compile_with_report(&src_file, &options)?;
+        assert!(report.llvm_ir.lines().any(|l| l.starts_with('-') && l.contains(" mul ")));
+        assert!(report.llvm_ir.contains("define"), "{}", report.llvm_ir);
+        Ok(())
+    }
+
+    #[test]
+    fn report_should_hold_skip_verify_warning() -> Result<()> {
+        let src_file = env::current_dir()?.join("test-data").join("report_warnings.bonsai");
+        std::fs::create_dir_all(src_file.parent().unwrap())?;
+        std::fs::write(&src_file, "6 * 7")?;
+        let (_, report) = compile_with_report(&src_file, &CompileOptions::default())?;
+        assert!(report.warnings.is_empty());
+
+        let options = CompileOptions {
+            skip_verify: true,
+            ..Default::default()
+        };
+        let (_, report) = compile_with_report(&src_file, &options)?;
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].severity, Severity::Warning);
+        assert_eq!(report.warnings[0].message, "skipping verification of module report_warnings");
+        Ok(())
+    }
+
+    #[test]
+    fn compile_dir_should_keep_going_past_broken_files() -> Result<()> {
+        let dir = env::current_dir()?.join("test-data").join("keep_going");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("a_broken.bonsai"), "6 *")?;
+        std::fs::write(dir.join("b_valid.bonsai"), "6 * 7")?;
+
+        let results = compile_dir(&dir, &CompileOptions::default())?;
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_err());
+
+        let options = CompileOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+        let results = compile_dir(&dir, &options)?;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.is_err());
+        let (exe, _) = results[1].1.as_ref().map_err(|e| anyhow!("{}", e))?;
+        let output = Command::new(exe).output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+        Ok(())
+    }
+
+    #[test]
+    fn compile_dir_should_reject_directory_without_sources() -> Result<()> {
+        let dir = env::current_dir()?.join("test-data").join("no_sources");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("notes.txt"), "6 * 7")?;
+
+        let err = compile_dir(&dir, &CompileOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with("no sources found in "));
+        Ok(())
+    }
+
+    #[test]
+    fn compile_many_should_match_sequential_output_when_parallel() -> Result<()> {
+        let dir = env::current_dir()?.join("test-data").join("jobs");
+        std::fs::create_dir_all(&dir)?;
+        let mut sources = vec![];
+        for (i, src) in ["6 * 7", "print(1); 2 + 3", "read_int() % 4", "abs(0 - 9)"].iter().enumerate() {
+            let source = dir.join(format!("job_{}.bonsai", i));
+            std::fs::write(&source, src)?;
+            sources.push(source);
+        }
+        let build = |jobs| -> Result<Vec<String>> {
+            let options = CompileOptions {
+                emit: Some(EmitKind::LlvmIr),
+                jobs: Some(jobs),
+                ..Default::default()
+            };
+            let mut outputs = vec![];
+            for (source, result) in compile_many(&sources, &options) {
+                let (output, _) = result.map_err(|e| anyhow!("{}: {}", source.display(), e))?;
+                outputs.push(std::fs::read_to_string(output)?);
+            }
+            Ok(outputs)
+        };
+        let sequential = build(1)?;
+        assert_eq!(sequential.len(), sources.len());
+        assert_eq!(build(4)?, sequential);
+        Ok(())
+    }
+
+    #[test]
+    fn static_result_should_evaluate_constant_programs() -> Result<()> {
+        assert_eq!(static_result("6 * 7")?, Some(42));
+        assert_eq!(static_result("print(1); 6 * 7")?, Some(42));
+        assert_eq!(static_result("read_int() * 7")?, None);
+        assert_eq!(static_result("0 * read_int()")?, None);
+        assert_eq!(static_result("assert(false)")?, None);
+        assert!(static_result("6 *").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn linker_command_should_append_cc_args_in_order() {
+        let args = vec!["-fuse-ld=lld".to_string(), "-m32".to_string()];
+        let command = linker_command(Path::new("a.o"), Path::new("a"), &args);
+        let actual: Vec<_> = command.get_args().collect();
+        assert_eq!(actual, ["a.o", "-o", "a", "-fuse-ld=lld", "-m32"]);
+    }
+
+    #[test]
+    fn compile_string_should_build_executable_without_source_file() -> Result<()> {
+        let exe = env::current_dir()?.join("test-data").join("eval");
+        compile_string("eval", "6 * 7", &exe, &CompileOptions::default())?;
+        let output = Command::new(&exe).output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+        assert!(compile_string("eval", "6 *", &exe, &CompileOptions::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_pass_true_assertion() -> Result<()> {
+        let output = compile_and_run("assert_pass", "assert(2 - 1 == 1)")?;
+        assert_eq!(output.status.code(), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_abort_on_false_assertion() -> Result<()> {
+        let output = compile_and_run("assert_fail", "1 + assert(1 - 1 > 0)")?;
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(stderr.contains("assertion failed at line 1:12: 1 - 1 > 0"));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_link_should_not_leave_partial_output() -> Result<()> {
+        let test_dir = env::current_dir()?.join("test-data");
+        let object = test_dir.join("bad_link.o");
+        let exe = test_dir.join("bad_link");
+        std::fs::write(&object, "this is not an object file")?;
+        let _ = std::fs::remove_file(&exe);
+
+        assert!(link(&object, &exe).is_err());
+        assert!(!exe.exists());
+        let leftovers = std::fs::read_dir(&test_dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(".bad_link."))
+            .count();
+        assert_eq!(leftovers, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_debug_print_and_return_value() -> Result<()> {
+        let output = compile_and_run("debug_print", "debug_print(6 * 7) + 1")?;
+        let stdout = String::from_utf8(output.stdout)?;
+        let stderr = String::from_utf8(output.stderr)?;
+        assert_eq!(stderr.trim(), "[line 1] 6 * 7 = 42");
+        assert_eq!(stdout.trim(), "result: 43");
+        Ok(())
+    }
+
+    #[test]
+    fn print_should_write_arguments_on_one_line() -> Result<()> {
+        let options = CompileOptions {
+            quiet_runtime: true,
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("print", "print(1, 2, 3)", &options)?;
+        // the statement itself prints nothing more
+        assert_eq!(String::from_utf8(output.stdout)?, "1 2 3\n");
+        Ok(())
+    }
+
+    #[test]
+    fn logical_operators_should_compute_bools_at_runtime() -> Result<()> {
+        // a conditional of logical operators is a bool as well, and widens when printed
+        let src = "let a = read_int()\n\
+                   if a > 0 { !(a > 9) && true } else { a < -9 || false }\n\
+                   a > 0 && a < 9 || !(a != 5)";
+        let output =
+            compile_and_run_with_input("bool_logic", src, "5\n", &CompileOptions::default())?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), ["result: 1", "result: 1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn print_uint_should_print_high_bit_values_as_unsigned() -> Result<()> {
+        let options = CompileOptions {
+            quiet_runtime: true,
+            ..Default::default()
+        };
+        let src = "print(0 - 1, 0 - 9223372036854775807 - 1); print_uint(0 - 1, 0 - 9223372036854775807 - 1, 7)";
+        let output = compile_and_run_with_options("print_uint", src, &options)?;
+        assert_eq!(
+            String::from_utf8(output.stdout)?,
+            "-1 -9223372036854775808\n18446744073709551615 9223372036854775808 7\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn logical_operators_should_short_circuit_at_runtime() -> Result<()> {
+        // read_int() keeps the operands from being folded away
+        let src = "read_int() != 0 && 1 / read_int() > 0; \
+                   !(read_int() != 0) || 1 / read_int() > 0";
+        let output =
+            compile_and_run_with_input("short_circuit", src, "0 0", &CompileOptions::default())?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), ["result: 0", "result: 1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_output_should_match_unbuffered_output() -> Result<()> {
+        let src = (1..=200).map(|i| format!("{} * 3", i)).collect::<Vec<_>>().join(";\n");
+        let unbuffered = compile_and_run("unbuffered", &src)?;
+        let options = CompileOptions {
+            buffered_output: true,
+            ..Default::default()
+        };
+        let buffered = compile_and_run_with_options("buffered", &src, &options)?;
+        assert_eq!(buffered.stdout, unbuffered.stdout);
+        assert_eq!(buffered.status.code(), unbuffered.status.code());
+        assert_eq!(String::from_utf8(buffered.stdout)?.lines().count(), 200);
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_print_each_statement_and_exit_with_their_sum() -> Result<()> {
+        let output = compile_and_run("statements", "1; 2; 3")?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), ["result: 1", "result: 2", "result: 3"]);
+        assert_eq!(output.status.code(), Some(6));
+        Ok(())
+    }
+
+    #[test]
+    fn quiet_runtime_should_print_one_number_per_statement() -> Result<()> {
+        let options = CompileOptions {
+            quiet_runtime: true,
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("quiet_statements", "6 * 7; 1", &options)?;
+        assert_eq!(String::from_utf8(output.stdout)?, "42\n1\n");
+        Ok(())
+    }
+
+    #[test]
+    fn exit_code_should_truncate_by_default() -> Result<()> {
+        let output = compile_and_run("exit_truncate", "300")?;
+        assert_eq!(output.status.code(), Some(44));
+        Ok(())
+    }
+
+    #[test]
+    fn exit_code_should_saturate_when_requested() -> Result<()> {
+        let options = CompileOptions {
+            exit_code: ExitCodeMode::Saturate,
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("exit_saturate", "300", &options)?;
+        assert_eq!(output.status.code(), Some(255));
+        let output = compile_and_run_with_options("exit_saturate_negative", "0 - 5", &options)?;
+        assert_eq!(output.status.code(), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_mode_should_decide_result_of_overflowing_addition() -> Result<()> {
+        let src = "9223372036854775807 + 1";
+        let output = compile_and_run("overflow_wrap", src)?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: -9223372036854775808");
+
+        let options = CompileOptions {
+            overflow: OverflowMode::Trap,
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("overflow_trap", src, &options)?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)?.contains("arithmetic overflow"));
+        let output = compile_and_run_with_options("overflow_trap_ok", "6 * 7", &options)?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+
+        let options = CompileOptions {
+            overflow: OverflowMode::Saturate,
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("overflow_saturate", src, &options)?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 9223372036854775807");
+        let output =
+            compile_and_run_with_options("overflow_saturate_mul", "(0 - 4611686018427387904) * 3", &options)?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: -9223372036854775808");
+        Ok(())
+    }
+
+    #[test]
+    fn no_main_object_should_link_into_host_program() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Object),
+            no_main: true,
+            entry_name: Some("answer".to_string()),
+            ..Default::default()
+        };
+        let object = compile_source("no_main", "print(1); 6 * 7", &options)?;
+        let host = object.with_file_name("no_main_host.c");
+        std::fs::write(
+            &host,
+            "#include <stdio.h>\n\
+             long long answer(void);\n\
+             int main(void) { printf(\"%lld\\n\", answer()); return 0; }\n",
+        )?;
+        let exe = object.with_file_name("no_main_host");
+        let cc = env::var("CC").unwrap_or("gcc".into());
+        let status = Command::new(cc)
+            .arg(&host)
+            .arg(&object)
+            .arg("-o")
+            .arg(&exe)
+            .status()?;
+        assert!(status.success());
+        let output = Command::new(&exe).output()?;
+        // print() still runs, but the statements are not printed implicitly
+        assert_eq!(String::from_utf8(output.stdout)?, "1\n42\n");
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_reuse_cached_objects() -> Result<()> {
+        let test_dir = env::current_dir()?.join("test-data");
+        let cache_dir = test_dir.join("cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let src_file = test_dir.join("cached.bonsai");
+        std::fs::write(&src_file, "6 * 7")?;
+        let options = CompileOptions {
+            cache_dir: Some(cache_dir.clone()),
+            ..Default::default()
+        };
+
+        let (_, timings) = compile_with_timings(&src_file, &options)?;
+        assert!(timings.codegen > Duration::ZERO);
+        assert_eq!(std::fs::read_dir(&cache_dir)?.count(), 1);
+        // the second compile copies the cached object instead of generating code
+        let (exe, timings) = compile_with_timings(&src_file, &options)?;
+        assert_eq!(timings.codegen, Duration::ZERO);
+        let output = Command::new(exe).output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+
+        let options = CompileOptions {
+            no_cache: true,
+            ..options
+        };
+        let (_, timings) = compile_with_timings(&src_file, &options)?;
+        assert!(timings.codegen > Duration::ZERO);
+
+        std::fs::write(&src_file, "6 * 8")?;
+        let options = CompileOptions {
+            no_cache: false,
+            ..options
+        };
+        let (_, timings) = compile_with_timings(&src_file, &options)?;
+        assert!(timings.codegen > Duration::ZERO);
+        assert_eq!(std::fs::read_dir(&cache_dir)?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cached_objects_should_depend_on_env_values() -> Result<()> {
+        let test_dir = env::current_dir()?.join("test-data");
+        let cache_dir = test_dir.join("env_cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let src_file = test_dir.join("env_cached.bonsai");
+        std::fs::write(&src_file, r#"env("BONSAI_TEST_CACHED_SCALE") * 7"#)?;
+        let options = CompileOptions {
+            cache_dir: Some(cache_dir.clone()),
+            ..Default::default()
+        };
+
+        env::set_var("BONSAI_TEST_CACHED_SCALE", "6");
+        let (exe, _) = compile_with_timings(&src_file, &options)?;
+        let output = Command::new(exe).output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+        // a different value is a different object, not a stale cache hit
+        env::set_var("BONSAI_TEST_CACHED_SCALE", "7");
+        let (exe, timings) = compile_with_timings(&src_file, &options)?;
+        assert!(timings.codegen > Duration::ZERO);
+        let output = Command::new(exe).output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 49");
+        assert_eq!(std::fs::read_dir(&cache_dir)?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn result_section_should_hold_constant_result() -> Result<()> {
+        use object::{Object, ObjectSection};
+
+        let section_data = |name: &str, src: &str, overflow| -> Result<Option<Vec<u8>>> {
+            let options = CompileOptions {
+                emit: Some(EmitKind::Object),
+                result_section: Some(DEFAULT_RESULT_SECTION.to_string()),
+                overflow,
+                ..Default::default()
+            };
+            let bytes = std::fs::read(compile_source(name, src, &options)?)?;
+            let file = object::File::parse(bytes.as_slice())?;
+            let data = match file.section_by_name(DEFAULT_RESULT_SECTION) {
+                Some(section) => Some(section.data()?.to_vec()),
+                None => None,
+            };
+            Ok(data)
+        };
+        let wrap = OverflowMode::Wrap;
+        assert_eq!(section_data("result_section", "6 * 7", wrap)?, Some(42i64.to_le_bytes().to_vec()));
+        assert_eq!(section_data("result_section_input", "read_int() * 7", wrap)?, None);
+        // the binary saturates to i64::MAX, which the wrapping evaluation would not match
+        let src = "9223372036854775807 + 1";
+        let min = i64::MIN.to_le_bytes().to_vec();
+        assert_eq!(section_data("result_section_wrap", src, wrap)?, Some(min));
+        assert_eq!(section_data("result_section_saturate", src, OverflowMode::Saturate)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn compiled_programs_should_match_ir_eval() -> Result<()> {
+        use proptest::{
+            strategy::{Strategy, ValueTree},
+            test_runner::{Config, RngAlgorithm, TestRng, TestRunner},
+        };
+
+        // a fixed seed keeps the cases, and so any failure, reproducible
+        let rng = TestRng::from_seed(RngAlgorithm::ChaCha, &[53; 32]);
+        let mut runner = TestRunner::new_with_rng(Config::default(), rng);
+        let strategy = ast::arbitrary::arithmetic_tree();
+        let mut sources = vec![];
+        let mut expected = vec![];
+        while sources.len() < 300 {
+            let tree = strategy
+                .new_tree(&mut runner)
+                .map_err(|e| anyhow!("{}", e))?
+                .current();
+            let mut arena = ast::Arena::new();
+            let root = tree.alloc(&mut arena);
+            let source = ast::format(&arena, root)?;
+            let (ast_arena, ast_root) = parser::parse(&source)?;
+            let (ir_arena, ir_root) = irgen::generate(ast_arena, ast_root, &source)?;
+            // division by zero aborts the program; overflow wraps in both, so it is kept
+            let Ok(value) = ir::eval(&ir_arena, ir_root) else {
+                continue;
+            };
+            sources.push(source);
+            expected.push(value.to_string());
+        }
+
+        let options = CompileOptions {
+            quiet_runtime: true,
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("differential", &sources.join(";\n"), &options)?;
+        let stdout = String::from_utf8(output.stdout)?;
+        let actual: Vec<&str> = stdout.lines().collect();
+        assert_eq!(actual.len(), sources.len());
+        for ((source, expected), actual) in sources.iter().zip(&expected).zip(actual) {
+            assert_eq!(actual, expected, "{}", source);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pow_mod_should_compute_modular_powers() -> Result<()> {
+        let src = "pow_mod(2, 10, 1000); pow_mod(3, 0, 7); pow_mod(read_int(), 10, 1000)";
+        let output = compile_and_run_with_input("pow_mod", src, "2", &CompileOptions::default())?;
+        assert_eq!(
+            String::from_utf8(output.stdout)?,
+            "result: 24\nresult: 1\nresult: 24\n"
+        );
+
+        let src = "pow_mod(9223372036854775807, 9223372036854775807, read_int())";
+        let output = compile_and_run_with_input(
+            "pow_mod_wide",
+            src,
+            "9223372036854775806",
+            &CompileOptions::default(),
+        )?;
+        assert_eq!(String::from_utf8(output.stdout)?, "result: 1\n");
+
+        let output = compile_and_run_with_input(
+            "pow_mod_zero",
+            "pow_mod(2, 3, read_int())",
+            "0",
+            &CompileOptions::default(),
+        )?;
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8(output.stderr)?, "pow_mod modulus must be positive\n");
+
+        let err = compile_source("pow_mod_const", "pow_mod(2, 3, 0)", &CompileOptions::default())
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Diagnostic>().and_then(|d| d.code), Some("E0012"));
+        Ok(())
+    }
+
+    #[test]
+    fn trapped_overflow_should_report_its_line() -> Result<()> {
+        let options = CompileOptions {
+            overflow: OverflowMode::Trap,
+            ..Default::default()
+        };
+        let src = "0;\nread_int() + read_int()";
+        let input = "9223372036854775807 1";
+        let output = compile_and_run_with_input("trap_line", src, input, &options)?;
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8(output.stderr)?, "arithmetic overflow at line 2\n");
+        Ok(())
+    }
+
+    #[test]
+    fn abs_of_min_should_follow_overflow_mode_at_runtime() -> Result<()> {
+        let cases = [
+            (OverflowMode::Wrap, Some("result: -9223372036854775808")),
+            (OverflowMode::Trap, None),
+            (OverflowMode::Saturate, Some("result: 9223372036854775807")),
+        ];
+        for (overflow, expected) in cases {
+            let options = CompileOptions {
+                overflow,
+                ..Default::default()
+            };
+            let name = format!("abs_{:?}", overflow).to_lowercase();
+            let input = "-9223372036854775808";
+            let output = compile_and_run_with_input(&name, "abs(read_int())", input, &options)?;
+            match expected {
+                Some(stdout) => assert_eq!(String::from_utf8(output.stdout)?.trim(), stdout),
+                None => assert!(!output.status.success()),
+            }
+        }
+        let output = compile_and_run_with_input(
+            "abs_positive",
+            "abs(read_int())",
+            "-42",
+            &CompileOptions::default(),
+        )?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+        Ok(())
+    }
+
+    #[test]
+    fn watch_should_recompile_on_change() -> Result<()> {
+        let src_file = env::current_dir()?.join("test-data").join("watch.bonsai");
+        std::fs::write(&src_file, "6 * 7")?;
+        let mut results = vec![];
+        watch(&src_file, &CompileOptions::default(), Duration::from_millis(10), |result| {
+            let stdout = match result {
+                Ok((exe, _)) => Command::new(exe)
+                    .output()
+                    .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+                    .unwrap_or_default(),
+                Err(e) => e.to_string(),
+            };
+            results.push(stdout);
+            match results.len() {
+                1 => std::fs::write(&src_file, "6 *").is_ok(),
+                2 => std::fs::write(&src_file, "6 * 8").is_ok(),
+                _ => false,
+            }
+        })?;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], "result: 42");
+        assert!(results[1].contains("failed to parse"), "{}", results[1]);
+        assert_eq!(results[2], "result: 48");
+        Ok(())
+    }
+
+    #[test]
+    fn compiled_division_should_match_interpreter() -> Result<()> {
+        let values = [i64::MIN, -7, -3, -2, -1, 0, 1, 2, 3, 7, i64::MAX];
+        let mut src = vec![];
+        let mut input = String::new();
+        let mut expected = String::new();
+        for lhs in values {
+            for rhs in values.into_iter().filter(|&rhs| rhs != 0) {
+                // operands are read at runtime so that folding leaves the division to codegen
+                src.push("print(read_int() / read_int(), read_int() % read_int())");
+                input += &format!("{} {} {} {}\n", lhs, rhs, lhs, rhs);
+                let literal = |v: i64| match v {
+                    i64::MIN => "(0 - 9223372036854775807 - 1)".to_string(),
+                    v if v < 0 => format!("(0 - {})", -v),
+                    v => v.to_string(),
+                };
+                let quotient = crate::eval_str(&format!("{} / {}", literal(lhs), literal(rhs)))?;
+                let remainder = crate::eval_str(&format!("{} % {}", literal(lhs), literal(rhs)))?;
+                expected += &format!("{} {}\n", quotient, remainder);
+            }
+        }
+        let output = compile_and_run_with_input(
+            "division_matrix",
+            &src.join(";\n"),
+            &input,
+            &CompileOptions::default(),
+        )?;
+        let stdout = String::from_utf8(output.stdout)?;
+        let printed: String = stdout
+            .lines()
+            .filter(|line| !line.starts_with("result: "))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_eq!(printed, expected);
+
+        let output = compile_and_run_with_input(
+            "division_by_zero",
+            "1 / read_int()",
+            "0",
+            &CompileOptions::default(),
+        )?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)?.contains("division by zero"));
+        Ok(())
+    }
+
+    #[test]
+    fn print_format_should_replace_result_label() -> Result<()> {
+        let options = CompileOptions {
+            print_format: Some("=> {}\\n".to_string()),
+            ..Default::default()
+        };
+        let output = compile_and_run_with_options("print_format", "6 * 7; 1", &options)?;
+        assert_eq!(String::from_utf8(output.stdout)?, "=> 42\n=> 1\n");
+
+        let options = CompileOptions {
+            print_format: Some("{} {}".to_string()),
+            ..Default::default()
+        };
+        assert!(compile_source("print_format_bad", "1", &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compiler_should_dump_cfg_next_to_output() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Object),
+            dump_cfg: true,
+            ..Default::default()
+        };
+        let object = compile_source("dump_cfg", "assert(true)", &options)?;
+        let dot = std::fs::read_to_string(object.with_extension("cfg.dot"))?;
+        assert!(dot.contains("\"entry\" -> \"assert.fail\";"));
+        Ok(())
+    }
+
+    #[test]
+    fn list_targets_should_include_host() -> Result<()> {
+        use inkwell::targets::{Target, TargetMachine};
+
+        let listing = list_targets();
+        assert!(!listing.is_empty());
+        let host = Target::from_triple(&TargetMachine::get_default_triple())
+            .map_err(|e| anyhow!("failed to get target: {}", e))?;
+        let host_name = host.get_name().to_str()?;
+        assert!(
+            listing.lines().any(|line| line.starts_with(&format!("{} - ", host_name))),
+            "{}",
+            listing
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn after_pass_should_stop_the_pipeline() -> Result<()> {
+        let emit_ir_after = |name: &str, pass: &str| -> Result<String> {
+            let options = CompileOptions {
+                emit: Some(EmitKind::Ir),
+                after_pass: Some(pass.to_string()),
+                ..Default::default()
+            };
+            Ok(std::fs::read_to_string(compile_source(name, "2+3", &options)?)?)
+        };
+        assert_eq!(emit_ir_after("after_fold", "fold")?, "%0 = int 5\n");
+        assert!(emit_ir_after("after_none", "none")?.contains("%2 = iadd %0, %1"));
+        let err = emit_ir_after("after_unknown", "inline").unwrap_err();
+        assert!(err.to_string().contains("unknown pass 'inline'"));
+        Ok(())
+    }
+
+    #[test]
+    fn bench_compile_should_measure_large_input() -> Result<()> {
+        let source = vec!["1 + 2 * 3"; 1000].join(" + ");
+        assert!(bench_compile(&source)? > Duration::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn unique_objects_should_allow_concurrent_builds_of_one_source() -> Result<()> {
+        let src_file = env::current_dir()?.join("test-data/parallel.bonsai");
+        std::fs::write(&src_file, "6 * 7")?;
+        let results = std::thread::scope(|scope| {
+            let handles = (0..2)
+                .map(|i| {
+                    let options = CompileOptions {
+                        output: Some(src_file.with_file_name(format!("parallel_{}", i))),
+                        unique_objects: true,
+                        ..Default::default()
+                    };
+                    let src_file = &src_file;
+                    scope.spawn(move || compile_with_options(src_file, &options))
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().map_err(|_| anyhow!("compile thread panicked"))?)
+                .collect::<Result<Vec<_>>>()
+        })?;
+        for exe in results {
+            let output = Command::new(&exe).output()?;
+            assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn output_dir_should_default_to_current_dir_for_bare_file_names() -> Result<()> {
+        assert_eq!(output_dir(Path::new("foo.bonsai"))?, env::current_dir()?);
+        assert_eq!(output_dir(Path::new("/tmp/foo.bonsai"))?, Path::new("/tmp"));
+        assert_eq!(output_dir(Path::new("src/foo.bonsai"))?, Path::new("src"));
+        Ok(())
+    }
+
+    #[test]
+    fn object_should_land_next_to_absolute_source() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Object),
+            ..Default::default()
+        };
+        let object = compile_source("absolute_source", "1", &options)?;
+        assert_eq!(object, env::current_dir()?.join("test-data/absolute_source.o"));
+        assert!(object.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn summary_should_report_node_counts() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Summary),
+            ..Default::default()
+        };
+        let output = compile_source("summary", "6 * 7", &options)?;
+        let summary = std::fs::read_to_string(&output)?;
+        let lines = summary.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "input: 5 bytes");
+        assert_eq!(lines[1], "ast nodes: 3");
+        assert_eq!(lines[2], "ir nodes: 3 before passes, 1 after");
+        assert!(lines[3].starts_with("llvm functions: "));
+        assert_eq!(lines[4], "verification: passed");
+        assert_eq!(lines[5], format!("output: {}", output.display()));
+        Ok(())
+    }
+
+    #[test]
+    fn max_ir_depth_should_reject_nested_expressions() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Object),
+            max_ir_depth: Some(2),
+            ..Default::default()
+        };
+        // folding would flatten constants, so keep the nesting at runtime
+        let src = "read_int() + (read_int() * (read_int() - 1))";
+        let err = compile_source("max_ir_depth", src, &options).unwrap_err();
+        assert_eq!(err.to_string(), "ir depth 4 exceeds the maximum of 2");
+        assert!(compile_source("max_ir_depth_ok", "read_int() + 1", &options).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn link_result_should_capture_linker_warnings() -> Result<()> {
+        // the GNU linker prints the contents of .gnu.warning.<symbol> when <symbol> is used
+        let c_file = env::current_dir()?.join("test-data/link_warning.c");
+        std::fs::write(
+            &c_file,
+            "__attribute__((used, section(\".gnu.warning.legacy\")))\n\
+             static const char warning[] = \"legacy is deprecated\";\n\
+             int legacy(void) { return 0; }\n\
+             int main(void) { return legacy(); }\n",
+        )?;
+        let object = c_file.with_extension("o");
+        let cc = env::var("CC").unwrap_or("gcc".into());
+        let status = Command::new(cc)
+            .arg("-c")
+            .arg(&c_file)
+            .arg("-o")
+            .arg(&object)
+            .status()?;
+        assert!(status.success());
+
+        let result = link_with_output(&object, &c_file.with_extension(""))?;
+        assert_eq!(result.status, 0);
+        assert!(result.stderr.contains("legacy is deprecated"), "{}", result.stderr);
+        assert!(result.exe.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn compile_with_link_result_should_return_executable() -> Result<()> {
+        let src_file = env::current_dir()?.join("test-data/link_result.bonsai");
+        std::fs::write(&src_file, "6 * 7")?;
+        let result = compile_with_link_result(&src_file, &CompileOptions::default())?;
+        assert_eq!(result.status, 0);
+        assert_eq!(result.exe, src_file.with_extension(""));
+        let output = Command::new(&result.exe).output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dylib_should_be_an_elf_shared_object() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Dylib),
+            ..Default::default()
+        };
+        let library = compile_source("dylib", "6 * 7", &options)?;
+        assert_eq!(library.extension().and_then(|e| e.to_str()), Some("so"));
+        let bytes = std::fs::read(&library)?;
+        assert_eq!(&bytes[..4], b"\x7fELF");
+        // e_type, little-endian on the hosts we test on
+        assert_eq!(u16::from_le_bytes([bytes[16], bytes[17]]), 3, "expected ET_DYN");
+        assert!(!library.with_extension("o").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn werror_on_should_escalate_only_the_named_kind() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Ir),
+            werror_on: vec!["redundant-paren".to_string()],
+            ..Default::default()
+        };
+        let err = compile_source("werror_paren", "(1) + 2", &options).unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(diag.severity, Severity::Error);
+        assert_eq!(diag.kind, Some("redundant-paren"));
+
+        compile_source("werror_dead_branch", "false && read_int() > 0", &options)?;
+        let src_file = env::current_dir()?.join("test-data/werror_dead_branch.bonsai");
+        let warnings = lint_file(&src_file, &options)?;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, Severity::Warning);
+        assert_eq!(warnings[0].kind, Some("dead-branch"));
+
+        let options = CompileOptions {
+            werror_on: vec!["unused".to_string()],
+            ..options
+        };
+        let err = compile_source("werror_unknown", "1", &options).unwrap_err();
+        assert!(err.to_string().contains("unknown warning kind 'unused'"));
+        Ok(())
+    }
+
+    #[test]
+    fn emit_ast_should_include_spans_on_request() -> Result<()> {
+        let options = CompileOptions {
+            emit: Some(EmitKind::Ast),
+            ast_spans: true,
+            ..Default::default()
+        };
+        let output = compile_source("ast_spans", "6 * 7", &options)?;
+        assert_eq!(std::fs::read_to_string(output)?, "(Mul@0..5 (Lit@0..1 6) (Lit@4..5 7))\n");
+        Ok(())
+    }
+
+    #[test]
+    fn wasm_target_should_produce_wasm_object() -> Result<()> {
+        let options = CompileOptions {
+            target: Some("wasm32-unknown-unknown".to_string()),
+            ..Default::default()
+        };
+        let output = compile_source("wasm", "print(1); 6 * 7", &options)?;
+        assert_eq!(output.extension().and_then(|e| e.to_str()), Some("wasm"));
+        let bytes = std::fs::read(&output)?;
+        assert_eq!(&bytes[..4], b"\0asm");
+
+        let options = CompileOptions {
+            target: Some("not-a-target".to_string()),
+            ..Default::default()
+        };
+        let err = compile_source("wasm_unknown", "1", &options).unwrap_err();
+        assert!(err.to_string().contains("no LLVM target for triple 'not-a-target'"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn wasm_target_should_write_the_named_output() -> Result<()> {
+        let test_dir = env::current_dir()?.join("test-data");
+        let output = test_dir.join("wasm_out").join("program.wasm");
+        let depfile = test_dir.join("wasm_out.d");
+        let options = CompileOptions {
+            target: Some("wasm32-unknown-unknown".to_string()),
+            output: Some(output.clone()),
+            unique_objects: true,
+            depfile: Some(depfile.clone()),
+            ..Default::default()
+        };
+        assert_eq!(compile_source("wasm_out", "6 * 7", &options)?, output);
+        assert_eq!(&std::fs::read(&output)?[..4], b"\0asm");
+        let source = test_dir.join("wasm_out.bonsai");
+        assert_eq!(
+            std::fs::read_to_string(&depfile)?,
+            format!("{}: {}\n", output.display(), source.display())
+        );
+
+        // without -o the wasm is named after the source, even with unique objects
+        let options = CompileOptions { output: None, ..options };
+        let output = compile_source("wasm_out", "6 * 7", &options)?;
+        assert_eq!(output, test_dir.join("wasm_out.wasm"));
+        assert_eq!(
+            std::fs::read_to_string(&depfile)?,
+            format!("{}: {}\n", output.display(), source.display())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn object_dir_should_hold_object_while_linking_elsewhere() -> Result<()> {
+        let test_dir = env::current_dir()?.join("test-data").join("object_dir");
+        let _ = std::fs::remove_dir_all(&test_dir);
+        let exe = test_dir.join("bin").join("object_dir");
+        let options = CompileOptions {
+            object_dir: Some(test_dir.join("obj")),
+            output: Some(exe.clone()),
+            ..Default::default()
+        };
+        assert_eq!(compile_source("object_dir", "6 * 7", &options)?, exe);
+        assert!(test_dir.join("obj").join("object_dir.o").is_file());
+        let output = Command::new(&exe).output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "result: 42");
+        Ok(())
+    }
+
+    #[test]
+    fn emit_kind_should_parse_all_names() -> Result<()> {
+        let cases = [
+            ("object", EmitKind::Object),
+            ("obj", EmitKind::Object),
+            ("asm", EmitKind::Assembly),
+            ("llvm-ir", EmitKind::LlvmIr),
+            ("llvm-bc", EmitKind::Bitcode),
+            ("ast", EmitKind::Ast),
+            ("ir", EmitKind::Ir),
+            ("dot", EmitKind::Dot),
+            ("tokens", EmitKind::Tokens),
+            ("summary", EmitKind::Summary),
+            ("dylib", EmitKind::Dylib),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(name.parse::<EmitKind>()?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn emit_kind_should_list_valid_options_on_error() {
+        let err = "exe".parse::<EmitKind>().unwrap_err().to_string();
+        assert!(err.contains("unknown emit kind 'exe'"));
+        assert!(err.contains("object, obj, asm, llvm-ir, llvm-bc, ast, ir, dot, tokens, summary, dylib"));
+    }
+}
@@ -0,0 +1,564 @@
+// SPDX-License-Identifier: Unlicense
+use crate::{codegen::OverflowMode, diagnostic::Diagnostic, ir, pass::Pass};
+use anyhow::{anyhow, Result};
+
+fn const_value(arena: &ir::Arena, id: ir::Id) -> Option<i64> {
+    match arena.get(id)?.kind {
+        ir::Kind::IntValue(i) => Some(i),
+        ir::Kind::BoolValue(b) => Some(b as i64),
+        _ => None,
+    }
+}
+
+/// Replaces the node `id` with the constant `value`, keeping it a bool if it is one.
+fn set_constant(arena: &mut ir::Arena, id: ir::Id, value: i64) {
+    arena[id].kind = match ir::is_bool(arena, id) {
+        true => ir::Kind::BoolValue(value != 0),
+        false => ir::Kind::IntValue(value),
+    };
+}
+
+/// Reports `message` at the source of the operation `id`.
+fn constant_error(
+    arena: &ir::Arena,
+    id: ir::Id,
+    message: &str,
+    code: &'static str,
+) -> anyhow::Error {
+    let mut diag = Diagnostic::error(message).with_code(code);
+    diag.span = arena[id].span.clone();
+    diag.into()
+}
+
+fn fold_impl(
+    arena: &mut ir::Arena,
+    id: ir::Id,
+    checked: bool,
+    overflow: OverflowMode,
+) -> Result<()> {
+    let kind = arena
+        .get(id)
+        .ok_or(anyhow!("failed to get ir from arena"))?
+        .kind
+        .clone();
+    // the right operand of a short-circuiting operator is never evaluated, and so must
+    // not be folded, when the left one decides the result
+    if let ir::Kind::Op(op @ (ir::OpKind::LAnd | ir::OpKind::LOr), args) = &kind {
+        fold_impl(arena, args[0], checked, overflow)?;
+        match (op, const_value(arena, args[0])) {
+            (ir::OpKind::LAnd, Some(0)) => {
+                set_constant(arena, id, 0);
+                return Ok(());
+            }
+            (ir::OpKind::LOr, Some(lhs)) if lhs != 0 => {
+                set_constant(arena, id, 1);
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    // likewise only the branch a constant condition picks is ever evaluated
+    if let ir::Kind::Op(ir::OpKind::If, args) = &kind {
+        fold_impl(arena, args[0], checked, overflow)?;
+        if let Some(cond) = const_value(arena, args[0]) {
+            let picked = if cond != 0 { args[1] } else { args[2] };
+            fold_impl(arena, picked, checked, overflow)?;
+            if let Some(value) = const_value(arena, picked) {
+                set_constant(arena, id, value);
+            }
+            return Ok(());
+        }
+    }
+    for &child in ir::children(&kind) {
+        fold_impl(arena, child, checked, overflow)?;
+    }
+    // bindings precede their uses, so a constant one has been folded by now
+    if let ir::Kind::Var(binding) = kind {
+        let assigned = arena
+            .iter()
+            .any(|(_, node)| matches!(node.kind, ir::Kind::Assign(b, _) if b == binding));
+        if let ir::Kind::Let(_, value) = arena[binding].kind {
+            match const_value(arena, value) {
+                Some(value) if !assigned => arena[id].kind = ir::Kind::IntValue(value),
+                _ => {}
+            }
+        }
+        return Ok(());
+    }
+    let ir::Kind::Op(op, args) = kind else {
+        return Ok(());
+    };
+
+    // an invalid constant modulus or exponent is an error even if the rest is unknown
+    if op == ir::OpKind::PowMod {
+        let invalid = match (const_value(arena, args[1]), const_value(arena, args[2])) {
+            (_, Some(modulus)) if modulus <= 0 => Some("pow_mod modulus must be positive"),
+            (Some(exp), _) if exp < 0 => Some("pow_mod exponent must not be negative"),
+            _ => None,
+        };
+        if let Some(message) = invalid {
+            return Err(constant_error(arena, id, message, "E0012"));
+        }
+    }
+    let Some(values) = args
+        .iter()
+        .map(|&arg| const_value(arena, arg))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Ok(());
+    };
+    let folded = match (&op, values.as_slice()) {
+        (ir::OpKind::IAdd, &[lhs, rhs]) => lhs.checked_add(rhs),
+        (ir::OpKind::ISub, &[lhs, rhs]) => lhs.checked_sub(rhs),
+        (ir::OpKind::IMul, &[lhs, rhs]) => lhs.checked_mul(rhs),
+        (ir::OpKind::IDiv | ir::OpKind::IRem, &[_, 0]) if checked => {
+            let message = "division by zero in constant expression";
+            return Err(constant_error(arena, id, message, "E0010"));
+        }
+        (ir::OpKind::IDiv, &[lhs, rhs]) => lhs.checked_div(rhs),
+        (ir::OpKind::IRem, &[lhs, rhs]) => lhs.checked_rem(rhs),
+        (ir::OpKind::IPow, &[base, exp]) => match ir::overflowing_pow(base, exp) {
+            (value, false) => Some(value),
+            (_, true) => None,
+        },
+        (&ir::OpKind::ICmp(predicate), &[lhs, rhs]) => Some(predicate.holds(lhs, rhs) as i64),
+        (ir::OpKind::And, &[lhs, rhs]) => Some(lhs & rhs),
+        (ir::OpKind::Xor, &[lhs, rhs]) => Some(lhs ^ rhs),
+        (ir::OpKind::Or, &[lhs, rhs]) => Some(lhs | rhs),
+        (ir::OpKind::Abs, &[value]) => match (value.checked_abs(), overflow) {
+            (Some(abs), _) => Some(abs),
+            (None, OverflowMode::Wrap) if !checked => Some(value),
+            (None, OverflowMode::Saturate) if !checked => Some(i64::MAX),
+            (None, _) => {
+                let message = "arithmetic overflow in constant expression";
+                return Err(constant_error(arena, id, message, "E0011"));
+            }
+        },
+        (ir::OpKind::PowMod, &[base, exp, modulus]) => ir::pow_mod(base, exp, modulus).ok(),
+        (ir::OpKind::LNot, &[value]) => Some((value == 0) as i64),
+        (ir::OpKind::LAnd, &[lhs, rhs]) => Some((lhs != 0 && rhs != 0) as i64),
+        (ir::OpKind::LOr, &[lhs, rhs]) => Some((lhs != 0 || rhs != 0) as i64),
+        _ => return Ok(()),
+    };
+    match folded {
+        Some(value) => {
+            // synthesized operations take the extent of their operands
+            if arena[id].span.is_none() {
+                arena[id].span = args
+                    .iter()
+                    .filter_map(|&arg| arena[arg].span.clone())
+                    .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end));
+            }
+            set_constant(arena, id, value);
+        }
+        None if checked => {
+            let message = "arithmetic overflow in constant expression";
+            return Err(constant_error(arena, id, message, "E0011"));
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Replaces operations whose operands are all constants with their result.
+///
+/// Folding never overflows the host: an operation whose result does not fit in `i64`
+/// (or that divides by zero) is left in place, so the program keeps the runtime
+/// semantics of the generated code. With `checked` set, such an operation is reported
+/// as a compile error instead.
+pub fn fold(arena: &mut ir::Arena, root: ir::Id, checked: bool) -> Result<ir::Id> {
+    fold_with_overflow(arena, root, checked, OverflowMode::Wrap)
+}
+
+/// Like [`fold`], but `abs(i64::MIN)` follows `overflow`: it stays `i64::MIN` when
+/// wrapping, becomes `i64::MAX` when saturating and is a compile error when trapping.
+pub fn fold_with_overflow(
+    arena: &mut ir::Arena,
+    root: ir::Id,
+    checked: bool,
+    overflow: OverflowMode,
+) -> Result<ir::Id> {
+    fold_impl(arena, root, checked, overflow)?;
+    Ok(root)
+}
+
+/// The constant folding pass; see [`fold`].
+#[derive(Debug, Clone, Default)]
+pub struct ConstantFolding {
+    pub checked: bool,
+    pub overflow: OverflowMode,
+}
+
+impl Pass for ConstantFolding {
+    fn run(&self, arena: &mut ir::Arena, root: ir::Id) -> Result<ir::Id> {
+        fold_with_overflow(arena, root, self.checked, self.overflow)
+    }
+
+    fn name(&self) -> &str {
+        "fold"
+    }
+}
+
+/// Collects the operands of the chain of `op` rooted at `id`, left to right.
+fn flatten_chain(arena: &ir::Arena, id: ir::Id, op: &ir::OpKind, operands: &mut Vec<ir::Id>) {
+    match &arena[id].kind {
+        ir::Kind::Op(o, args) if o == op => {
+            for &arg in args {
+                flatten_chain(arena, arg, op, operands);
+            }
+        }
+        _ => operands.push(id),
+    }
+}
+
+fn span_of(arena: &ir::Arena, ids: &[ir::Id]) -> Option<crate::ast::Span> {
+    ids.iter()
+        .filter_map(|&id| arena[id].span.clone())
+        .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+}
+
+fn reassociate_impl(arena: &mut ir::Arena, id: ir::Id) -> Result<()> {
+    let kind = arena
+        .get(id)
+        .ok_or(anyhow!("failed to get ir from arena"))?
+        .kind
+        .clone();
+    let op = match &kind {
+        ir::Kind::Op(op @ (ir::OpKind::IAdd | ir::OpKind::IMul), _) => op,
+        _ => {
+            for &child in ir::children(&kind) {
+                reassociate_impl(arena, child)?;
+            }
+            return Ok(());
+        }
+    };
+    let mut operands = vec![];
+    flatten_chain(arena, id, op, &mut operands);
+    let (constants, variables): (Vec<_>, Vec<_>) = operands
+        .into_iter()
+        .partition(|&operand| const_value(arena, operand).is_some());
+    for &variable in &variables {
+        reassociate_impl(arena, variable)?;
+    }
+    if constants.len() < 2 {
+        return Ok(());
+    }
+
+    let values = constants.iter().filter_map(|&c| const_value(arena, c));
+    let constant = match op {
+        ir::OpKind::IAdd => values.fold(0, i64::wrapping_add),
+        _ => values.fold(1, i64::wrapping_mul),
+    };
+    let Some((&first, rest)) = variables.split_first() else {
+        arena[id].kind = ir::Kind::IntValue(constant);
+        return Ok(());
+    };
+    // the variable operands keep their order, so their side effects do too
+    let mut chain = first;
+    for &variable in rest {
+        chain = arena.alloc(ir::Node {
+            kind: ir::Kind::Op(op.clone(), vec![chain, variable]),
+            span: span_of(arena, &[chain, variable]),
+        });
+    }
+    let constant = arena.alloc(ir::Node {
+        kind: ir::Kind::IntValue(constant),
+        span: span_of(arena, &constants),
+    });
+    arena[id].kind = ir::Kind::Op(op.clone(), vec![chain, constant]);
+    Ok(())
+}
+
+/// Gathers the constant operands of every chain of `+` or `*` into a single constant at
+/// its end, e.g. `1 + x + 2` into `x + 3`.
+///
+/// The constants are combined with wrapping arithmetic, which only preserves the
+/// program's meaning when the operations wrap at runtime; other overflow modes can
+/// observe the intermediate results this skips.
+pub fn reassociate(arena: &mut ir::Arena, root: ir::Id) -> Result<ir::Id> {
+    reassociate_impl(arena, root)?;
+    Ok(root)
+}
+
+/// The reassociation pass; see [`reassociate`].
+#[derive(Debug, Clone, Default)]
+pub struct Reassociation;
+
+impl Pass for Reassociation {
+    fn run(&self, arena: &mut ir::Arena, root: ir::Id) -> Result<ir::Id> {
+        reassociate(arena, root)
+    }
+
+    fn name(&self) -> &str {
+        "reassociate"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{irgen, parser};
+
+    fn lower(src: &str) -> Result<(ir::Arena, ir::Id)> {
+        let (ast_arena, ast_root) = parser::parse(src)?;
+        irgen::generate(ast_arena, ast_root, src)
+    }
+
+    #[test]
+    fn reassociate_should_gather_constants_of_chains() -> Result<()> {
+        for (src, op, expected) in [
+            ("1 + read_int() + 2", ir::OpKind::IAdd, 3),
+            ("2 * read_int() * 3", ir::OpKind::IMul, 6),
+        ] {
+            let (mut arena, root) = lower(src)?;
+            let root = reassociate(&mut arena, root)?;
+            let ir::Kind::Op(root_op, args) = &arena[root].kind else {
+                panic!("{}: not an operation", src);
+            };
+            assert_eq!(root_op, &op);
+            assert_eq!(
+                arena[args[0]].kind,
+                ir::Kind::Op(ir::OpKind::ReadInt, vec![])
+            );
+            assert_eq!(arena[args[1]].kind, ir::Kind::IntValue(expected));
+            assert_eq!(arena[root].span, Some(0..src.len()));
+        }
+
+        let (mut arena, root) = lower("9223372036854775807 + read_int() + 1 + read_int() * 2")?;
+        let root = reassociate(&mut arena, root)?;
+        assert_eq!(
+            ir::dump(&arena, root)?,
+            "%0 = readint\n%1 = readint\n%2 = int 2\n%3 = imul %1, %2\n\
+             %4 = iadd %0, %3\n%5 = int -9223372036854775808\n%6 = iadd %4, %5\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_collapse_constant_expressions() -> Result<()> {
+        let (mut arena, root) = lower("(1 + 2) * 14")?;
+        let root = fold(&mut arena, root, false)?;
+        assert_eq!(arena[root].kind, ir::Kind::IntValue(42));
+        Ok(())
+    }
+
+    #[test]
+    fn folded_constant_should_keep_span_of_expression() -> Result<()> {
+        let (mut arena, root) = lower("6 * 7")?;
+        let root = fold(&mut arena, root, false)?;
+        assert_eq!(arena[root].span, Some(0..5));
+
+        let lhs = arena.alloc(ir::Node {
+            kind: ir::Kind::IntValue(6),
+            span: Some(10..11),
+        });
+        let rhs = arena.alloc(ir::Node {
+            kind: ir::Kind::IntValue(7),
+            span: Some(2..3),
+        });
+        let synthesized = arena.alloc(ir::Node {
+            kind: ir::Kind::Op(ir::OpKind::IMul, vec![lhs, rhs]),
+            span: None,
+        });
+        let synthesized = fold(&mut arena, synthesized, false)?;
+        assert_eq!(arena[synthesized].span, Some(2..11));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_not_evaluate_short_circuited_operands() -> Result<()> {
+        for (src, expected) in [
+            ("false && 1 / 0 > 0", false),
+            ("3 > 2 || 1 / 0 > 0", true),
+            ("!(2 > 1 && 0 > 1)", true),
+        ] {
+            let (mut arena, root) = lower(src)?;
+            let root = fold(&mut arena, root, true)?;
+            assert_eq!(
+                arena[root].kind,
+                ir::Kind::BoolValue(expected),
+                "source: {}",
+                src
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_combine_literals_of_every_radix() -> Result<()> {
+        let cases = [
+            ("0xFF + 0b1 + 10", 266),
+            ("0xFF + 0o17 + 0b101 + 10", 285),
+            ("0b11 * 0x10 - 0o7 / 7", 47),
+            ("(0x10 + 0o10) * 0b10", 48),
+        ];
+        for (src, expected) in cases {
+            let (mut arena, root) = lower(src)?;
+            let root = fold(&mut arena, root, false)?;
+            assert_eq!(
+                arena[root].kind,
+                ir::Kind::IntValue(expected),
+                "source: {}",
+                src
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_propagate_constant_bindings() -> Result<()> {
+        let (mut arena, root) = lower("let x = 6 * 7; let y = read_int(); x + 1; y")?;
+        let root = fold(&mut arena, root, false)?;
+        let ir::Kind::Seq(stmts) = &arena[root].kind else {
+            panic!("expected a statement sequence");
+        };
+        assert_eq!(arena[stmts[2]].kind, ir::Kind::IntValue(43));
+        assert!(matches!(arena[stmts[3]].kind, ir::Kind::Var(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_not_propagate_assigned_bindings() -> Result<()> {
+        let (mut arena, root) = lower("let mut x = 1; x = read_int(); x + 1")?;
+        let root = fold(&mut arena, root, false)?;
+        let ir::Kind::Seq(stmts) = &arena[root].kind else {
+            panic!("expected a statement sequence");
+        };
+        assert!(matches!(
+            arena[stmts[2]].kind,
+            ir::Kind::Op(ir::OpKind::IAdd, _)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_not_propagate_loop_variables() -> Result<()> {
+        let (mut arena, root) = lower("for i in 0..3 { print(i * 2) }")?;
+        let root = fold(&mut arena, root, false)?;
+        let ir::Kind::Seq(stmts) = &arena[root].kind else {
+            panic!("expected a statement sequence");
+        };
+        let ir::Kind::Loop(operands) = &arena[stmts[2]].kind else {
+            panic!("expected a loop");
+        };
+        let ir::Kind::Op(ir::OpKind::Print, args) = &arena[operands[2]].kind else {
+            panic!("expected a print");
+        };
+        assert!(matches!(
+            arena[args[0]].kind,
+            ir::Kind::Op(ir::OpKind::IMul, _)
+        ));
+        assert_eq!(ir::eval(&arena, root)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_only_fold_the_branch_a_constant_condition_picks() -> Result<()> {
+        let (mut arena, root) = lower("if 2 > 1 { 2 * 3 } else { 1 / 0 }")?;
+        let root = fold(&mut arena, root, true)?;
+        assert_eq!(arena[root].kind, ir::Kind::IntValue(6));
+
+        let (mut arena, root) = lower("if read_int() > 0 { 2 * 3 } else { 4 }")?;
+        let root = fold(&mut arena, root, true)?;
+        let ir::Kind::Op(ir::OpKind::If, args) = &arena[root].kind else {
+            panic!("expected the conditional to remain");
+        };
+        assert_eq!(arena[args[1]].kind, ir::Kind::IntValue(6));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_fold_bools_to_bool_constants() -> Result<()> {
+        let (mut arena, root) = lower("if true { false } else { true }")?;
+        let root = fold(&mut arena, root, true)?;
+        assert_eq!(arena[root].kind, ir::Kind::BoolValue(false));
+
+        let (mut arena, root) = lower("!true || false")?;
+        let root = fold(&mut arena, root, true)?;
+        assert_eq!(arena[root].kind, ir::Kind::BoolValue(false));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_fold_comparisons_to_bools() -> Result<()> {
+        let (mut arena, root) = lower("3 < 5")?;
+        let root = fold(&mut arena, root, true)?;
+        assert_eq!(arena[root].kind, ir::Kind::BoolValue(true));
+
+        let (mut arena, root) = lower("(3 < 5) != (5 < 3) && true == (1 <= 1)")?;
+        let root = fold(&mut arena, root, true)?;
+        assert_eq!(arena[root].kind, ir::Kind::BoolValue(true));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_keep_runtime_operands() -> Result<()> {
+        let (mut arena, root) = lower("read_int() * (3 + 4)")?;
+        let root = fold(&mut arena, root, false)?;
+        let ir::Kind::Op(ir::OpKind::IMul, args) = &arena[root].kind else {
+            panic!("expected multiplication to remain");
+        };
+        assert_eq!(arena[args[1]].kind, ir::Kind::IntValue(7));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_leave_overflow_unfolded_by_default() -> Result<()> {
+        let (mut arena, root) = lower("9223372036854775807 + 1")?;
+        let root = fold(&mut arena, root, false)?;
+        assert!(matches!(
+            arena[root].kind,
+            ir::Kind::Op(ir::OpKind::IAdd, _)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_follow_overflow_mode_for_abs_of_min() -> Result<()> {
+        let src = "abs(0 - 9223372036854775807 - 1)";
+        let cases = [
+            (OverflowMode::Wrap, i64::MIN),
+            (OverflowMode::Saturate, i64::MAX),
+        ];
+        for (overflow, expected) in cases {
+            let (mut arena, root) = lower(src)?;
+            let root = fold_with_overflow(&mut arena, root, false, overflow)?;
+            assert_eq!(arena[root].kind, ir::Kind::IntValue(expected));
+        }
+
+        let (mut arena, root) = lower(src)?;
+        let err = fold_with_overflow(&mut arena, root, false, OverflowMode::Trap).unwrap_err();
+        let diag = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diag.code, Some("E0011"));
+        assert_eq!(diag.span, Some(0..32));
+
+        let (mut arena, root) = lower("abs(0 - 5)")?;
+        let root = fold_with_overflow(&mut arena, root, false, OverflowMode::Trap)?;
+        assert_eq!(arena[root].kind, ir::Kind::IntValue(5));
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_reject_invalid_constant_pow_mod_arguments() -> Result<()> {
+        let (mut arena, root) = lower("pow_mod(2, 10, 1000)")?;
+        let root = fold(&mut arena, root, false)?;
+        assert_eq!(arena[root].kind, ir::Kind::IntValue(24));
+
+        for src in ["pow_mod(read_int(), 2, 0)", "pow_mod(2, 0 - 1, read_int())"] {
+            let (mut arena, root) = lower(src)?;
+            let err = fold(&mut arena, root, false).unwrap_err();
+            let diag = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diag.code, Some("E0012"), "{}", src);
+            assert_eq!(diag.span, Some(0..src.len()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fold_should_reject_overflow_in_checked_mode() -> Result<()> {
+        let (mut arena, root) = lower("9223372036854775807 + 1")?;
+        let err = fold(&mut arena, root, true).unwrap_err();
+        assert!(err.to_string().contains("arithmetic overflow"));
+        Ok(())
+    }
+}
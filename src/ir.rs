@@ -1,21 +1,1017 @@
 // SPDX-License-Identifier: Unlicense
+use crate::ast;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+};
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum OpKind {
     IAdd,
     ISub,
     IMul,
+    /// Division truncating toward zero, so `-7 / 2` is `-3`. Dividing by zero aborts and
+    /// `i64::MIN / -1` wraps to `i64::MIN`.
     IDiv,
+    /// The remainder of [`OpKind::IDiv`], taking the sign of the dividend: `-7 % 2` is
+    /// `-1` and `7 % -2` is `1`. Dividing by zero aborts and `i64::MIN % -1` is 0.
+    IRem,
+    /// `lhs` raised to `rhs`, wrapping on overflow; a negative exponent gives 0.
+    IPow,
+    /// Signed comparison, evaluating to a bool.
+    ICmp(Predicate),
+    /// Bitwise and.
+    And,
+    /// Bitwise exclusive or.
+    Xor,
+    /// Bitwise or.
+    Or,
+    /// Absolute value; `abs(i64::MIN)` overflows.
+    Abs,
+    /// `base.pow(exp) % modulus` without overflowing; the modulus must be positive and the
+    /// exponent non-negative.
+    PowMod,
+    /// Logical not, evaluating to a bool.
+    LNot,
+    /// Evaluates the second operand only if the first is true or non-zero; a bool.
+    LAnd,
+    /// Evaluates the second operand only if the first is false or zero; a bool.
+    LOr,
+    /// Evaluates to the second operand if the first is non-zero and to the third otherwise,
+    /// evaluating only the one it picks.
+    If,
+    ReadInt,
+    /// Aborts with the message when the operand is zero, otherwise evaluates to zero.
+    Assert(String),
+    /// Prints the label and the operand to stderr, then evaluates to the operand.
+    DebugPrint(String),
+    /// Prints the operands space-separated on one line, then evaluates to zero. A top-level
+    /// statement that only prints is not printed itself.
+    Print,
+    /// Like [`OpKind::Print`], but reads the operands' bits as unsigned.
+    PrintUnsigned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Predicate {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
 }
+
+impl Predicate {
+    pub fn holds(self, lhs: i64, rhs: i64) -> bool {
+        match self {
+            Predicate::Eq => lhs == rhs,
+            Predicate::Ne => lhs != rhs,
+            Predicate::Lt => lhs < rhs,
+            Predicate::Le => lhs <= rhs,
+            Predicate::Gt => lhs > rhs,
+            Predicate::Ge => lhs >= rhs,
+        }
+    }
+}
+
+impl OpKind {
+    /// Returns the number of operands the operation takes, or `None` if it is variadic.
+    pub fn arity(&self) -> Option<usize> {
+        match self {
+            OpKind::IAdd
+            | OpKind::ISub
+            | OpKind::IMul
+            | OpKind::IDiv
+            | OpKind::IRem
+            | OpKind::IPow
+            | OpKind::ICmp(_)
+            | OpKind::And
+            | OpKind::Xor
+            | OpKind::Or
+            | OpKind::LAnd
+            | OpKind::LOr => Some(2),
+            OpKind::Abs | OpKind::LNot | OpKind::Assert(_) | OpKind::DebugPrint(_) => Some(1),
+            OpKind::PowMod | OpKind::If => Some(3),
+            OpKind::ReadInt => Some(0),
+            OpKind::Print | OpKind::PrintUnsigned => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Kind {
     IntValue(i64),
+    BoolValue(bool),
     Op(OpKind, Vec<Id>),
+    /// Top-level statements; each value is printed and the program returns their sum.
+    Seq(Vec<Id>),
+    /// A statement binding the name to the operand's value. It is not printed and
+    /// evaluates to zero.
+    Let(String, Id),
+    /// The value last stored in the [`Kind::Let`] node's binding, or the argument passed
+    /// for the [`Kind::Param`] node.
+    Var(Id),
+    /// A statement storing the second operand's value in the binding of the first, a
+    /// [`Kind::Let`] node. It is not printed and evaluates to zero.
+    Assign(Id, Id),
+    /// A top-level statement defining the named function. The operands are its
+    /// [`Kind::Param`] nodes followed by its body. It is not printed and evaluates to zero.
+    Fn(String, Vec<Id>),
+    /// A parameter of the [`Kind::Fn`] node listing it.
+    Param(String),
+    /// Calls the [`Kind::Fn`] node with the operands as arguments.
+    Call(Id, Vec<Id>),
+    /// A statement running the operands after the first two while the first is true or
+    /// non-zero, and the second after each of those runs. All but the first are
+    /// statements. It is not printed and evaluates to zero.
+    Loop(Vec<Id>),
+    /// Ends the innermost [`Kind::Loop`] whose statements it is in, leaving the rest of
+    /// the iteration unevaluated.
+    Break,
+    /// Skips the rest of the current iteration of the innermost [`Kind::Loop`] whose
+    /// statements it is in, moving on to the step.
+    Continue,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     pub kind: Kind,
+    /// The source range the node was lowered from, if any.
+    pub span: Option<ast::Span>,
 }
 
 pub type Id = id_arena::Id<Node>;
 pub type Arena = id_arena::Arena<Node>;
+
+/// Evaluates the program rooted at `root` without going through LLVM.
+///
+/// Arithmetic wraps on overflow in the same way as the generated code does. A statement
+/// sequence evaluates to the wrapping sum of its statements, matching the value `main`
+/// returns.
+pub fn eval(arena: &Arena, root: Id) -> Result<i64> {
+    eval_impl(arena, root, &mut Frame::default())
+}
+
+/// Calls nested deeper than this are rejected instead of overflowing the stack.
+const MAX_CALL_DEPTH: usize = 256;
+
+/// Programs running more loop iterations and calls than this, taken together, are
+/// rejected instead of hanging.
+const MAX_STEPS: usize = 1 << 20;
+
+/// Unwinds evaluation from a [`Kind::Break`] or [`Kind::Continue`] to the loop it
+/// belongs to.
+#[derive(Debug)]
+enum LoopExit {
+    Break,
+    Continue,
+}
+
+impl std::fmt::Display for LoopExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoopExit::Break => write!(f, "break outside of a loop"),
+            LoopExit::Continue => write!(f, "continue outside of a loop"),
+        }
+    }
+}
+
+impl std::error::Error for LoopExit {}
+
+/// The state of the program or function call being evaluated.
+#[derive(Default)]
+struct Frame {
+    /// The values of the [`Kind::Let`] and [`Kind::Param`] nodes bound so far.
+    bindings: HashMap<Id, i64>,
+    /// The number of calls this one is nested in.
+    depth: usize,
+    /// The number of loop iterations and calls run so far, including those of other calls.
+    steps: usize,
+}
+
+/// Counts a loop iteration or call against [`MAX_STEPS`].
+fn step(frame: &mut Frame) -> Result<()> {
+    if frame.steps == MAX_STEPS {
+        anyhow::bail!(
+            "programs running more than {} loop iterations and calls cannot be evaluated at \
+             compile time",
+            MAX_STEPS
+        );
+    }
+    frame.steps += 1;
+    Ok(())
+}
+
+fn eval_impl(arena: &Arena, root: Id, frame: &mut Frame) -> Result<i64> {
+    let kind = &arena
+        .get(root)
+        .ok_or(anyhow!("failed to get ir from arena"))?
+        .kind;
+
+    match kind {
+        &Kind::IntValue(i) => Ok(i),
+        &Kind::BoolValue(b) => Ok(b as i64),
+        Kind::Seq(stmts) => {
+            let mut sum = 0i64;
+            for &stmt in stmts {
+                sum = sum.wrapping_add(eval_impl(arena, stmt, frame)?);
+            }
+            Ok(sum)
+        }
+        Kind::Let(_, value) => {
+            let value = eval_impl(arena, *value, frame)?;
+            frame.bindings.insert(root, value);
+            Ok(0)
+        }
+        Kind::Var(binding) => frame
+            .bindings
+            .get(binding)
+            .copied()
+            .ok_or(anyhow!("variable used before its let")),
+        Kind::Assign(binding, value) => {
+            let value = eval_impl(arena, *value, frame)?;
+            *frame
+                .bindings
+                .get_mut(binding)
+                .ok_or(anyhow!("variable assigned before its let"))? = value;
+            Ok(0)
+        }
+        Kind::Fn(..) => Ok(0),
+        Kind::Param(name) => anyhow::bail!("parameter {} used outside of a variable", name),
+        Kind::Call(function, args) => {
+            let Kind::Fn(name, operands) = &arena[*function].kind else {
+                anyhow::bail!("call of something other than a function");
+            };
+            step(frame)?;
+            if frame.depth == MAX_CALL_DEPTH {
+                anyhow::bail!(
+                    "{}() calls nested deeper than {} cannot be evaluated at compile time",
+                    name,
+                    MAX_CALL_DEPTH
+                );
+            }
+            let (body, params) = operands
+                .split_last()
+                .ok_or(anyhow!("function {} has no body", name))?;
+            let mut bindings = HashMap::new();
+            for (&param, &arg) in params.iter().zip(args) {
+                bindings.insert(param, eval_impl(arena, arg, frame)?);
+            }
+            let mut callee = Frame {
+                bindings,
+                depth: frame.depth + 1,
+                steps: frame.steps,
+            };
+            let value = eval_impl(arena, *body, &mut callee);
+            frame.steps = callee.steps;
+            value
+        }
+        Kind::Loop(operands) => {
+            let [cond, step, body @ ..] = operands.as_slice() else {
+                anyhow::bail!("loop has no condition or step");
+            };
+            while eval_impl(arena, *cond, frame)? != 0 {
+                step(frame)?;
+                for &stmt in body {
+                    let Err(err) = eval_impl(arena, stmt, frame) else {
+                        continue;
+                    };
+                    match err.downcast_ref::<LoopExit>() {
+                        Some(LoopExit::Break) => return Ok(0),
+                        Some(LoopExit::Continue) => break,
+                        None => return Err(err),
+                    }
+                }
+                eval_impl(arena, *step, frame)?;
+            }
+            Ok(0)
+        }
+        Kind::Break => Err(LoopExit::Break.into()),
+        Kind::Continue => Err(LoopExit::Continue.into()),
+        Kind::Op(op, args) => {
+            if *op == OpKind::ReadInt {
+                anyhow::bail!("read_int() cannot be evaluated at compile time");
+            }
+            if let (OpKind::LAnd | OpKind::LOr, &[lhs, rhs]) = (op, args.as_slice()) {
+                let lhs = eval_impl(arena, lhs, frame)? != 0;
+                let result = match op {
+                    OpKind::LAnd => lhs && eval_impl(arena, rhs, frame)? != 0,
+                    _ => lhs || eval_impl(arena, rhs, frame)? != 0,
+                };
+                return Ok(result as i64);
+            }
+            if let (OpKind::If, &[cond, then, otherwise]) = (op, args.as_slice()) {
+                return match eval_impl(arena, cond, frame)? {
+                    0 => eval_impl(arena, otherwise, frame),
+                    _ => eval_impl(arena, then, frame),
+                };
+            }
+            let values = args
+                .iter()
+                .map(|&arg| eval_impl(arena, arg, frame))
+                .collect::<Result<Vec<_>>>()?;
+            match (op, values.as_slice()) {
+                (OpKind::IAdd, &[lhs, rhs]) => Ok(lhs.wrapping_add(rhs)),
+                (OpKind::ISub, &[lhs, rhs]) => Ok(lhs.wrapping_sub(rhs)),
+                (OpKind::IMul, &[lhs, rhs]) => Ok(lhs.wrapping_mul(rhs)),
+                (OpKind::IDiv, &[lhs, rhs]) => {
+                    if rhs == 0 {
+                        anyhow::bail!("division by zero");
+                    }
+                    Ok(lhs.wrapping_div(rhs))
+                }
+                (OpKind::IRem, &[lhs, rhs]) => {
+                    if rhs == 0 {
+                        anyhow::bail!("division by zero");
+                    }
+                    Ok(lhs.wrapping_rem(rhs))
+                }
+                (OpKind::IPow, &[base, exp]) => Ok(overflowing_pow(base, exp).0),
+                (&OpKind::ICmp(predicate), &[lhs, rhs]) => Ok(predicate.holds(lhs, rhs) as i64),
+                (OpKind::And, &[lhs, rhs]) => Ok(lhs & rhs),
+                (OpKind::Xor, &[lhs, rhs]) => Ok(lhs ^ rhs),
+                (OpKind::Or, &[lhs, rhs]) => Ok(lhs | rhs),
+                (OpKind::Abs, &[value]) => Ok(value.wrapping_abs()),
+                (OpKind::PowMod, &[base, exp, modulus]) => pow_mod(base, exp, modulus),
+                (OpKind::LNot, &[value]) => Ok((value == 0) as i64),
+                (OpKind::DebugPrint(_), &[value]) => Ok(value),
+                (OpKind::Print | OpKind::PrintUnsigned, values) if !values.is_empty() => Ok(0),
+                (OpKind::Assert(message), &[cond]) => {
+                    if cond == 0 {
+                        anyhow::bail!("{}", message);
+                    }
+                    Ok(0)
+                }
+                _ => anyhow::bail!("malformed {:?} with {} arguments", op, values.len()),
+            }
+        }
+    }
+}
+
+/// Computes `base` raised to `exp` by squaring, returning the wrapped power and whether
+/// the exact one overflows. A negative exponent gives 0, as the truncated reciprocal
+/// would for every base but -1, 0 and 1.
+pub fn overflowing_pow(base: i64, exp: i64) -> (i64, bool) {
+    if exp < 0 {
+        return (0, false);
+    }
+    let (mut result, mut square, mut exp, mut overflowed) = (1i64, base, exp, false);
+    while exp != 0 {
+        if exp & 1 != 0 {
+            let (product, o) = result.overflowing_mul(square);
+            result = product;
+            overflowed |= o;
+        }
+        exp >>= 1;
+        // the last square is never multiplied in, so its overflow does not count
+        if exp != 0 {
+            let (product, o) = square.overflowing_mul(square);
+            square = product;
+            overflowed |= o;
+        }
+    }
+    (result, overflowed)
+}
+
+/// Computes `base.pow(exp) % modulus` by squaring, reducing after every step.
+///
+/// Like `%`, the result has the sign of the exact power.
+pub fn pow_mod(base: i64, exp: i64, modulus: i64) -> Result<i64> {
+    if modulus <= 0 {
+        anyhow::bail!("pow_mod modulus must be positive");
+    }
+    if exp < 0 {
+        anyhow::bail!("pow_mod exponent must not be negative");
+    }
+    let modulus = modulus as i128;
+    let (mut result, mut base, mut exp) = (1 % modulus, base as i128 % modulus, exp);
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exp >>= 1;
+    }
+    Ok(result as i64)
+}
+
+/// Whether the node `id` evaluates to a bool, which is represented as 0 or 1 once it
+/// has to be stored. Bindings, parameters and calls always hold integers, so a bool
+/// read back from them is compared with 0 where one is needed.
+pub fn is_bool(arena: &Arena, id: Id) -> bool {
+    match &arena[id].kind {
+        Kind::BoolValue(_) => true,
+        Kind::Op(OpKind::ICmp(_) | OpKind::LNot | OpKind::LAnd | OpKind::LOr, _) => true,
+        Kind::Op(OpKind::If, args) => is_bool(arena, args[1]) && is_bool(arena, args[2]),
+        _ => false,
+    }
+}
+
+pub fn children(kind: &Kind) -> &[Id] {
+    match kind {
+        Kind::IntValue(_) | Kind::BoolValue(_) => &[],
+        Kind::Op(_, args) => args,
+        Kind::Seq(stmts) => stmts,
+        Kind::Let(_, value) => std::slice::from_ref(value),
+        Kind::Var(_) => &[],
+        Kind::Assign(_, value) => std::slice::from_ref(value),
+        Kind::Fn(_, operands) => operands,
+        Kind::Param(_) => &[],
+        Kind::Call(_, args) => args,
+        Kind::Loop(operands) => operands,
+        Kind::Break | Kind::Continue => &[],
+    }
+}
+
+fn describe(kind: &Kind) -> String {
+    match kind {
+        Kind::IntValue(i) => format!("int {}", i),
+        Kind::BoolValue(b) => format!("bool {}", b),
+        Kind::Op(OpKind::Assert(message), _) => format!("assert {:?}", message),
+        Kind::Op(OpKind::DebugPrint(label), _) => format!("debug_print {:?}", label),
+        Kind::Op(OpKind::ICmp(predicate), _) => format!("icmp {:?}", predicate).to_lowercase(),
+        Kind::Op(op, _) => format!("{:?}", op).to_lowercase(),
+        Kind::Seq(_) => "seq".to_string(),
+        Kind::Let(name, _) => format!("let {}", name),
+        Kind::Var(_) => "var".to_string(),
+        Kind::Assign(..) => "assign".to_string(),
+        Kind::Fn(name, _) => format!("fn {}", name),
+        Kind::Param(name) => format!("param {}", name),
+        Kind::Call(..) => "call".to_string(),
+        Kind::Loop(_) => "loop".to_string(),
+        Kind::Break => "break".to_string(),
+        Kind::Continue => "continue".to_string(),
+    }
+}
+
+/// Collects the nodes reachable from `root` in post-order, visiting each node once.
+fn post_order(arena: &Arena, root: Id) -> Result<Vec<Id>> {
+    fn visit(arena: &Arena, id: Id, seen: &mut HashSet<Id>, order: &mut Vec<Id>) -> Result<()> {
+        if !seen.insert(id) {
+            return Ok(());
+        }
+        let node = arena.get(id).ok_or(anyhow!("failed to get ir from arena"))?;
+        for &child in children(&node.kind) {
+            visit(arena, child, seen, order)?;
+        }
+        order.push(id);
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    visit(arena, root, &mut HashSet::new(), &mut order)?;
+    Ok(order)
+}
+
+/// Numbers the nodes reachable from `root` in post-order, so the numbers depend only on
+/// the shape of the tree and not on the order its nodes were allocated in.
+pub fn assign_stable_ids(arena: &Arena, root: Id) -> Result<HashMap<Id, u32>> {
+    Ok(post_order(arena, root)?.into_iter().zip(0..).collect())
+}
+
+fn instruction(kind: &Kind, number: impl Fn(Id) -> usize) -> String {
+    match kind {
+        Kind::Var(binding) => return format!("var %{}", number(*binding)),
+        Kind::Assign(binding, value) => {
+            return format!("assign %{}, %{}", number(*binding), number(*value))
+        }
+        Kind::Call(function, args) => {
+            let operands = std::iter::once(function)
+                .chain(args)
+                .map(|&operand| format!("%{}", number(operand)))
+                .collect::<Vec<_>>();
+            return format!("call {}", operands.join(", "));
+        }
+        _ => {}
+    }
+    let operands = children(kind)
+        .iter()
+        .map(|&arg| format!("%{}", number(arg)))
+        .collect::<Vec<_>>();
+    if operands.is_empty() {
+        describe(kind)
+    } else {
+        format!("{} {}", describe(kind), operands.join(", "))
+    }
+}
+
+/// Checks that every operation reachable from `root` has as many operands as its
+/// [`OpKind::arity`] demands, that statement sequences only appear at the root,
+/// bindings, assignments and loops only as statements and functions only as top-level
+/// ones, that `break` and `continue` only appear in the statements of loops, that
+/// variables refer to bindings or parameters and that calls pass every parameter of a
+/// function.
+pub fn validate(arena: &Arena, root: Id) -> Result<()> {
+    let top_level = match &arena[root].kind {
+        Kind::Seq(stmts) => stmts.clone(),
+        _ => vec![root],
+    };
+    // the statements of loops are statements too, and may be loops themselves
+    let mut stmts = top_level.clone();
+    let mut i = 0;
+    while i < stmts.len() {
+        if let Some(Node {
+            kind: Kind::Loop(operands),
+            ..
+        }) = arena.get(stmts[i])
+        {
+            stmts.extend(operands.iter().skip(1));
+        }
+        i += 1;
+    }
+    // function bodies are not below any statement, so they are outside of every loop
+    let mut in_loops = HashSet::new();
+    for &stmt in &stmts {
+        if let Some(Node {
+            kind: Kind::Loop(operands),
+            ..
+        }) = arena.get(stmt)
+        {
+            for &operand in operands.iter().skip(2) {
+                in_loops.extend(post_order(arena, operand)?);
+            }
+        }
+    }
+    let is_let = |id: Id| {
+        matches!(
+            arena.get(id),
+            Some(Node {
+                kind: Kind::Let(..),
+                ..
+            })
+        )
+    };
+    let is_param = |id: Id| {
+        matches!(
+            arena.get(id),
+            Some(Node {
+                kind: Kind::Param(_),
+                ..
+            })
+        )
+    };
+    for id in post_order(arena, root)? {
+        match &arena[id].kind {
+            Kind::Op(op, args) => {
+                if let Some(arity) = op.arity() {
+                    if args.len() != arity {
+                        anyhow::bail!(
+                            "%{}: {:?} takes {} operand(s) but has {}",
+                            id.index(),
+                            op,
+                            arity,
+                            args.len()
+                        );
+                    }
+                }
+            }
+            Kind::Seq(_) if id != root => {
+                anyhow::bail!("%{}: statement sequence below the root", id.index())
+            }
+            Kind::Let(..) | Kind::Assign(..) if !stmts.contains(&id) => {
+                anyhow::bail!("%{}: binding below the statement level", id.index())
+            }
+            Kind::Loop(_) if !stmts.contains(&id) => {
+                anyhow::bail!("%{}: loop below the statement level", id.index())
+            }
+            Kind::Fn(..) if !top_level.contains(&id) => {
+                anyhow::bail!("%{}: function below the top level", id.index())
+            }
+            Kind::Loop(operands) if operands.len() < 2 => {
+                anyhow::bail!("%{}: loop has no condition or step", id.index())
+            }
+            Kind::Break | Kind::Continue if !in_loops.contains(&id) => {
+                anyhow::bail!(
+                    "%{}: {} outside of a loop",
+                    id.index(),
+                    describe(&arena[id].kind)
+                )
+            }
+            Kind::Var(binding) if !is_let(*binding) && !is_param(*binding) => {
+                anyhow::bail!("%{}: variable does not refer to a binding", id.index())
+            }
+            Kind::Assign(binding, _) if !is_let(*binding) => {
+                anyhow::bail!("%{}: variable does not refer to a binding", id.index())
+            }
+            Kind::Call(function, args) => match arena.get(*function).map(|node| &node.kind) {
+                Some(Kind::Fn(name, operands)) if operands.len() != args.len() + 1 => {
+                    anyhow::bail!(
+                        "%{}: {} takes {} argument(s) but has {}",
+                        id.index(),
+                        name,
+                        operands.len() - 1,
+                        args.len()
+                    )
+                }
+                Some(Kind::Fn(..)) => {}
+                _ => anyhow::bail!("%{}: call does not refer to a function", id.index()),
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of distinct nodes reachable from `root`.
+pub fn node_count(arena: &Arena, root: Id) -> Result<usize> {
+    Ok(post_order(arena, root)?.len())
+}
+
+/// Returns whether any node reachable from `root` reads input when the program runs.
+pub fn reads_input(arena: &Arena, root: Id) -> Result<bool> {
+    Ok(post_order(arena, root)?
+        .into_iter()
+        .any(|id| matches!(arena[id].kind, Kind::Op(OpKind::ReadInt, _))))
+}
+
+/// Returns the length of the longest path from `root` down to a leaf; a lone leaf has
+/// depth 1.
+pub fn depth(arena: &Arena, root: Id) -> Result<usize> {
+    let mut depths = HashMap::new();
+    for id in post_order(arena, root)? {
+        let depth = children(&arena[id].kind)
+            .iter()
+            .map(|child| depths[child])
+            .max()
+            .unwrap_or(0)
+            + 1;
+        depths.insert(id, depth);
+    }
+    Ok(depths[&root])
+}
+
+/// Renders the nodes reachable from `root` one per line, operands before their users,
+/// numbered by [`assign_stable_ids`].
+pub fn dump(arena: &Arena, root: Id) -> Result<String> {
+    let ids = assign_stable_ids(arena, root)?;
+    let mut out = String::new();
+    for id in post_order(arena, root)? {
+        let kind = &arena[id].kind;
+        writeln!(
+            out,
+            "%{} = {}",
+            ids[&id],
+            instruction(kind, |arg| ids[&arg] as usize)
+        )?;
+    }
+    Ok(out)
+}
+
+/// Reports how the tree reachable from `after_root` differs from the one reachable from
+/// `before_root`.
+///
+/// Nodes are matched by arena index, so this is meant for comparing an arena with a
+/// transformed copy of itself, e.g. before and after an optimization pass.
+pub fn diff(before: &Arena, before_root: Id, after: &Arena, after_root: Id) -> Result<String> {
+    fn collect(arena: &Arena, root: Id) -> Result<BTreeMap<usize, &Kind>> {
+        Ok(post_order(arena, root)?
+            .into_iter()
+            .map(|id| (id.index(), &arena[id].kind))
+            .collect())
+    }
+
+    let before_nodes = collect(before, before_root)?;
+    let after_nodes = collect(after, after_root)?;
+
+    let mut out = String::new();
+    for (index, old) in &before_nodes {
+        match after_nodes.get(index) {
+            None => writeln!(out, "removed %{}: {}", index, instruction(old, Id::index))?,
+            Some(new) if new == old => {}
+            Some(new) => {
+                let verb = match (old, new) {
+                    (Kind::Op(..), Kind::IntValue(_) | Kind::BoolValue(_)) => "folded",
+                    _ => "changed",
+                };
+                writeln!(out, "{} %{}: {} => {}", verb, index, instruction(old, Id::index), instruction(new, Id::index))?
+            }
+        }
+    }
+    for (index, new) in &after_nodes {
+        if !before_nodes.contains_key(index) {
+            writeln!(out, "added %{}: {}", index, instruction(new, Id::index))?;
+        }
+    }
+    Ok(out)
+}
+
+/// Renders the nodes reachable from `root` as a Graphviz digraph, naming them by
+/// [`assign_stable_ids`].
+pub fn to_dot(arena: &Arena, root: Id) -> Result<String> {
+    let ids = assign_stable_ids(arena, root)?;
+    let mut out = String::from("digraph ir {\n");
+    for id in post_order(arena, root)? {
+        let kind = &arena[id].kind;
+        writeln!(out, "    n{} [label=\"{}\"];", ids[&id], describe(kind))?;
+        for arg in children(kind) {
+            writeln!(out, "    n{} -> n{};", ids[&id], ids[arg])?;
+        }
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{irgen, parser};
+
+    fn eval_source(src: &str) -> Result<i64> {
+        let (ast_arena, ast_root) = parser::parse(src)?;
+        let (ir_arena, ir_root) = irgen::generate(ast_arena, ast_root, src)?;
+        eval(&ir_arena, ir_root)
+    }
+
+    fn lower(src: &str) -> Result<(Arena, Id)> {
+        let (ast_arena, ast_root) = parser::parse(src)?;
+        irgen::generate(ast_arena, ast_root, src)
+    }
+
+    #[test]
+    fn dump_should_name_bindings_of_variables() -> Result<()> {
+        let (arena, root) = lower("let x = 1; x + x")?;
+        assert_eq!(
+            dump(&arena, root)?,
+            "%0 = int 1\n%1 = let x %0\n%2 = var %1\n%3 = var %1\n%4 = iadd %2, %3\n\
+             %5 = seq %1, %4\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dump_should_list_parameters_before_function_bodies() -> Result<()> {
+        let (arena, root) = lower("fn f(a) = a + 1; f(2)")?;
+        assert_eq!(
+            dump(&arena, root)?,
+            "%0 = param a\n%1 = var %0\n%2 = int 1\n%3 = iadd %1, %2\n%4 = fn f %0, %3\n\
+             %5 = int 2\n%6 = call %4, %5\n%7 = seq %4, %6\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_run_loops_until_the_condition_fails() -> Result<()> {
+        let src = "let mut sum = 0; for i in 0..10 { for j in 0..i { sum = sum + j } }; sum";
+        assert_eq!(eval_source(src)?, 120);
+        // the bounds are only evaluated once, and an empty range runs nothing
+        assert_eq!(
+            eval_source("let mut n = 3; for i in 0..n { n = n + 1 }; n")?,
+            6
+        );
+        assert_eq!(eval_source("for i in 5..0 { assert(false) }; 1")?, 1);
+        let err = eval_source("for i in 0..10000000 {}").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("cannot be evaluated at compile time"));
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_count_calls_against_the_step_budget() -> Result<()> {
+        let src = "fn f(n) = if n > 0 { f(n - 1) + f(n - 1) } else { 1 }; f(10)";
+        assert_eq!(eval_source(src)?, 1024);
+        // shallow but exponential recursion must not hang
+        let src = "fn f(n) = if n > 0 { f(n - 1) + f(n - 1) } else { 1 }; f(60)";
+        let err = eval_source(src).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("cannot be evaluated at compile time"));
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_leave_loops_early() -> Result<()> {
+        let src =
+            "let mut s = 0; for i in 0..10 { if i % 3 == 0 { continue }; if i > 7 { break }; \
+                   s = s + i }; s";
+        assert_eq!(eval_source(src)?, 1 + 2 + 4 + 5 + 7);
+        // only the innermost loop ends
+        let src =
+            "let mut n = 0; for i in 0..4 { for j in 0..4 { if j >= i { break }; n = n + 1 } }; n";
+        assert_eq!(eval_source(src)?, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_should_keep_bindings_at_the_statement_level() -> Result<()> {
+        let mut arena = Arena::new();
+        let mut alloc = |kind| arena.alloc(Node { kind, span: None });
+        let one = alloc(Kind::IntValue(1));
+        let binding = alloc(Kind::Let("x".to_string(), one));
+        let step = alloc(Kind::Assign(binding, one));
+        let nested = alloc(Kind::Let("y".to_string(), one));
+        let inner = alloc(Kind::Loop(vec![one, step, nested]));
+        let outer = alloc(Kind::Loop(vec![one, step, inner]));
+        let seq = alloc(Kind::Seq(vec![binding, outer]));
+        // the condition is an expression
+        let broken = alloc(Kind::Loop(vec![nested, step]));
+        let broken_seq = alloc(Kind::Seq(vec![binding, broken]));
+        // so is the step, which a break cannot leave
+        let exit = alloc(Kind::Break);
+        let exiting = alloc(Kind::Loop(vec![one, exit]));
+        let exiting_seq = alloc(Kind::Seq(vec![exiting]));
+        validate(&arena, seq)?;
+        assert_eq!(
+            validate(&arena, broken_seq).unwrap_err().to_string(),
+            "%3: binding below the statement level"
+        );
+        assert_eq!(
+            validate(&arena, exiting_seq).unwrap_err().to_string(),
+            "%9: break outside of a loop"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stable_ids_should_not_depend_on_allocation_order() -> Result<()> {
+        let numbered = |arena: &Arena, root: Id| -> Result<Vec<(u32, String)>> {
+            let mut nodes: Vec<_> = assign_stable_ids(arena, root)?
+                .into_iter()
+                .map(|(id, n)| (n, describe(&arena[id].kind)))
+                .collect();
+            nodes.sort();
+            Ok(nodes)
+        };
+        let src = "print(1 + 2 * 3); 4 - 5";
+        let (first, first_root) = lower(src)?;
+        let (second, second_root) = lower(src)?;
+        assert_eq!(
+            numbered(&first, first_root)?,
+            numbered(&second, second_root)?
+        );
+
+        // the same tree allocated parents-first gets the same numbers
+        let mut arena = Arena::new();
+        let mut alloc = |kind| arena.alloc(Node { kind, span: None });
+        let (sub, two, one) = (
+            alloc(Kind::IntValue(0)),
+            alloc(Kind::IntValue(2)),
+            alloc(Kind::IntValue(1)),
+        );
+        arena[sub].kind = Kind::Op(OpKind::ISub, vec![one, two]);
+        let (lowered, lowered_root) = lower("1 - 2")?;
+        assert_eq!(numbered(&arena, sub)?, numbered(&lowered, lowered_root)?);
+        assert_eq!(
+            dump(&arena, sub)?,
+            "%0 = int 1\n%1 = int 2\n%2 = isub %0, %1\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overflowing_pow_should_only_count_squares_that_are_used() {
+        assert_eq!(overflowing_pow(2, 10), (1024, false));
+        assert_eq!(overflowing_pow(2, 62), (1 << 62, false));
+        assert_eq!(overflowing_pow(-2, 63), (i64::MIN, false));
+        assert_eq!(overflowing_pow(2, 63), (i64::MIN, true));
+        assert_eq!(overflowing_pow(2, 64), (0, true));
+        assert_eq!(overflowing_pow(-1, i64::MAX), (-1, false));
+        assert_eq!(overflowing_pow(0, 0), (1, false));
+        assert_eq!(overflowing_pow(2, -1), (0, false));
+        assert_eq!(overflowing_pow(1, -1), (0, false));
+    }
+
+    #[test]
+    fn pow_mod_should_reduce_while_squaring() -> Result<()> {
+        assert_eq!(pow_mod(2, 10, 1000)?, 24);
+        assert_eq!(pow_mod(3, 0, 7)?, 1);
+        assert_eq!(pow_mod(3, 0, 1)?, 0);
+        assert_eq!(pow_mod(-2, 3, 5)?, -3);
+        assert_eq!(pow_mod(i64::MAX, i64::MAX, i64::MAX - 1)?, 1);
+        assert!(pow_mod(2, 3, 0).is_err());
+        assert!(pow_mod(2, -1, 5).is_err());
+        Ok(())
+    }
+
+    fn depth_of(src: &str) -> Result<usize> {
+        let (ast_arena, ast_root) = parser::parse(src)?;
+        let (ir_arena, ir_root) = irgen::generate(ast_arena, ast_root, src)?;
+        depth(&ir_arena, ir_root)
+    }
+
+    #[test]
+    fn arity_should_be_fixed_for_binary_ops_and_open_for_print() {
+        for op in [
+            OpKind::IAdd,
+            OpKind::ISub,
+            OpKind::IMul,
+            OpKind::IDiv,
+            OpKind::LAnd,
+        ] {
+            assert_eq!(op.arity(), Some(2), "{:?}", op);
+        }
+        assert_eq!(OpKind::ReadInt.arity(), Some(0));
+        assert_eq!(OpKind::Print.arity(), None);
+    }
+
+    #[test]
+    fn validate_should_enforce_arity() -> Result<()> {
+        let mut arena = Arena::new();
+        let one = arena.alloc(Node {
+            kind: Kind::IntValue(1),
+            span: None,
+        });
+        let print = arena.alloc(Node {
+            kind: Kind::Op(OpKind::Print, vec![one, one, one]),
+            span: None,
+        });
+        validate(&arena, print)?;
+
+        let broken = arena.alloc(Node {
+            kind: Kind::Op(OpKind::IAdd, vec![one]),
+            span: None,
+        });
+        let err = validate(&arena, broken).unwrap_err();
+        assert_eq!(err.to_string(), "%2: IAdd takes 2 operand(s) but has 1");
+        Ok(())
+    }
+
+    #[test]
+    fn depth_should_count_nodes_on_longest_path() -> Result<()> {
+        assert_eq!(depth_of("1")?, 1);
+        assert_eq!(depth_of("((1+1)+1)")?, 3);
+        assert_eq!(depth_of("1 + (2 * (3 - 4))")?, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_compute_expressions() -> Result<()> {
+        let cases = [
+            ("42", 42),
+            ("6 * 7", 42),
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("100 / 7", 14),
+            ("84 / 2 / 2", 21),
+            ("((2))", 2),
+            ("1; 2; 3", 6),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(eval_source(src)?, expected, "source: {}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_wrap_on_overflow() -> Result<()> {
+        assert_eq!(eval_source("9223372036854775807 + 1")?, i64::MIN);
+        Ok(())
+    }
+
+    #[test]
+    fn diff_should_report_folded_nodes() -> Result<()> {
+        let (ast_arena, ast_root) = parser::parse("2 + 3")?;
+        let (before, root) = irgen::generate(ast_arena, ast_root, "2 + 3")?;
+
+        let mut after = before.clone();
+        after[root].kind = Kind::IntValue(eval(&before, root)?);
+
+        let diff = diff(&before, root, &after, root)?;
+        let lines = diff.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "removed %0: int 2",
+                "removed %1: int 3",
+                "folded %2: iadd %0, %1 => int 5",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_check_assertions() -> Result<()> {
+        assert_eq!(eval_source("assert(true) + 2")?, 2);
+        let err = eval_source("assert(3 - 3 != 0)").unwrap_err();
+        assert_eq!(err.to_string(), "assertion failed at line 1:8: 3 - 3 != 0");
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_reject_runtime_input() {
+        assert!(eval_source("read_int() * 2").is_err());
+    }
+
+    #[test]
+    fn eval_should_truncate_division_toward_zero() -> Result<()> {
+        assert_eq!(eval_source("(0 - 7) / 2")?, -3);
+        assert_eq!(eval_source("(0 - 7) % 2")?, -1);
+        assert_eq!(eval_source("7 % (0 - 2)")?, 1);
+        assert_eq!(eval_source("(0 - 7) % (0 - 2)")?, -1);
+        assert_eq!(
+            eval_source("(0 - 9223372036854775807 - 1) / (0 - 1)")?,
+            i64::MIN
+        );
+        assert_eq!(eval_source("(0 - 9223372036854775807 - 1) % (0 - 1)")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn eval_should_report_division_by_zero() {
+        let err = eval_source("1 / (2 - 2)").unwrap_err();
+        assert!(err.to_string().contains("division by zero"));
+    }
+}
@@ -1,22 +1,78 @@
 // SPDX-License-Identifier: Unlicense
-use crate::{ast, ir};
+use crate::{ast, diagnostic, ir, sema};
 use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 
 pub struct IrGen {
     ast_arena: ast::Arena,
     ir_arena: ir::Arena,
+    source: String,
+    /// The `let` or parameter node each name is currently bound by.
+    bindings: HashMap<String, ir::Id>,
+    /// The `fn` node each function name is currently bound by, and its parameter count.
+    functions: HashMap<String, (ir::Id, usize)>,
+}
+
+enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+/// Returns the number of arguments the builtin function `name` takes.
+fn builtin_arity(name: &str) -> Option<Arity> {
+    match name {
+        "read_int" => Some(Arity::Exactly(0)),
+        "assert" => Some(Arity::Exactly(1)),
+        "debug_print" => Some(Arity::Exactly(1)),
+        "print" => Some(Arity::AtLeast(1)),
+        "print_uint" => Some(Arity::AtLeast(1)),
+        "env" => Some(Arity::Exactly(1)),
+        "abs" => Some(Arity::Exactly(1)),
+        "pow_mod" => Some(Arity::Exactly(3)),
+        _ => None,
+    }
 }
 
 impl IrGen {
-    fn new(ast_arena: ast::Arena) -> Self {
+    fn new(ast_arena: ast::Arena, source: &str) -> Self {
         Self {
             ast_arena,
             ir_arena: ir::Arena::new(),
+            source: source.to_string(),
+            bindings: HashMap::new(),
+            functions: HashMap::new(),
         }
     }
 
-    fn new_node(&mut self, kind: ir::Kind) -> ir::Id {
-        self.ir_arena.alloc(ir::Node { kind })
+    fn node(&self, id: ast::Id) -> Result<&ast::Node> {
+        self.ast_arena
+            .get(id)
+            .ok_or(anyhow!("failed to get ast node from arena"))
+    }
+
+    /// Returns the line, column and text of `id` in the source.
+    fn source_of(&self, id: ast::Id) -> Result<(usize, usize, &str)> {
+        let span = self.node(id)?.span.clone();
+        let (line, column) = diagnostic::position(&self.source, span.start);
+        Ok((line, column, self.source.get(span).unwrap_or("")))
+    }
+
+    fn binding(&self, name: &str) -> Result<ir::Id> {
+        self.bindings
+            .get(name)
+            .copied()
+            .ok_or(anyhow!("unknown variable '{}'", name))
+    }
+
+    fn new_node(&mut self, kind: ir::Kind, span: &ast::Span) -> ir::Id {
+        self.ir_arena.alloc(ir::Node {
+            kind,
+            span: Some(span.clone()),
+        })
+    }
+
+    fn new_op(&mut self, kind: ir::OpKind, args: Vec<ir::Id>, span: &ast::Span) -> ir::Id {
+        self.new_node(ir::Kind::Op(kind, args), span)
     }
 
     fn map_biop_kind(kind: &ast::BiOpKind) -> Result<ir::OpKind> {
@@ -25,34 +81,423 @@ impl IrGen {
             ast::BiOpKind::Sub => Ok(ir::OpKind::ISub),
             ast::BiOpKind::Mul => Ok(ir::OpKind::IMul),
             ast::BiOpKind::Div => Ok(ir::OpKind::IDiv),
+            ast::BiOpKind::Rem => Ok(ir::OpKind::IRem),
+            ast::BiOpKind::Pow => Ok(ir::OpKind::IPow),
+            ast::BiOpKind::Eq => Ok(ir::OpKind::ICmp(ir::Predicate::Eq)),
+            ast::BiOpKind::Ne => Ok(ir::OpKind::ICmp(ir::Predicate::Ne)),
+            ast::BiOpKind::Lt => Ok(ir::OpKind::ICmp(ir::Predicate::Lt)),
+            ast::BiOpKind::Le => Ok(ir::OpKind::ICmp(ir::Predicate::Le)),
+            ast::BiOpKind::Gt => Ok(ir::OpKind::ICmp(ir::Predicate::Gt)),
+            ast::BiOpKind::Ge => Ok(ir::OpKind::ICmp(ir::Predicate::Ge)),
+            ast::BiOpKind::BitAnd => Ok(ir::OpKind::And),
+            ast::BiOpKind::BitXor => Ok(ir::OpKind::Xor),
+            ast::BiOpKind::BitOr => Ok(ir::OpKind::Or),
+            ast::BiOpKind::And => Ok(ir::OpKind::LAnd),
+            ast::BiOpKind::Or => Ok(ir::OpKind::LOr),
         }
     }
 
+    /// Reads the environment variable named by the string literal `arg` as an integer.
+    fn env_value(&self, arg: ast::Id) -> Result<i64> {
+        let ast::NodeKind::Lit(ast::LitKind::StrLit(var)) = &self.node(arg)?.kind else {
+            anyhow::bail!("env() takes a string literal naming an environment variable");
+        };
+        let value =
+            std::env::var(var).map_err(|_| anyhow!("environment variable {} is not set", var))?;
+        value.trim().parse().map_err(|_| {
+            anyhow!(
+                "environment variable {} is not an integer: '{}'",
+                var,
+                value
+            )
+        })
+    }
+
     fn generate_impl(&mut self, root: ast::Id) -> Result<ir::Id> {
-        let kind = &self
-            .ast_arena
-            .get(root)
-            .ok_or(anyhow!("failed to get ast node from arena"))?
-            .kind
-            .clone();
+        let node = self.node(root)?.clone();
+        let (kind, span) = (&node.kind, &node.span);
         match kind {
             ast::NodeKind::Lit(lit) => match lit {
-                &ast::LitKind::IntLit(i) => Ok(self.new_node(ir::Kind::IntValue(i))),
+                &ast::LitKind::IntLit(i) => Ok(self.new_node(ir::Kind::IntValue(i), span)),
+                &ast::LitKind::BoolLit(b) => Ok(self.new_node(ir::Kind::BoolValue(b), span)),
+                ast::LitKind::StrLit(_) => Err(anyhow!(
+                    "string literals are only allowed as the argument of env()"
+                )),
             },
             ast::NodeKind::Paren(e) => self.generate_impl(*e),
+            ast::NodeKind::UnOp(ast::UnOpKind::Not, e) => {
+                let e = self.generate_impl(*e)?;
+                Ok(self.new_op(ir::OpKind::LNot, vec![e], span))
+            }
+            // `0 - x` overflows for i64::MIN as the overflow mode says, like abs()
+            ast::NodeKind::UnOp(ast::UnOpKind::Neg, e) => {
+                let e = self.generate_impl(*e)?;
+                let zero = self.new_node(ir::Kind::IntValue(0), span);
+                Ok(self.new_op(ir::OpKind::ISub, vec![zero, e], span))
+            }
             ast::NodeKind::BiOp(kind, lhs, rhs) => {
                 let op_kind = Self::map_biop_kind(&kind)?;
                 let lhs = self.generate_impl(*lhs)?;
                 let rhs = self.generate_impl(*rhs)?;
                 let args = vec![lhs, rhs];
-                Ok(self.new_node(ir::Kind::Op(op_kind, args)))
+                Ok(self.new_op(op_kind, args, span))
+            }
+            ast::NodeKind::Call(name, args) => self.generate_call(name, args, span),
+            ast::NodeKind::Seq(stmts) => {
+                let stmts = self.generate_stmts(stmts)?;
+                Ok(self.new_node(ir::Kind::Seq(stmts), span))
+            }
+            // the value is lowered first, so `let x = x + 1` refers to the previous `x`
+            ast::NodeKind::Let(name, _, value) => {
+                let value = self.generate_impl(*value)?;
+                let binding = self.new_node(ir::Kind::Let(name.clone(), value), span);
+                self.bindings.insert(name.clone(), binding);
+                Ok(binding)
             }
+            ast::NodeKind::Var(name) => {
+                let binding = self.binding(name)?;
+                Ok(self.new_node(ir::Kind::Var(binding), span))
+            }
+            // mutability was checked by sema::check
+            ast::NodeKind::Assign(name, value) => {
+                let value = self.generate_impl(*value)?;
+                let binding = self.binding(name)?;
+                Ok(self.new_node(ir::Kind::Assign(binding, value), span))
+            }
+            ast::NodeKind::Fn(name, params, body) => self.generate_fn(name, params, *body, span),
+            ast::NodeKind::If(cond, then, otherwise) => {
+                let cond = self.generate_impl(*cond)?;
+                let then = self.generate_impl(*then)?;
+                let otherwise = match otherwise {
+                    Some(otherwise) => self.generate_impl(*otherwise)?,
+                    None => self.new_node(ir::Kind::IntValue(0), span),
+                };
+                Ok(self.new_op(ir::OpKind::If, vec![cond, then, otherwise], span))
+            }
+            ast::NodeKind::For(..) => Err(anyhow!("loops are only allowed as statements")),
+            // placement was checked by sema::check
+            ast::NodeKind::Break => Ok(self.new_node(ir::Kind::Break, span)),
+            ast::NodeKind::Continue => Ok(self.new_node(ir::Kind::Continue, span)),
+        }
+    }
+
+    /// Lowers the statements `stmts`, expanding each `for` loop into several.
+    fn generate_stmts(&mut self, stmts: &[ast::Id]) -> Result<Vec<ir::Id>> {
+        let mut lowered = vec![];
+        for &stmt in stmts {
+            let node = self.node(stmt)?.clone();
+            match &node.kind {
+                ast::NodeKind::For(name, start, end, body) => {
+                    lowered.extend(self.generate_for(name, *start, *end, body, &node.span)?)
+                }
+                _ => lowered.push(self.generate_impl(stmt)?),
+            }
+        }
+        Ok(lowered)
+    }
+
+    /// Lowers `for name in start..end { body }` to bindings of the variable and of the end,
+    /// which the source cannot name, followed by an [`ir::Kind::Loop`] running the body
+    /// and incrementing the variable while it is below the end.
+    fn generate_for(
+        &mut self,
+        name: &str,
+        start: ast::Id,
+        end: ast::Id,
+        body: &[ast::Id],
+        span: &ast::Span,
+    ) -> Result<Vec<ir::Id>> {
+        let start = self.generate_impl(start)?;
+        let end = self.generate_impl(end)?;
+        let binding = self.new_node(ir::Kind::Let(name.to_string(), start), span);
+        let end = self.new_node(ir::Kind::Let(format!("{}.end", name), end), span);
+
+        let (var, end_var) = (
+            self.new_node(ir::Kind::Var(binding), span),
+            self.new_node(ir::Kind::Var(end), span),
+        );
+        let cond = self.new_op(
+            ir::OpKind::ICmp(ir::Predicate::Lt),
+            vec![var, end_var],
+            span,
+        );
+        let (var, one) = (
+            self.new_node(ir::Kind::Var(binding), span),
+            self.new_node(ir::Kind::IntValue(1), span),
+        );
+        let next = self.new_op(ir::OpKind::IAdd, vec![var, one], span);
+        let step = self.new_node(ir::Kind::Assign(binding, next), span);
+
+        // the variable and the bindings of the body are only visible in the body
+        let outer = self.bindings.clone();
+        self.bindings.insert(name.to_string(), binding);
+        let body = self.generate_stmts(body);
+        self.bindings = outer;
+        let operands = [cond, step].into_iter().chain(body?).collect();
+        let lowered = self.new_node(ir::Kind::Loop(operands), span);
+        Ok(vec![binding, end, lowered])
+    }
+
+    fn generate_fn(
+        &mut self,
+        name: &str,
+        params: &[String],
+        body: ast::Id,
+        span: &ast::Span,
+    ) -> Result<ir::Id> {
+        if builtin_arity(name).is_some() {
+            anyhow::bail!("cannot define function '{}', which is a builtin", name);
         }
+        // the function is bound before its body is lowered, so it can call itself
+        let function = self.new_node(ir::Kind::Fn(name.to_string(), vec![]), span);
+        self.functions
+            .insert(name.to_string(), (function, params.len()));
+
+        let mut bindings = HashMap::new();
+        let mut operands = vec![];
+        for param in params {
+            let id = self.new_node(ir::Kind::Param(param.clone()), span);
+            if bindings.insert(param.clone(), id).is_some() {
+                anyhow::bail!("parameter '{}' is declared twice in {}()", param, name);
+            }
+            operands.push(id);
+        }
+        // the body only sees the parameters, not the bindings around the definition
+        let outer = std::mem::replace(&mut self.bindings, bindings);
+        let body = self.generate_impl(body);
+        self.bindings = outer;
+        operands.push(body?);
+        self.ir_arena[function].kind = ir::Kind::Fn(name.to_string(), operands);
+        Ok(function)
+    }
+
+    fn generate_call(&mut self, name: &str, args: &[ast::Id], span: &ast::Span) -> Result<ir::Id> {
+        if let Some(&(function, params)) = self.functions.get(name) {
+            if args.len() != params {
+                anyhow::bail!(
+                    "{}() takes {} argument(s) but {} were given",
+                    name,
+                    params,
+                    args.len()
+                );
+            }
+            let args = args
+                .iter()
+                .map(|&arg| self.generate_impl(arg))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(self.new_node(ir::Kind::Call(function, args), span));
+        }
+        let arity = builtin_arity(name).ok_or(anyhow!("unknown function '{}'", name))?;
+        match arity {
+            Arity::Exactly(n) if args.len() != n => anyhow::bail!(
+                "{}() takes {} argument(s) but {} were given",
+                name,
+                n,
+                args.len()
+            ),
+            Arity::AtLeast(n) if args.len() < n => anyhow::bail!(
+                "{}() takes at least {} argument(s) but {} were given",
+                name,
+                n,
+                args.len()
+            ),
+            _ => {}
+        }
+        if name == "env" {
+            let value = self.env_value(args[0])?;
+            return Ok(self.new_node(ir::Kind::IntValue(value), span));
+        }
+        let op_kind = match name {
+            "read_int" => ir::OpKind::ReadInt,
+            "assert" => {
+                let (line, column, text) = self.source_of(args[0])?;
+                ir::OpKind::Assert(format!(
+                    "assertion failed at line {}:{}: {}",
+                    line, column, text
+                ))
+            }
+            "debug_print" => {
+                let (line, _, text) = self.source_of(args[0])?;
+                ir::OpKind::DebugPrint(format!("[line {}] {}", line, text))
+            }
+            "print" => ir::OpKind::Print,
+            "print_uint" => ir::OpKind::PrintUnsigned,
+            "abs" => ir::OpKind::Abs,
+            "pow_mod" => ir::OpKind::PowMod,
+            _ => anyhow::bail!("unknown function '{}'", name),
+        };
+        let args = args
+            .iter()
+            .map(|&arg| self.generate_impl(arg))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.new_op(op_kind, args, span))
     }
 }
 
-pub fn generate(ast_arena: ast::Arena, root: ast::Id) -> Result<(ir::Arena, ir::Id)> {
-    let mut irgen = IrGen::new(ast_arena);
-    let ir = irgen.generate_impl(root)?;
+/// Lowers the AST rooted at `root`; `source` is the text it was parsed from.
+pub fn generate(ast_arena: ast::Arena, root: ast::Id, source: &str) -> Result<(ir::Arena, ir::Id)> {
+    sema::check(&ast_arena, root)?;
+    let mut irgen = IrGen::new(ast_arena, source);
+    let ir = match irgen.node(root)?.clone() {
+        // a lone loop still lowers to several statements
+        ast::Node {
+            kind: ast::NodeKind::For(..),
+            span,
+        } => {
+            let stmts = irgen.generate_stmts(&[root])?;
+            irgen.new_node(ir::Kind::Seq(stmts), &span)
+        }
+        _ => irgen.generate_impl(root)?,
+    };
+    ir::validate(&irgen.ir_arena, ir)?;
     Ok((irgen.ir_arena, ir))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn eval(src: &str) -> Result<i64> {
+        let (ast_arena, ast_root) = parser::parse(src)?;
+        let (ir_arena, ir_root) = generate(ast_arena, ast_root, src)?;
+        ir::eval(&ir_arena, ir_root)
+    }
+
+    #[test]
+    fn ir_nodes_should_carry_source_spans() -> Result<()> {
+        let src = "1; 6 * 7";
+        let (ast_arena, ast_root) = parser::parse(src)?;
+        let (ir_arena, ir_root) = generate(ast_arena, ast_root, src)?;
+        let ir::Kind::Seq(stmts) = &ir_arena[ir_root].kind else {
+            panic!("expected a statement sequence");
+        };
+        assert_eq!(ir_arena[stmts[1]].span, Some(3..8));
+        let ir::Kind::Op(_, args) = &ir_arena[stmts[1]].kind else {
+            panic!("expected a multiplication");
+        };
+        assert_eq!(ir_arena[args[0]].span, Some(3..4));
+        assert_eq!(ir_arena[args[1]].span, Some(7..8));
+        Ok(())
+    }
+
+    #[test]
+    fn variables_should_refer_to_latest_binding() -> Result<()> {
+        assert_eq!(eval("let x = 6 * 7; x")?, 42);
+        // bindings evaluate to zero, so only the other statements add up
+        assert_eq!(eval("let x = 1; let x = x + 10; x; x * 2")?, 33);
+        let err = eval("let x = y").unwrap_err();
+        assert_eq!(err.to_string(), "unknown variable 'y'");
+        Ok(())
+    }
+
+    #[test]
+    fn calls_should_bind_arguments_to_parameters() -> Result<()> {
+        assert_eq!(eval("fn add(a, b) = a + b; add(40, 2)")?, 42);
+        // parameters shadow outer bindings, and calls refer to the latest definition
+        let src = "let a = 100; fn f(a) = a * 2; fn g(b) = f(b) + 1; fn f(c) = c; f(a) + g(a)";
+        assert_eq!(eval(src)?, 100 + 201);
+        // recursion ends through short-circuiting
+        assert_eq!(
+            eval("fn odd(n) = n > 0 && !odd(n - 1); odd(7); odd(10)")?,
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn functions_should_reject_bad_definitions_and_calls() {
+        let error = |src: &str| eval(src).unwrap_err().to_string();
+        assert_eq!(error("let x = 1; fn f() = x"), "unknown variable 'x'");
+        assert_eq!(
+            error("fn f(a) = a; f(1, 2)"),
+            "f() takes 1 argument(s) but 2 were given"
+        );
+        assert_eq!(
+            error("fn f(a, a) = a"),
+            "parameter 'a' is declared twice in f()"
+        );
+        assert_eq!(
+            error("fn abs(x) = x"),
+            "cannot define function 'abs', which is a builtin"
+        );
+        assert_eq!(error("f(1); fn f(a) = a"), "unknown function 'f'");
+        assert!(error("fn f(n) = f(n + 1); f(0)").contains("cannot be evaluated at compile time"));
+    }
+
+    #[test]
+    fn conditionals_should_only_evaluate_the_picked_branch() -> Result<()> {
+        assert_eq!(eval("let a = 3; let b = 5; if a < b { a } else { b }")?, 3);
+        assert_eq!(eval("if false { 1 / 0 }")?, 0);
+        let src = "fn fib(n) = if n < 2 { n } else { fib(n - 1) + fib(n - 2) }; fib(20)";
+        assert_eq!(eval(src)?, 6765);
+        Ok(())
+    }
+
+    #[test]
+    fn loops_should_scope_their_variable_to_the_body() -> Result<()> {
+        let src =
+            "let i = 100; let mut sum = 0; for i in 1..5 { let j = i * i; sum = sum + j }; sum + i";
+        assert_eq!(eval(src)?, 30 + 100);
+        assert_eq!(
+            eval("for i in 0..3 { let j = i }; j")
+                .unwrap_err()
+                .to_string(),
+            "unknown variable 'j'"
+        );
+        assert_eq!(
+            eval("for i in 0..i {}").unwrap_err().to_string(),
+            "unknown variable 'i'"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bools_should_count_as_zero_or_one() -> Result<()> {
+        assert_eq!(eval("true; false; 1 < 2")?, 2);
+        assert_eq!(eval("let t = true; if t { 6 * 7 } else { 0 }")?, 42);
+        assert!(eval("true + true").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn logical_operators_should_take_bools() -> Result<()> {
+        assert_eq!(eval("true && 1 < 5; 0 > 1 || false; !true; !(0 > 1)")?, 2);
+        assert_eq!(eval("1 < 2 && true == (2 > 1)")?, 1);
+        assert!(eval("true && 5").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn env_should_substitute_integer_variable() -> Result<()> {
+        std::env::set_var("BONSAI_TEST_SCALE", "6");
+        assert_eq!(eval(r#"env("BONSAI_TEST_SCALE") * 7"#)?, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn env_should_reject_unset_variable() {
+        std::env::remove_var("BONSAI_TEST_UNSET");
+        let err = eval(r#"env("BONSAI_TEST_UNSET")"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "environment variable BONSAI_TEST_UNSET is not set"
+        );
+    }
+
+    #[test]
+    fn env_should_reject_non_integer_variable() {
+        std::env::set_var("BONSAI_TEST_WORD", "six");
+        let err = eval(r#"env("BONSAI_TEST_WORD")"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "environment variable BONSAI_TEST_WORD is not an integer: 'six'"
+        );
+    }
+
+    #[test]
+    fn string_literal_should_only_be_allowed_in_env() {
+        assert!(eval(r#""SCALE" + 1"#).is_err());
+        assert!(eval("env(1)").is_err());
+    }
+}
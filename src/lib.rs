@@ -1,7 +1,43 @@
 // SPDX-License-Identifier: Unlicense
-mod ast;
-mod parser;
-mod ir;
+pub mod ast;
+pub mod parser;
+mod preprocess;
+pub mod ir;
 mod irgen;
+mod sema;
+pub mod fold;
+pub mod pass;
 mod codegen;
 pub mod driver;
+pub mod build;
+pub mod diagnostic;
+pub mod lint;
+
+use anyhow::Result;
+
+/// Parses, lowers and interprets `source` without touching LLVM or the filesystem.
+///
+/// ```
+/// assert_eq!(bonsai::eval_str("6 * 7").unwrap(), 42);
+/// ```
+pub fn eval_str(source: &str) -> Result<i64> {
+    let (ast_arena, ast_root) = parser::parse(source)?;
+    let (ir_arena, ir_root) = irgen::generate(ast_arena, ast_root, source)?;
+    ir::eval(&ir_arena, ir_root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_str_should_evaluate_expression() -> Result<()> {
+        assert_eq!(eval_str("6 * 7")?, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn eval_str_should_fail_on_division_by_zero() {
+        assert!(eval_str("1/0").is_err());
+    }
+}
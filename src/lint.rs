@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: Unlicense
+use crate::{ast, diagnostic::Diagnostic};
+use anyhow::{anyhow, Result};
+
+/// Parentheses around a literal, another parenthesized expression or a whole statement.
+pub const REDUNDANT_PAREN: &str = "redundant-paren";
+/// The right operand of `&&`/`||` that can never be evaluated.
+pub const DEAD_BRANCH: &str = "dead-branch";
+
+/// Every warning kind [`lint`] can report.
+pub const KINDS: &[&str] = &[REDUNDANT_PAREN, DEAD_BRANCH];
+
+fn node(arena: &ast::Arena, id: ast::Id) -> Result<&ast::Node> {
+    arena
+        .get(id)
+        .ok_or(anyhow!("failed to get ast node from arena"))
+}
+
+fn lint_impl(arena: &ast::Arena, id: ast::Id, warnings: &mut Vec<Diagnostic>) -> Result<()> {
+    let node = node(arena, id)?;
+    match &node.kind {
+        ast::NodeKind::Lit(_) => {}
+        ast::NodeKind::Paren(e) => {
+            if matches!(
+                node(arena, *e)?.kind,
+                ast::NodeKind::Lit(_) | ast::NodeKind::Paren(_)
+            ) {
+                warnings.push(
+                    Diagnostic::warning("unnecessary parentheses")
+                        .with_span(node.span.clone())
+                        .with_kind(REDUNDANT_PAREN),
+                );
+            }
+            lint_impl(arena, *e, warnings)?;
+        }
+        ast::NodeKind::UnOp(_, e) => lint_impl(arena, *e, warnings)?,
+        ast::NodeKind::BiOp(op, lhs, rhs) => {
+            let decided = match (op, ast::eval(arena, *lhs)) {
+                (ast::BiOpKind::And, Ok(0)) => Some("false"),
+                (ast::BiOpKind::Or, Ok(value)) if value != 0 => Some("true"),
+                _ => None,
+            };
+            if let Some(value) = decided {
+                warnings.push(
+                    Diagnostic::warning(format!(
+                        "right operand is never evaluated since the left one is always {}",
+                        value
+                    ))
+                    .with_span(node(arena, *rhs)?.span.clone())
+                    .with_kind(DEAD_BRANCH),
+                );
+            }
+            lint_impl(arena, *lhs, warnings)?;
+            lint_impl(arena, *rhs, warnings)?;
+        }
+        ast::NodeKind::Call(_, args) => {
+            for &arg in args {
+                lint_impl(arena, arg, warnings)?;
+            }
+        }
+        ast::NodeKind::Let(_, _, value)
+        | ast::NodeKind::Assign(_, value)
+        | ast::NodeKind::Fn(_, _, value) => lint_impl(arena, *value, warnings)?,
+        ast::NodeKind::Var(_) | ast::NodeKind::Break | ast::NodeKind::Continue => {}
+        ast::NodeKind::For(_, start, end, body) => {
+            lint_impl(arena, *start, warnings)?;
+            lint_impl(arena, *end, warnings)?;
+            for &stmt in body {
+                lint_stmt(arena, stmt, warnings)?;
+            }
+        }
+        ast::NodeKind::If(cond, then, otherwise) => {
+            for &e in [cond, then].into_iter().chain(otherwise) {
+                lint_impl(arena, e, warnings)?;
+            }
+        }
+        ast::NodeKind::Seq(stmts) => {
+            for &stmt in stmts {
+                lint_stmt(arena, stmt, warnings)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn lint_stmt(arena: &ast::Arena, id: ast::Id, warnings: &mut Vec<Diagnostic>) -> Result<()> {
+    let node = node(arena, id)?;
+    match node.kind {
+        // parentheses around a literal or parentheses are reported by lint_impl
+        ast::NodeKind::Paren(e)
+            if !matches!(
+                arena[e].kind,
+                ast::NodeKind::Lit(_) | ast::NodeKind::Paren(_)
+            ) =>
+        {
+            warnings.push(
+                Diagnostic::warning("unnecessary parentheses around statement")
+                    .with_span(node.span.clone())
+                    .with_kind(REDUNDANT_PAREN),
+            );
+        }
+        _ => {}
+    }
+    lint_impl(arena, id, warnings)
+}
+
+/// Returns warnings about suspicious but valid code in the tree rooted at `root`.
+pub fn lint(arena: &ast::Arena, root: ast::Id) -> Result<Vec<Diagnostic>> {
+    let mut warnings = Vec::new();
+    match node(arena, root)?.kind {
+        ast::NodeKind::Seq(_) => lint_impl(arena, root, &mut warnings)?,
+        _ => lint_stmt(arena, root, &mut warnings)?,
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn kinds(src: &str) -> Result<Vec<&'static str>> {
+        let (arena, root) = parser::parse(src)?;
+        Ok(lint(&arena, root)?
+            .into_iter()
+            .filter_map(|diag| diag.kind)
+            .collect())
+    }
+
+    #[test]
+    fn lint_should_report_redundant_parens() -> Result<()> {
+        assert_eq!(kinds("(1) + 2")?, [REDUNDANT_PAREN]);
+        assert_eq!(kinds("((1 + 2)) * 3")?, [REDUNDANT_PAREN]);
+        assert_eq!(kinds("1; (2 * 3)")?, [REDUNDANT_PAREN]);
+        assert!(kinds("(1 + 2) * 3")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn lint_should_report_dead_branches() -> Result<()> {
+        assert_eq!(kinds("false && read_int() > 0")?, [DEAD_BRANCH]);
+        assert_eq!(kinds("2 - 1 == 1 || read_int() > 0")?, [DEAD_BRANCH]);
+        assert!(kinds("read_int() > 0 && false")?.is_empty());
+        Ok(())
+    }
+}
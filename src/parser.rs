@@ -1,56 +1,1112 @@
 // SPDX-License-Identifier: Unlicense
-use crate::ast;
+use crate::{
+    ast,
+    diagnostic::{self, Diagnostic, LineDirective},
+    preprocess,
+};
 use anyhow::{anyhow, Result};
-use std::cell::RefCell;
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, ops::Range};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Context {
     pub arena: RefCell<ast::Arena>,
+    /// `#line` directives seen so far, keyed by the offset they take effect from.
+    pub line_directives: RefCell<BTreeMap<usize, (usize, String)>>,
+    /// The last construct an action recognized but rejected, e.g. a reserved keyword
+    /// used as an identifier. Reported instead of the generic parse error when the
+    /// parse fails within its span.
+    pub rejection: RefCell<Option<Diagnostic>>,
+    /// The first `let` that binds a name twice, reported once the source has parsed.
+    pub rebinding: RefCell<Option<Diagnostic>>,
+}
+
+impl Context {
+    pub fn line_directives(&self) -> Vec<LineDirective> {
+        self.line_directives
+            .borrow()
+            .iter()
+            .map(|(&offset, (line, file))| LineDirective {
+                offset,
+                line: *line,
+                file: file.clone(),
+            })
+            .collect()
+    }
 }
 
 peg::parser! {
     grammar main_parser(context: &Context) for str {
         #[cache]
-        rule _() = quiet!{[' '|'\t'|'\r'|'\n']*{}}
+        rule _() = quiet!{([' '|'\t'|'\r'|'\n'] {} / comment() / line_directive())*{}}
+
+        rule comment()
+            = "//" [^'\n']*
+            / "/*" (!"*/" [_])* "*/"
 
-        rule node(r: rule<ast::NodeKind>) -> ast::Id = n: r() {
+        rule line_directive()
+            = "#line" [' '|'\t']+ n:$(['0' ..= '9']+) [' '|'\t']+
+              "\"" f:$([^'"' | '\n']*) "\"" p:position!() {?
+            let line = n.parse().or(Err("line number"))?;
+            context.line_directives.borrow_mut().insert(p, (line, f.to_string()));
+            Ok(())
+        }
+
+        rule node(r: rule<ast::NodeKind>) -> ast::Id = s:position!() n: r() e:position!() {
             let mut arena = context.arena.borrow_mut();
-            arena.alloc(ast::Node{ kind: n })
+            arena.alloc(ast::Node{ kind: n, span: s..e })
         }
 
-        rule int_lit() -> ast::NodeKind = _ n:$(['0' ..= '9']+) {
-            ast::NodeKind::Lit(ast::LitKind::IntLit(n.parse().unwrap()))
+        rule int_lit() -> ast::NodeKind = _ n:(
+                "0x" d:$(['0' ..= '9' | 'a' ..= 'f' | 'A' ..= 'F']+) {?
+                    i64::from_str_radix(d, 16).or(Err("hexadecimal literal that fits in 64 bits"))
+                }
+                / "0o" d:$(['0' ..= '7']+) {?
+                    i64::from_str_radix(d, 8).or(Err("octal literal that fits in 64 bits"))
+                }
+                / "0b" d:$(['0' | '1']+) {?
+                    i64::from_str_radix(d, 2).or(Err("binary literal that fits in 64 bits"))
+                }
+                / d:$(['0' ..= '9']+) {?
+                    d.parse().or(Err("integer literal that fits in 64 bits"))
+                }
+            ) {
+            ast::NodeKind::Lit(ast::LitKind::IntLit(n))
+        }
+
+        rule bool_lit() -> ast::NodeKind
+            = _ b:("true" { true } / "false" { false }) !ident_char() {
+            ast::NodeKind::Lit(ast::LitKind::BoolLit(b))
+        }
+
+        rule str_lit() -> ast::NodeKind = _ "\"" s:$([^'"' | '\n']*) "\"" {
+            ast::NodeKind::Lit(ast::LitKind::StrLit(s.to_string()))
+        }
+
+        rule ident() -> String
+            = _ s:position!() raw:"r#"? n:$(['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) {?
+            if raw.is_none() && KEYWORDS.contains(&n) {
+                let message = format!("'{}' is a reserved keyword", n);
+                let diag = Diagnostic::error(message).with_span(s..s + n.len()).with_code("E0003");
+                *context.rejection.borrow_mut() = Some(diag);
+                Err("identifier")
+            } else {
+                Ok(n.to_string())
+            }
         }
 
         rule expr() -> ast::Id = precedence! {
-            _:position!() p:@ _:position!() {
+            s:position!() p:@ e:position!() {
                 let mut arena = context.arena.borrow_mut();
                 arena.alloc(
                     ast::Node {
                         kind: p,
+                        span: s..e,
                     }
                 )
             }
             --
+            x:(@) (_ "||") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Or, x, y) }
+            --
+            x:(@) (_ "&&") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::And, x, y) }
+            --
+            x:(@) (_ "==") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Eq, x, y) }
+            x:(@) (_ "!=") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Ne, x, y) }
+            x:(@) (_ "<=") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Le, x, y) }
+            x:(@) (_ ">=") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Ge, x, y) }
+            x:(@) (_ "<" !"=") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Lt, x, y) }
+            x:(@) (_ ">" !"=") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Gt, x, y) }
+            --
+            x:(@) (_ "|" !"|") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::BitOr, x, y) }
+            --
+            x:(@) (_ "^") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::BitXor, x, y) }
+            --
+            x:(@) (_ "&" !"&") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::BitAnd, x, y) }
+            --
             x:(@) (_ "+") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Add, x, y) }
             x:(@) (_ "-") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Sub, x, y) }
             --
-            x:(@) (_ "*") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Mul, x, y) }
+            x:(@) (_ "*" !"*") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Mul, x, y) }
             x:(@) (_ "/") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Div, x, y) }
+            x:(@) (_ "%") y:@ { ast::NodeKind::BiOp(ast::BiOpKind::Rem, x, y) }
+            --
+            (_ "!") x:@ { ast::NodeKind::UnOp(ast::UnOpKind::Not, x) }
+            (_ "-") x:@ { ast::NodeKind::UnOp(ast::UnOpKind::Neg, x) }
+            --
+            x:@ (_ "**") y:(@) { ast::NodeKind::BiOp(ast::BiOpKind::Pow, x, y) }
+            // a negated exponent needs no parentheses, e.g. `2 ** -1`
+            x:@ (_ "**") _ s:position!() op:("-" { ast::UnOpKind::Neg } / "!" { ast::UnOpKind::Not })
+              y:(@) {
+                let mut arena = context.arena.borrow_mut();
+                let span = s..arena[y].span.end;
+                let y = arena.alloc(ast::Node { kind: ast::NodeKind::UnOp(op, y), span });
+                ast::NodeKind::BiOp(ast::BiOpKind::Pow, x, y)
+            }
             --
             n: int_lit() { n }
 
+            s: str_lit() { s }
+
+            b: bool_lit() { b }
+
+            i: if_expr() { i }
+
+            _ "break" !ident_char() { ast::NodeKind::Break }
+
+            _ "continue" !ident_char() { ast::NodeKind::Continue }
+
+            // a `(` on the next line starts a new statement rather than calling the name
+            name:ident() [' ' | '\t']* "(" args:(expr() ** (_ ",")) _ ")" {
+                ast::NodeKind::Call(name, args)
+            }
+
+            name:ident() { ast::NodeKind::Var(name) }
+
+            _ s:position!() "(" expr() (_ "," expr())+ (_ ",")? _ ")" e:position!() {?
+                let diag = Diagnostic::error("tuples are not supported")
+                    .with_span(s..e)
+                    .with_code("E0004");
+                *context.rejection.borrow_mut() = Some(diag);
+                Err("expression")
+            }
+
             _ "(" e:expr() _ ")" { ast::NodeKind::Paren(e) }
         }
-        pub rule parse() -> ast::Id = n:expr() _ { n }
+        rule if_expr() -> ast::NodeKind
+            = _ "if" !ident_char() cond:expr() then:block()
+              otherwise:(_ "else" !ident_char() e:(node(<if_expr()>) / block()) { e })? {
+            ast::NodeKind::If(cond, then, otherwise)
+        }
+
+        rule block() -> ast::Id = _ "{" e:expr() _ "}" { e }
+
+        rule stmt() -> Vec<ast::Id> = s:fn_stmt() { vec![s] } / body_stmt()
+
+        // functions can only be defined at the top level
+        rule body_stmt() -> Vec<ast::Id>
+            = let_stmt() / s:(for_stmt() / assign_stmt() / expr()) { vec![s] }
+
+        rule for_stmt() -> ast::Id
+            = _ s:position!() "for" !ident_char() name:ident() _ "in" !ident_char()
+              start:expr() _ ".." end:expr() _ "{" body:(body_stmt() ** separator()) (_ ";")? _ "}"
+              e:position!() {
+            let mut arena = context.arena.borrow_mut();
+            let kind = ast::NodeKind::For(name, start, end, body.concat());
+            arena.alloc(ast::Node { kind, span: s..e })
+        }
+
+        rule fn_stmt() -> ast::Id
+            = _ s:position!() "fn" !ident_char() name:ident() _ "(" params:(ident() ** (_ ",")) _ ")"
+              _ "=" !"=" body:expr() e:position!() {
+            let mut arena = context.arena.borrow_mut();
+            arena.alloc(ast::Node { kind: ast::NodeKind::Fn(name, params, body), span: s..e })
+        }
+
+        // `let a = 1, b = a + 1` binds in order, as if each binding were its own statement
+        rule let_stmt() -> Vec<ast::Id>
+            = _ s:position!() "let" !ident_char() bindings:(let_binding() ++ (_ ",")) {
+            let mut arena = context.arena.borrow_mut();
+            arena[bindings[0]].span.start = s;
+            for (i, &binding) in bindings.iter().enumerate() {
+                let ast::NodeKind::Let(name, ..) = &arena[binding].kind else {
+                    continue;
+                };
+                let rebound = bindings[..i].iter().any(|&earlier| {
+                    matches!(&arena[earlier].kind, ast::NodeKind::Let(other, ..) if other == name)
+                });
+                if rebound && context.rebinding.borrow().is_none() {
+                    let message = format!("'{}' is bound twice in the same let", name);
+                    let diag = Diagnostic::error(message)
+                        .with_span(arena[binding].span.clone())
+                        .with_code("E0013");
+                    *context.rebinding.borrow_mut() = Some(diag);
+                }
+            }
+            bindings
+        }
+
+        rule let_binding() -> ast::Id
+            = _ s:position!() mutable:("mut" !ident_char())? name:ident() _ "=" !"=" value:expr()
+              e:position!() {
+            let mut arena = context.arena.borrow_mut();
+            let kind = ast::NodeKind::Let(name, mutable.is_some(), value);
+            arena.alloc(ast::Node { kind, span: s..e })
+        }
+
+        // a keyword cannot be assigned to, and trying it would leave a misleading rejection
+        // behind when the statement turns out to be an expression like `true`
+        rule assign_stmt() -> ast::Id
+            = _ s:position!() !keyword() name:ident() _ "=" !"=" value:expr() e:position!() {
+            let mut arena = context.arena.borrow_mut();
+            arena.alloc(ast::Node { kind: ast::NodeKind::Assign(name, value), span: s..e })
+        }
+
+        rule ident_char() = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
+
+        rule keyword() = n:$(ident_char()+) {?
+            if KEYWORDS.contains(&n) { Ok(()) } else { Err("keyword") }
+        }
+
+        // a line ending after a complete statement separates it from the next one, unless
+        // the next line continues it with an operator
+        rule separator() = _ ";" / line_break()
+
+        rule line_break()
+            = quiet!{([' '|'\t'|'\r'] / "/*" (!"*/" [^'\n'])* "*/")* ("//" [^'\n']*)? "\n"}
+
+        pub rule parse() -> ast::Id
+            = s:position!() stmts:(stmt() ++ separator()) (_ ";")? e:position!() _ {
+            let stmts = stmts.concat();
+            if stmts.len() == 1 {
+                stmts[0]
+            } else {
+                let mut arena = context.arena.borrow_mut();
+                arena.alloc(ast::Node { kind: ast::NodeKind::Seq(stmts), span: s..e })
+            }
+        }
+
+        rule token() -> &'input str
+            = $("0x" ['0' ..= '9' | 'a' ..= 'f' | 'A' ..= 'F']+)
+            / $("0o" ['0' ..= '7']+)
+            / $("0b" ['0' | '1']+)
+            / $(['0' ..= '9']+)
+            / $("\"" [^'"' | '\n']* "\"")
+            / $("r#"? ['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*)
+            / $("&&" / "||" / "**" / "==" / "!=" / "<=" / ">=" / "..")
+            / $(['+' | '-' | '*' | '/' | '%' | '!' | '&' | '^' | '|' | '<' | '>' | '=' | '(' | ')' | '{' | '}' | ',' | ';'])
+
+        // matches input made of nothing but whitespace, comments and `#line` directives
+        pub rule trivia() = _
+
+        pub rule tokens() -> Vec<&'input str> = t:(_ t:token() { t })* _ { t }
+    }
+}
+
+/// Words that cannot be used as identifiers unless written as raw identifiers, e.g. `r#let`.
+pub const KEYWORDS: &[&str] = &[
+    "let", "mut", "if", "else", "while", "for", "in", "break", "continue", "fn", "return", "true",
+    "false",
+];
+
+/// Maximum nesting of parentheses and braces accepted by the parser.
+///
+/// The grammar is recursive, so unbounded nesting would overflow the stack.
+pub const MAX_NESTING_DEPTH: usize = 256;
+
+/// Brackets in comments and string literals don't count, as the grammar skips those.
+fn check_nesting(source: &str) -> Result<()> {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while let Some(c) = source[i..].chars().next() {
+        let rest = &source[i..];
+        // an unterminated comment or string runs to the end, which fails to parse anyway
+        let skipped = if rest.starts_with("//") {
+            Some(rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") {
+            Some(rest[2..].find("*/").map_or(rest.len(), |end| end + 4))
+        } else if c == '"' {
+            Some(
+                rest[1..]
+                    .find(['"', '\n'])
+                    .map_or(rest.len(), |end| end + 2),
+            )
+        } else {
+            None
+        };
+        if let Some(len) = skipped {
+            i += len;
+            continue;
+        }
+        match c {
+            '(' | '{' => {
+                depth += 1;
+                if depth > MAX_NESTING_DEPTH {
+                    let message = format!(
+                        "failed to parse: expression nested deeper than {} levels",
+                        MAX_NESTING_DEPTH
+                    );
+                    return Err(Diagnostic::error(message)
+                        .with_span(i..i + 1)
+                        .with_code("E0002")
+                        .into());
+                }
+            }
+            ')' | '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        i += c.len_utf8();
     }
+    Ok(())
+}
+
+/// Blanks out a `#!` line at the very start of `source`, keeping every offset intact.
+fn strip_shebang(source: &str) -> Cow<'_, str> {
+    if !source.starts_with("#!") {
+        return Cow::Borrowed(source);
+    }
+    let end = source.find('\n').unwrap_or(source.len());
+    Cow::Owned(" ".repeat(end) + &source[end..])
 }
 
 pub fn parse(source: &str) -> Result<(ast::Arena, ast::Id)> {
-    let arena_cell = RefCell::new(ast::Arena::new());
-    let context = Context { arena: arena_cell };
-    let root =
-        main_parser::parse(source, &context).map_err(|e| anyhow!("failed to parse: {}", e))?;
+    let source = strip_shebang(source);
+    let source = source.as_ref();
+    let expansion = preprocess::expand(source)?;
+    let text = expansion.as_ref().map_or(source, |e| e.text.as_str());
+    let original = |span: Range<usize>| match &expansion {
+        Some(expansion) => expansion.to_original_span(&span),
+        None => span,
+    };
+    if main_parser::trivia(text, &Context::default()).is_ok() {
+        let message = if text.trim().is_empty() {
+            "program is empty"
+        } else {
+            "program contains no expression, only comments"
+        };
+        return Err(Diagnostic::error(message).with_code("E0001").into());
+    }
+    check_nesting(text).map_err(|e| match e.downcast::<Diagnostic>() {
+        Ok(diag) => Diagnostic {
+            span: diag.span.clone().map(original),
+            ..diag
+        }
+        .into(),
+        Err(e) => e,
+    })?;
+    let context = Context::default();
+    let directives = || {
+        let mut directives = context.line_directives();
+        for directive in &mut directives {
+            directive.offset = original(directive.offset..directive.offset).start;
+        }
+        directives
+    };
+    let root = main_parser::parse(text, &context).map_err(|e| {
+        let offset = e.location.offset;
+        let directives = directives();
+        let diag = match context.rejection.take() {
+            // a rejecting action reports the failure at the end of what it matched
+            Some(diag)
+                if diag
+                    .span
+                    .as_ref()
+                    .is_some_and(|span| (span.start..=span.end).contains(&offset)) =>
+            {
+                diag
+            }
+            _ => Diagnostic::error(format!("failed to parse: expected {}", e.expected))
+                .with_span(offset..offset + 1)
+                .with_code("E0001"),
+        };
+        let location = diagnostic::locate(source, original(offset..offset).start, &directives);
+        Diagnostic {
+            span: diag.span.clone().map(original),
+            ..diag
+        }
+        .with_location(location)
+    })?;
+
+    // operands consume the trivia in front of them, so move each start past it
+    let mut arena = context.arena.take();
+    for (_, node) in arena.iter_mut() {
+        node.span.start = skip_trivia(text, node.span.start).min(node.span.end);
+        node.span = original(node.span.clone());
+    }
+
+    if let Some(diag) = context.rebinding.take() {
+        let span = diag.span.clone().unwrap_or_default();
+        let span = original(skip_trivia(text, span.start).min(span.end)..span.end);
+        let location = diagnostic::locate(source, span.start, &directives());
+        return Err(diag.with_span(span).with_location(location).into());
+    }
+
+    // comparisons parse left-associatively, but `1 < 2 < 3` would compare a 0/1 result
+    let chained = arena.iter().find(|(_, node)| match &node.kind {
+        ast::NodeKind::BiOp(op, lhs, _) if ast::is_comparison(op) => {
+            matches!(&arena[*lhs].kind, ast::NodeKind::BiOp(op, _, _) if ast::is_comparison(op))
+        }
+        _ => false,
+    });
+    if let Some((_, node)) = chained {
+        let location = diagnostic::locate(source, node.span.start, &directives());
+        return Err(Diagnostic::error("comparison operators cannot be chained")
+            .with_span(node.span.clone())
+            .with_code("E0007")
+            .with_location(location)
+            .into());
+    }
+    Ok((arena, root))
+}
+
+/// Parses `source` on a dedicated thread with a `stack_size`-byte stack.
+///
+/// The grammar is recursive, so deeply nested input can exhaust a small default stack
+/// before [`MAX_NESTING_DEPTH`] is reached.
+pub fn parse_with_stack_size(source: &str, stack_size: usize) -> Result<(ast::Arena, ast::Id)> {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .name("bonsai-parser".into())
+            .stack_size(stack_size)
+            .spawn_scoped(scope, || parse(source))?
+            .join()
+            .map_err(|_| anyhow!("parser thread panicked"))?
+    })
+}
+
+/// Returns the offset of the first token at or after `offset`, skipping whitespace,
+/// comments and `#line` directives.
+fn skip_trivia(source: &str, mut offset: usize) -> usize {
+    loop {
+        let rest = source.get(offset..).unwrap_or("");
+        let trimmed = rest.trim_start_matches([' ', '\t', '\r', '\n']);
+        offset += rest.len() - trimmed.len();
+        let skipped = if trimmed.starts_with("//") || trimmed.starts_with("#line") {
+            trimmed.find('\n').unwrap_or(trimmed.len())
+        } else if let Some(comment) = trimmed.strip_prefix("/*") {
+            comment.find("*/").map_or(trimmed.len(), |end| end + 4)
+        } else {
+            return offset;
+        };
+        offset += skipped;
+    }
+}
+
+/// Splits `source` into the tokens the grammar recognizes.
+pub fn tokenize(source: &str) -> Result<Vec<String>> {
+    let context = Context::default();
+    let source = strip_shebang(source);
+    let tokens = main_parser::tokens(&source, &context)
+        .map_err(|e| anyhow!("failed to tokenize: {}", e))?;
+    Ok(tokens.into_iter().map(String::from).collect())
+}
+
+/// Parses arbitrary bytes, interpreting them as lossy UTF-8.
+///
+/// Never panics; intended as the entry point for fuzz targets.
+pub fn fuzz_parse(data: &[u8]) -> Result<(ast::Arena, ast::Id)> {
+    parse(&String::from_utf8_lossy(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok((context.arena.take(), root))
+    #[test]
+    fn parser_should_reject_too_large_int_literal() {
+        assert!(parse("99999999999999999999").is_err());
+    }
+
+    fn int_value(src: &str) -> Result<i64> {
+        let (arena, root) = parse(src)?;
+        match arena[root].kind {
+            ast::NodeKind::Lit(ast::LitKind::IntLit(i)) => Ok(i),
+            ref kind => Err(anyhow!("expected an integer literal, got {:?}", kind)),
+        }
+    }
+
+    #[test]
+    fn parser_should_accept_radix_prefixed_literals() -> Result<()> {
+        assert_eq!(int_value("0xff")?, 255);
+        assert!(int_value("0XFF").is_err());
+        assert_eq!(int_value("0x7FFFFFFFFFFFFFFF")?, i64::MAX);
+        assert_eq!(int_value("0o17")?, 15);
+        assert_eq!(int_value("0b101")?, 5);
+        assert_eq!(int_value("010")?, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_reject_malformed_radix_literals() {
+        for src in ["0x", "0o8", "0b2", "0x8000000000000000"] {
+            assert!(parse(src).is_err(), "source: {}", src);
+        }
+    }
+
+    #[test]
+    fn parser_should_bind_negation_tighter_than_multiplication() -> Result<()> {
+        let dump = |src: &str| -> Result<String> {
+            let (arena, root) = parse(src)?;
+            ast::dump(&arena, root)
+        };
+        assert_eq!(dump("-2 * 3")?, "(Mul (Neg (Lit 2)) (Lit 3))");
+        assert_eq!(dump("-(3 + 4)")?, "(Neg (Paren (Add (Lit 3) (Lit 4))))");
+        assert_eq!(dump("1 - -2")?, "(Sub (Lit 1) (Neg (Lit 2)))");
+        // repeated negation nests
+        assert_eq!(dump("--5")?, "(Neg (Neg (Lit 5)))");
+        let (arena, root) = parse("--5")?;
+        assert_eq!(ast::eval(&arena, root)?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_statement_sequences() -> Result<()> {
+        let (arena, root) = parse("1; 2 * 3;\n4;")?;
+        assert_eq!(
+            ast::dump(&arena, root)?,
+            "(Seq (Lit 1) (Mul (Lit 2) (Lit 3)) (Lit 4))"
+        );
+        let (arena, root) = parse("42;")?;
+        assert_eq!(ast::dump(&arena, root)?, "(Lit 42)");
+        assert!(parse("1;;2").is_err());
+        Ok(())
+    }
+
+    fn dump(src: &str) -> Result<String> {
+        let (arena, root) = parse(src)?;
+        ast::dump(&arena, root)
+    }
+
+    #[test]
+    fn multiplicative_operators_should_be_left_associative() -> Result<()> {
+        assert_eq!(dump("10 % 3 % 2")?, "(Rem (Rem (Lit 10) (Lit 3)) (Lit 2))");
+        assert_eq!(
+            dump("100 / 3 * 2 % 5")?,
+            "(Rem (Mul (Div (Lit 100) (Lit 3)) (Lit 2)) (Lit 5))"
+        );
+        assert_eq!(
+            dump("7 % 4 * 3 / 2")?,
+            "(Div (Mul (Rem (Lit 7) (Lit 4)) (Lit 3)) (Lit 2))"
+        );
+        assert_eq!(
+            dump("1 + 10 % 4 - 2")?,
+            "(Sub (Add (Lit 1) (Rem (Lit 10) (Lit 4))) (Lit 2))"
+        );
+        assert_eq!(crate::eval_str("10 % 3 % 2")?, 0);
+        assert_eq!(crate::eval_str("100 / 3 * 2 % 5")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn power_should_be_right_associative_and_bind_tighter_than_negation() -> Result<()> {
+        assert_eq!(dump("2 ** 3 ** 2")?, "(Pow (Lit 2) (Pow (Lit 3) (Lit 2)))");
+        assert_eq!(dump("-2 ** 2")?, "(Neg (Pow (Lit 2) (Lit 2)))");
+        assert_eq!(dump("2 * 3 ** 2")?, "(Mul (Lit 2) (Pow (Lit 3) (Lit 2)))");
+        assert_eq!(tokenize("2**10")?, ["2", "**", "10"]);
+        assert_eq!(crate::eval_str("2 ** 3 ** 2")?, 512);
+        assert_eq!(crate::eval_str("-2 ** 2")?, -4);
+        assert_eq!(crate::eval_str("(-2) ** 3")?, -8);
+        // the exponent alone can be negated without parentheses
+        assert_eq!(dump("2 ** -1")?, "(Pow (Lit 2) (Neg (Lit 1)))");
+        assert_eq!(
+            dump("2 ** -3 ** 2")?,
+            "(Pow (Lit 2) (Neg (Pow (Lit 3) (Lit 2))))"
+        );
+        assert_eq!(dump("2 ** !0")?, "(Pow (Lit 2) (Not (Lit 0)))");
+        assert_eq!(crate::eval_str("2 ** -1")?, 0);
+        assert_eq!(crate::eval_str("-1 ** -1 + 2 ** 1")?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn bitwise_operators_should_bind_looser_than_arithmetic() -> Result<()> {
+        assert_eq!(
+            dump("1 | 2 ^ 3 & 4 + 5")?,
+            "(BitOr (Lit 1) (BitXor (Lit 2) (BitAnd (Lit 3) (Add (Lit 4) (Lit 5)))))"
+        );
+        assert_eq!(
+            dump("1 & 2 ^ 3 | 4")?,
+            "(BitOr (BitXor (BitAnd (Lit 1) (Lit 2)) (Lit 3)) (Lit 4))"
+        );
+        assert_eq!(
+            dump("1 | 2 && 3 & 4 || 5")?,
+            "(Or (And (BitOr (Lit 1) (Lit 2)) (BitAnd (Lit 3) (Lit 4))) (Lit 5))"
+        );
+        assert_eq!(
+            dump("(1 | 2) * 3")?,
+            "(Mul (Paren (BitOr (Lit 1) (Lit 2))) (Lit 3))"
+        );
+        assert_eq!(tokenize("a&b^c|d")?, ["a", "&", "b", "^", "c", "|", "d"]);
+        assert_eq!(crate::eval_str("12 & 10 | 1")?, 9);
+        assert_eq!(crate::eval_str("(3 ^ 5) * 2")?, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_reject_reserved_keywords_as_identifiers() {
+        let err = parse("let(1)").unwrap_err();
+        assert_eq!(err.to_string(), "'let' is a reserved keyword");
+        let err = parse("1 + while(2)").unwrap_err();
+        assert_eq!(err.to_string(), "'while' is a reserved keyword");
+        // keywords that start an expression are not blamed for what follows them
+        for src in ["true)", "false)", "break)", "continue)", "if 1 { 2 } )"] {
+            let err = parse(src).unwrap_err();
+            let diag = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diag.code, Some("E0001"), "source: {}", src);
+        }
+    }
+
+    #[test]
+    fn parser_should_accept_raw_identifiers() -> Result<()> {
+        assert_eq!(dump("r#print(1)")?, "(Call print (Lit 1))");
+        assert_eq!(dump("r#let(1)")?, "(Call let (Lit 1))");
+        assert_eq!(tokenize("r#let(1)")?, ["r#let", "(", "1", ")"]);
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_skip_leading_shebang() -> Result<()> {
+        let src = "#!/usr/bin/env bonsaic run\n6 * 7";
+        let (arena, root) = parse(src)?;
+        assert_eq!(ast::dump(&arena, root)?, "(Mul (Lit 6) (Lit 7))");
+        assert_eq!(&src[arena[root].span.clone()], "6 * 7");
+
+        let err = parse("#!/usr/bin/env bonsaic run\n6 *").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(diag.location.as_ref().map(|l| l.line), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_reject_shebang_after_start() {
+        assert!(parse(" #!/usr/bin/env bonsaic run\n1").is_err());
+        assert!(parse("1\n#!/usr/bin/env bonsaic run").is_err());
+    }
+
+    #[test]
+    fn logical_operators_should_bind_looser_than_arithmetic() -> Result<()> {
+        assert_eq!(
+            dump("1 || 0 && 2 + 3")?,
+            "(Or (Lit 1) (And (Lit 0) (Add (Lit 2) (Lit 3))))"
+        );
+        assert_eq!(dump("!0 * 2")?, "(Mul (Not (Lit 0)) (Lit 2))");
+        assert_eq!(dump("!!1")?, "(Not (Not (Lit 1)))");
+        assert_eq!(tokenize("!a && b || c")?, ["!", "a", "&&", "b", "||", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_error_should_report_character_column() -> Result<()> {
+        let err = parse("/* 日本語 */ 6 *").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(diag.location.as_ref().map(|l| l.column), Some(14));
+        Ok(())
+    }
+
+    #[test]
+    fn comparisons_should_bind_between_logical_and_bitwise_operators() -> Result<()> {
+        assert_eq!(
+            dump("1 + 2 < 3 | 4")?,
+            "(Lt (Add (Lit 1) (Lit 2)) (BitOr (Lit 3) (Lit 4)))"
+        );
+        assert_eq!(
+            dump("1 <= 2 && 3 != 4")?,
+            "(And (Le (Lit 1) (Lit 2)) (Ne (Lit 3) (Lit 4)))"
+        );
+        assert_eq!(dump("1>=2")?, "(Ge (Lit 1) (Lit 2))");
+        assert_eq!(dump("1 == !2")?, "(Eq (Lit 1) (Not (Lit 2)))");
+        assert_eq!(
+            tokenize("1<=2>3!=4")?,
+            ["1", "<=", "2", ">", "3", "!=", "4"]
+        );
+        assert_eq!(crate::eval_str("(3 < 5) == (10 == 10)")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_reject_chained_comparisons() -> Result<()> {
+        for src in ["1 < 2 < 3", "1 == 1 != 0", "2 * (1 <= 2 >= 0)"] {
+            let err = parse(src).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "comparison operators cannot be chained",
+                "source: {}",
+                src
+            );
+        }
+        let err = parse("0;\n1 + (1 < 2 < 3)").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(diag.span, Some(8..17));
+        assert_eq!(diag.code, Some("E0007"));
+        // parentheses only move the error: the comparison's bool is not an integer
+        assert!(crate::eval_str("(1 < 2) < 3").is_err());
+        assert_eq!(crate::eval_str("(1 < 2) == (2 < 3)")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_let_statements() -> Result<()> {
+        assert_eq!(
+            dump("let x = 6 * 7; x + 1")?,
+            "(Seq (Let x (Mul (Lit 6) (Lit 7))) (Add (Var x) (Lit 1)))"
+        );
+        assert_eq!(dump("letter")?, "(Var letter)");
+        assert_eq!(dump("let r#if = 1")?, "(Let if (Lit 1))");
+        assert_eq!(tokenize("let x=1")?, ["let", "x", "=", "1"]);
+        for src in ["let = 1", "let x == 1", "1 + let x = 2", "let if = 1"] {
+            assert!(parse(src).is_err(), "source: {}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn let_should_bind_several_names_in_order() -> Result<()> {
+        assert_eq!(
+            dump("let a = 1, b = a + 1; a + b")?,
+            "(Seq (Let a (Lit 1)) (Let b (Add (Var a) (Lit 1))) (Add (Var a) (Var b)))"
+        );
+        assert_eq!(crate::eval_str("let a = 1, b = a + 1; a + b")?, 3);
+        assert_eq!(
+            dump("let mut a = 1, b = 2")?,
+            "(Seq (Let mut a (Lit 1)) (Let b (Lit 2)))"
+        );
+        let src = "let mut s = 0; for i in 0..3 { let a = i, b = a * 2; s = s + b }; s";
+        assert_eq!(crate::eval_str(src)?, 6);
+        // the first binding's span covers the `let`
+        let (arena, root) = parse("let a = 1, b = 2")?;
+        let ast::NodeKind::Seq(stmts) = &arena[root].kind else {
+            return Err(anyhow!("expected a sequence"));
+        };
+        assert_eq!(arena[stmts[0]].span, 0..9);
+        assert_eq!(arena[stmts[1]].span, 11..16);
+
+        let err = parse("let a = 1, a = 2; a").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(diag.message, "'a' is bound twice in the same let");
+        assert_eq!(diag.span, Some(11..16));
+        assert_eq!(diag.code, Some("E0013"));
+        // separate statements may still shadow
+        assert_eq!(crate::eval_str("let a = 1; let a = a + 1; a")?, 2);
+        assert!(parse("let a = 1,").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_assignments() -> Result<()> {
+        assert_eq!(
+            dump("let mut x = 1; x = x * 2")?,
+            "(Seq (Let mut x (Lit 1)) (Assign x (Mul (Var x) (Lit 2))))"
+        );
+        assert_eq!(dump("x == 1")?, "(Eq (Var x) (Lit 1))");
+        assert_eq!(dump("let mutable = 1")?, "(Let mutable (Lit 1))");
+        for src in ["let mut = 1", "1 + x = 2", "(x) = 2"] {
+            assert!(parse(src).is_err(), "source: {}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_function_definitions() -> Result<()> {
+        assert_eq!(
+            dump("fn add(a, b) = a + b; add(1, 2)")?,
+            "(Seq (Fn add (a b) (Add (Var a) (Var b))) (Call add (Lit 1) (Lit 2)))"
+        );
+        assert_eq!(dump("fn one() = 1")?, "(Fn one () (Lit 1))");
+        assert_eq!(
+            dump("fn f(x) = x\nf(2)")?,
+            "(Seq (Fn f (x) (Var x)) (Call f (Lit 2)))"
+        );
+        assert_eq!(dump("fnord")?, "(Var fnord)");
+        for src in [
+            "fn (x) = x",
+            "fn f(x) x",
+            "fn f(1) = 1",
+            "fn f(x, ) = x",
+            "1 + fn f() = 1",
+        ] {
+            assert!(parse(src).is_err(), "source: {}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_bool_literals() -> Result<()> {
+        assert_eq!(
+            dump("true && !false")?,
+            "(And (Lit true) (Not (Lit false)))"
+        );
+        assert_eq!(dump("r#true + truth")?, "(Add (Var true) (Var truth))");
+        assert!(parse("let true = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_conditionals() -> Result<()> {
+        assert_eq!(
+            dump("if a < b { a } else { b }")?,
+            "(If (Lt (Var a) (Var b)) (Var a) (Var b))"
+        );
+        assert_eq!(dump("if x { 1 }")?, "(If (Var x) (Lit 1))");
+        assert_eq!(
+            dump("if x { 1 } else if y { 2 } else { 3 }")?,
+            "(If (Var x) (Lit 1) (If (Var y) (Lit 2) (Lit 3)))"
+        );
+        // a conditional is an operand like any other
+        assert_eq!(
+            dump("1 + if x {\n2\n}\nelse { 3 } * 4")?,
+            "(Add (Lit 1) (Mul (If (Var x) (Lit 2) (Lit 3)) (Lit 4)))"
+        );
+        assert_eq!(dump("iffy")?, "(Var iffy)");
+        for src in [
+            "if x 1 else 2",
+            "if x { 1 } else 2",
+            "if { 1 }",
+            "if x { let y = 1 }",
+        ] {
+            assert!(parse(src).is_err(), "source: {}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_for_loops() -> Result<()> {
+        assert_eq!(
+            dump("for i in 0..n + 1 { print(i); x = x + i }")?,
+            "(For i (Lit 0) (Add (Var n) (Lit 1)) (Call print (Var i)) \
+             (Assign x (Add (Var x) (Var i))))"
+        );
+        assert_eq!(
+            dump("for i in 0..3 {\n  let j = i * 2\n  for k in j..4 {}\n}\n1")?,
+            "(Seq (For i (Lit 0) (Lit 3) (Let j (Mul (Var i) (Lit 2))) (For k (Var j) (Lit 4))) \
+             (Lit 1))"
+        );
+        assert_eq!(dump("format")?, "(Var format)");
+        for src in [
+            "for i in 0..3 print(i)",
+            "for i in 0 { 1 }",
+            "for 1 in 0..3 {}",
+            "for i in 0..3 { fn f() = 1 }",
+            "1 + for i in 0..3 {}",
+        ] {
+            assert!(parse(src).is_err(), "source: {}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_accept_break_and_continue() -> Result<()> {
+        assert_eq!(
+            dump("for i in 0..n { if i > 2 { break } else { continue } }")?,
+            "(For i (Lit 0) (Var n) (If (Gt (Var i) (Lit 2)) (Break) (Continue)))"
+        );
+        // placement is checked after parsing
+        assert_eq!(dump("break; continue")?, "(Seq (Break) (Continue))");
+        assert_eq!(dump("breaking")?, "(Var breaking)");
+        assert!(parse("let break = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_separate_statements_by_newlines() -> Result<()> {
+        assert_eq!(
+            dump("1\n2 * 3\n\n4\n")?,
+            "(Seq (Lit 1) (Mul (Lit 2) (Lit 3)) (Lit 4))"
+        );
+        assert_eq!(dump("1 // one\n2")?, "(Seq (Lit 1) (Lit 2))");
+        assert_eq!(dump("1 /* one */\r\n2;")?, "(Seq (Lit 1) (Lit 2))");
+        assert_eq!(dump("let x = 1\nx")?, "(Seq (Let x (Lit 1)) (Var x))");
+        assert_eq!(dump("1 +\n2")?, "(Add (Lit 1) (Lit 2))");
+        assert_eq!(dump("1\n- 2")?, "(Sub (Lit 1) (Lit 2))");
+        assert_eq!(
+            dump("let y = x\n(1 + 2)")?,
+            "(Seq (Let y (Var x)) (Paren (Add (Lit 1) (Lit 2))))"
+        );
+        assert_eq!(
+            dump("f (1)\nf(\n2\n)")?,
+            "(Seq (Call f (Lit 1)) (Call f (Lit 2)))"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_reject_tuples() -> Result<()> {
+        assert_eq!(dump("(1)")?, "(Paren (Lit 1))");
+        for src in ["(1, 2)", "3 * (1, 2, 3)", "print((1, 2))", "(1, 2,)"] {
+            let err = parse(src).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "tuples are not supported",
+                "source: {}",
+                src
+            );
+        }
+        let err = parse("1 + (2, 3)").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(diag.span, Some(4..10));
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_tell_comment_only_files_from_empty_ones() -> Result<()> {
+        let message = |src: &str| parse(src).unwrap_err().to_string();
+        assert_eq!(message(""), "program is empty");
+        assert_eq!(message(" \n\t\r\n"), "program is empty");
+        assert_eq!(
+            message("// note"),
+            "program contains no expression, only comments"
+        );
+        assert_eq!(
+            message("/* a */\n#line 3 \"x.bonsai\"\n// b\n"),
+            "program contains no expression, only comments"
+        );
+        // an unterminated comment is a parse error, not a comment
+        assert!(message("/* note").starts_with("failed to parse"));
+        let (arena, root) = parse("// note\n6 * 7 // answer")?;
+        assert_eq!(ast::eval(&arena, root)?, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_expand_defines() -> Result<()> {
+        let src = "define SIX 2 * 3\ndefine ANSWER SIX * 7\nANSWER + 1";
+        assert_eq!(
+            dump(src)?,
+            "(Add (Paren (Mul (Paren (Mul (Lit 2) (Lit 3))) (Lit 7))) (Lit 1))"
+        );
+        let (arena, root) = parse(src)?;
+        let ast::NodeKind::BiOp(_, lhs, rhs) = arena[root].kind else {
+            panic!("expected an addition");
+        };
+        assert_eq!(&src[arena[lhs].span.clone()], "ANSWER");
+        assert_eq!(&src[arena[rhs].span.clone()], "1");
+
+        // errors after a substitution still point into the source
+        let err = parse("define N 1\nN + * 2").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(diag.span, Some(15..16));
+        Ok(())
+    }
+
+    #[test]
+    fn parser_should_skip_comments() -> Result<()> {
+        let (arena, root) = parse("// answer\n6 /* six */ * 7 // seven")?;
+        assert_eq!(ast::dump(&arena, root)?, "(Mul (Lit 6) (Lit 7))");
+        assert!(parse("6 /* unterminated * 7").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn spans_should_exclude_interior_comments() -> Result<()> {
+        let src = "  6 /* six */ * 7";
+        let (arena, root) = parse(src)?;
+        let ast::NodeKind::BiOp(ast::BiOpKind::Mul, lhs, rhs) = arena[root].kind else {
+            panic!("expected a multiplication");
+        };
+        assert_eq!(&src[arena[root].span.clone()], "6 /* six */ * 7");
+        assert_eq!(&src[arena[lhs].span.clone()], "6");
+        assert_eq!(&src[arena[rhs].span.clone()], "7");
+        Ok(())
+    }
+
+    #[test]
+    fn spans_should_exclude_leading_comments() -> Result<()> {
+        let src = "/* a */ // b\n (1 + 2)";
+        let (arena, root) = parse(src)?;
+        assert_eq!(&src[arena[root].span.clone()], "(1 + 2)");
+        Ok(())
+    }
+
+    fn deepest_nesting() -> String {
+        let depth = MAX_NESTING_DEPTH;
+        format!("{}1{}", "(".repeat(depth), ")".repeat(depth))
+    }
+
+    #[test]
+    fn parse_with_stack_size_should_handle_deep_nesting() -> Result<()> {
+        let (arena, root) = parse_with_stack_size(&deepest_nesting(), 256 * 1024 * 1024)?;
+        assert!(matches!(arena[root].kind, ast::NodeKind::Paren(_)));
+
+        // the same input overflows a small stack, which aborts the process, so check that
+        // in a child running the test below
+        let output = std::process::Command::new(std::env::current_exe()?)
+            .args([
+                "--ignored",
+                "--exact",
+                "parser::tests::deep_nesting_should_overflow_small_stack",
+            ])
+            .output()?;
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("'bonsai-parser' has overflowed its stack"),
+            "{}",
+            stderr
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "aborts; run by parse_with_stack_size_should_handle_deep_nesting"]
+    fn deep_nesting_should_overflow_small_stack() {
+        let _ = parse_with_stack_size(&deepest_nesting(), 64 * 1024);
+    }
+
+    #[test]
+    fn parser_should_reject_too_deep_nesting() {
+        let src = format!("{}1{}", "(".repeat(1000), ")".repeat(1000));
+        assert!(parse(&src).is_err());
+    }
+
+    #[test]
+    fn parser_should_not_count_brackets_in_comments_and_strings() -> Result<()> {
+        let deep = "(".repeat(1000);
+        parse(&format!("/* {} */ 1 // {}\n+ 2", deep, deep))?;
+        parse(&format!("env(\"{}\")", deep))?;
+        // brackets after the comment closes still count
+        let src = format!("/* ( */ {}1{}", deep, ")".repeat(1000));
+        assert!(parse(&src).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn line_directive_should_remap_error_location() {
+        let src = "1 +\n#line 100 \"orig.bon\"\n2 * ";
+        let err = parse(src).unwrap_err();
+        let diag = err.downcast_ref::<Diagnostic>().unwrap();
+        let location = diag.location.as_ref().unwrap();
+        assert_eq!(location.file.as_deref(), Some("orig.bon"));
+        assert_eq!(location.line, 100);
+        assert_eq!(location.column, 5);
+
+        // an error on the directive's own line keeps its physical location
+        for src in ["#line 0 \"a\" )", "#line 18446744073709551615 \"a\" )"] {
+            let err = parse(src).unwrap_err();
+            let diag = err.downcast_ref::<Diagnostic>().unwrap();
+            let location = diag.location.as_ref().unwrap();
+            assert_eq!((location.file.as_deref(), location.line), (None, 1));
+        }
+    }
+
+    #[test]
+    fn fuzz_parse_should_not_panic_on_garbage() {
+        let mut inputs: Vec<Vec<u8>> = [
+            "", " ", "(", ")", "()", "+", "1 +", "* 2", "1 2", "((1)", "1))",
+            "18446744073709551616", "\u{0}", "\u{feff}1", "1 / / 2", "--1",
+        ]
+        .iter()
+        .map(|s| s.as_bytes().to_vec())
+        .collect();
+        inputs.push(vec![0xff, 0xfe, b'1', 0x80]);
+
+        // deterministic pseudo-random inputs drawn from the grammar's alphabet and beyond
+        let alphabet = b"0123456789+-*/() \t\n\xffx";
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..500 {
+            let len = (next() % 32) as usize;
+            let input = (0..len)
+                .map(|_| alphabet[(next() % alphabet.len() as u64) as usize])
+                .collect();
+            inputs.push(input);
+        }
+
+        for input in inputs {
+            let _ = fuzz_parse(&input);
+        }
+    }
 }
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: Unlicense
+use crate::ir;
+use anyhow::Result;
+
+/// A transformation over the IR, run by the driver between irgen and codegen.
+pub trait Pass {
+    /// Transforms the tree rooted at `root` and returns the new root.
+    fn run(&self, arena: &mut ir::Arena, root: ir::Id) -> Result<ir::Id>;
+
+    /// The name used to refer to the pass on the command line, e.g. in `--after=NAME`.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
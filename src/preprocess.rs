@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: Unlicense
+//! Expands `define NAME expr` lines before parsing.
+//!
+//! A define occupies a whole line and substitutes the parenthesized `expr` for every
+//! later use of `NAME`. Defines take no parameters and must come before their uses.
+use crate::diagnostic::Diagnostic;
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+/// A run of expanded text and the source range it came from.
+///
+/// Copied runs have ranges of equal length; a substituted run maps to the use of the
+/// name it replaced.
+#[derive(Debug)]
+struct Segment {
+    expanded: Range<usize>,
+    original: Range<usize>,
+    substituted: bool,
+}
+
+/// The expanded source along with what is needed to map offsets back.
+#[derive(Debug)]
+pub struct Expansion {
+    pub text: String,
+    segments: Vec<Segment>,
+}
+
+impl Expansion {
+    /// Maps an offset in the expanded text back to the source. `end` selects the end of
+    /// a substituted use instead of its start.
+    pub fn to_original(&self, offset: usize, end: bool) -> usize {
+        let segment = self
+            .segments
+            .iter()
+            .find(|s| s.expanded.contains(&offset) || (end && s.expanded.end == offset));
+        match segment {
+            Some(s) if s.substituted => {
+                if end {
+                    s.original.end
+                } else {
+                    s.original.start
+                }
+            }
+            Some(s) => s.original.start + (offset - s.expanded.start),
+            None => match self.segments.last() {
+                Some(last) => last.original.end + offset.saturating_sub(last.expanded.end),
+                None => offset,
+            },
+        }
+    }
+
+    pub fn to_original_span(&self, span: &Range<usize>) -> Range<usize> {
+        let start = self.to_original(span.start, false);
+        start..self.to_original(span.end, true).max(start)
+    }
+
+    fn copy(&mut self, source: &str, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let start = self.text.len();
+        self.text.push_str(&source[range.clone()]);
+        self.segments.push(Segment {
+            expanded: start..self.text.len(),
+            original: range,
+            substituted: false,
+        });
+    }
+
+    fn substitute(&mut self, body: &str, original: Range<usize>) {
+        let start = self.text.len();
+        self.text.push('(');
+        self.text.push_str(body);
+        self.text.push(')');
+        self.segments.push(Segment {
+            expanded: start..self.text.len(),
+            original,
+            substituted: true,
+        });
+    }
+}
+
+/// A `define` line: the span of its name and of its body.
+struct Define {
+    line: Range<usize>,
+    name: Range<usize>,
+    body: Range<usize>,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident_continue(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Recognizes `define NAME expr` on the line starting at `start`.
+fn parse_define(source: &str, start: usize) -> Option<Define> {
+    let end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    let line = &source[start..end];
+    let indent = line.len() - line.trim_start_matches([' ', '\t']).len();
+    let rest = line[indent..].strip_prefix("define")?;
+    let gap = rest.len() - rest.trim_start_matches([' ', '\t']).len();
+    if gap == 0 {
+        return None;
+    }
+    let name_start = start + indent + "define".len() + gap;
+    if !source[name_start..end].starts_with(is_ident_start) {
+        return None;
+    }
+    let name_len = source[name_start..end]
+        .find(|c: char| !is_ident_continue(c))
+        .unwrap_or(end - name_start);
+    let name = name_start..name_start + name_len;
+    let body = &source[name.end..end];
+    let body = body[..body.find("//").unwrap_or(body.len())].trim_end_matches('\r');
+    let body_start = name.end + (body.len() - body.trim_start_matches([' ', '\t']).len());
+    let body_end = name.end + body.trim_end_matches([' ', '\t']).len();
+    if body_start >= body_end {
+        return None;
+    }
+    Some(Define {
+        line: start..end,
+        name,
+        body: body_start..body_end,
+    })
+}
+
+/// Returns the length of the literal or comment at the start of `rest`, which
+/// substitution leaves alone.
+fn skip_literal(rest: &str) -> Option<usize> {
+    if rest.starts_with(|c: char| c.is_ascii_digit()) {
+        Some(
+            rest.find(|c: char| !is_ident_continue(c))
+                .unwrap_or(rest.len()),
+        )
+    } else if let Some(s) = rest.strip_prefix('"') {
+        Some(s.find(['"', '\n']).map_or(rest.len(), |i| i + 2))
+    } else if rest.starts_with("//") {
+        Some(rest.find('\n').unwrap_or(rest.len()))
+    } else if let Some(s) = rest.strip_prefix("/*") {
+        Some(s.find("*/").map_or(rest.len(), |i| i + 4))
+    } else {
+        None
+    }
+}
+
+/// Expands the defines in `source`. Returns `None` when there are none, so the source
+/// can be parsed as is.
+pub fn expand(source: &str) -> Result<Option<Expansion>> {
+    let mut line_starts = vec![0];
+    line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+    let defines: Vec<Define> = line_starts
+        .into_iter()
+        .filter(|&start| start < source.len())
+        .filter_map(|start| parse_define(source, start))
+        .collect();
+    let defines = reached_defines(source, defines);
+    if defines.is_empty() {
+        return Ok(None);
+    }
+
+    let mut seen = HashSet::new();
+    for define in &defines {
+        let name = &source[define.name.clone()];
+        if !seen.insert(name) {
+            return Err(Diagnostic::error(format!("'{}' is already defined", name))
+                .with_span(define.name.clone())
+                .with_code("E0005")
+                .into());
+        }
+    }
+
+    let mut expansion = Expansion {
+        text: String::with_capacity(source.len()),
+        segments: vec![],
+    };
+    let mut bodies: HashMap<&str, String> = HashMap::new();
+    let mut copied = 0;
+    let mut offset = 0;
+    let mut defines = defines.iter().peekable();
+    while offset < source.len() {
+        if let Some(define) = defines.next_if(|d| d.line.start == offset) {
+            // keep the line's extent so offsets of later lines stay close to the source
+            expansion.copy(source, copied..offset);
+            let body = expand_body(source, define, &bodies, &seen)?;
+            let blank = " ".repeat(define.line.len());
+            let start = expansion.text.len();
+            expansion.text.push_str(&blank);
+            expansion.segments.push(Segment {
+                expanded: start..expansion.text.len(),
+                original: define.line.clone(),
+                substituted: false,
+            });
+            bodies.insert(&source[define.name.clone()], body);
+            offset = define.line.end;
+            copied = offset;
+            continue;
+        }
+        let rest = &source[offset..];
+        if let Some(len) = skip_literal(rest) {
+            offset += len;
+        } else if rest.starts_with(is_ident_start) && !source[..offset].ends_with("r#") {
+            let len = rest
+                .find(|c: char| !is_ident_continue(c))
+                .unwrap_or(rest.len());
+            let name = &rest[..len];
+            let call = source[offset + len..].starts_with('(');
+            if let Some(body) = bodies.get(name).filter(|_| !call) {
+                expansion.copy(source, copied..offset);
+                expansion.substitute(body, offset..offset + len);
+                copied = offset + len;
+            } else if seen.contains(name) && !call {
+                return Err(use_before_define(name, offset..offset + len));
+            }
+            offset += len;
+        } else {
+            offset += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    expansion.copy(source, copied..source.len());
+    Ok(Some(expansion))
+}
+
+/// Drops the defines that a multi-line comment swallows, which are not defines, by
+/// scanning `source` the way [`expand`] does.
+fn reached_defines(source: &str, defines: Vec<Define>) -> Vec<Define> {
+    let mut reached = vec![];
+    let mut offset = 0;
+    let mut defines = defines.into_iter().peekable();
+    while offset < source.len() {
+        while defines.next_if(|d| d.line.start < offset).is_some() {}
+        if let Some(define) = defines.next_if(|d| d.line.start == offset) {
+            offset = define.line.end;
+            reached.push(define);
+            continue;
+        }
+        let rest = &source[offset..];
+        if let Some(len) = skip_literal(rest) {
+            offset += len;
+        } else if rest.starts_with(is_ident_start) {
+            offset += rest
+                .find(|c: char| !is_ident_continue(c))
+                .unwrap_or(rest.len());
+        } else {
+            offset += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    reached
+}
+
+/// Substitutes earlier defines into the body of `define`.
+fn expand_body(
+    source: &str,
+    define: &Define,
+    bodies: &HashMap<&str, String>,
+    seen: &HashSet<&str>,
+) -> Result<String> {
+    let mut body = String::new();
+    let mut offset = define.body.start;
+    while offset < define.body.end {
+        let rest = &source[offset..define.body.end];
+        if let Some(len) = skip_literal(rest) {
+            body.push_str(&rest[..len]);
+            offset += len;
+        } else if rest.starts_with(is_ident_start) && !source[..offset].ends_with("r#") {
+            let len = rest
+                .find(|c: char| !is_ident_continue(c))
+                .unwrap_or(rest.len());
+            let name = &rest[..len];
+            let call = rest[len..].starts_with('(');
+            match bodies.get(name).filter(|_| !call) {
+                Some(expanded) => {
+                    body.push('(');
+                    body.push_str(expanded);
+                    body.push(')');
+                }
+                None if seen.contains(name) && !call => {
+                    return Err(use_before_define(name, offset..offset + len));
+                }
+                None => body.push_str(name),
+            }
+            offset += len;
+        } else {
+            let c = rest.chars().next().unwrap_or(' ');
+            body.push(c);
+            offset += c.len_utf8();
+        }
+    }
+    Ok(body)
+}
+
+fn use_before_define(name: &str, span: Range<usize>) -> anyhow::Error {
+    Diagnostic::error(format!("'{}' is used before its definition", name))
+        .with_span(span)
+        .with_code("E0006")
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_should_substitute_defined_names() -> Result<()> {
+        let expansion = expand("define N 6\nN * 7")?.expect("a define");
+        assert_eq!(expansion.text, "          \n(6) * 7");
+        // the substituted text maps back to the use of the name
+        assert_eq!(expansion.to_original_span(&(11..14)), 11..12);
+        assert_eq!(expansion.to_original_span(&(17..18)), 15..16);
+        Ok(())
+    }
+
+    #[test]
+    fn expand_should_leave_sources_without_defines_alone() -> Result<()> {
+        assert!(expand("1 + define(2)")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn expand_should_skip_strings_and_comments() -> Result<()> {
+        let expansion = expand("define N 1\nenv(\"N\") // N\n+ 0xN + N")?.expect("a define");
+        assert_eq!(expansion.text, "          \nenv(\"N\") // N\n+ 0xN + (1)");
+        Ok(())
+    }
+
+    #[test]
+    fn expand_should_reject_use_before_define() {
+        let err = expand("N + 1\ndefine N 2").unwrap_err();
+        let diag = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diag.message, "'N' is used before its definition");
+        assert_eq!(diag.span, Some(0..1));
+        assert_eq!(diag.code, Some("E0006"));
+    }
+
+    #[test]
+    fn expand_should_reject_redefinition() {
+        let err = expand("define N 1\ndefine N 2\nN").unwrap_err();
+        let diag = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diag.message, "'N' is already defined");
+        assert_eq!(diag.span, Some(18..19));
+        assert_eq!(diag.code, Some("E0005"));
+    }
+
+    #[test]
+    fn expand_should_ignore_defines_inside_block_comments() -> Result<()> {
+        // neither a redefinition nor a use before the definition
+        let expansion = expand("/*\ndefine N 1\n*/\ndefine N 2\nN")?.expect("a define");
+        assert!(expansion.text.ends_with("\n(2)"));
+        let expansion = expand("N\n/*\ndefine N 1\n*/")?;
+        assert!(expansion.is_none());
+        Ok(())
+    }
+}
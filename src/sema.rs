@@ -0,0 +1,395 @@
+// SPDX-License-Identifier: Unlicense
+use crate::{ast, diagnostic::Diagnostic};
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, fmt};
+
+fn node(arena: &ast::Arena, id: ast::Id) -> Result<&ast::Node> {
+    arena
+        .get(id)
+        .ok_or(anyhow!("failed to get ast node from arena"))
+}
+
+/// The node binding a name.
+#[derive(Clone)]
+enum Binding {
+    /// A `let` with its span and whether it is `mut`.
+    Let(ast::Span, bool),
+    /// The variable of the `for` loop with this span.
+    Loop(ast::Span),
+}
+
+/// The types of expressions. Bools are lowered to 0 or 1, but cannot be used where an
+/// integer is expected or the other way around.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Int,
+    Bool,
+    /// `break`, `continue` and unknown names, which fit anywhere: the first two never
+    /// finish, and lowering reports the others.
+    Any,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "an integer"),
+            Type::Bool => write!(f, "a bool"),
+            Type::Any => write!(f, "anything"),
+        }
+    }
+}
+
+/// The types of the names visible to an expression.
+#[derive(Clone, Default)]
+struct Scope<'a> {
+    vars: HashMap<&'a str, Type>,
+    /// The result types of the functions defined so far.
+    fns: HashMap<&'a str, Type>,
+}
+
+/// Checks that assignments only target `let mut` bindings, that `break` and
+/// `continue` only appear in loop bodies, and that bools and integers are not mixed.
+///
+/// Unknown names are left to lowering, which reports them.
+pub fn check(arena: &ast::Arena, root: ast::Id) -> Result<()> {
+    let stmts = match &node(arena, root)?.kind {
+        ast::NodeKind::Seq(stmts) => stmts.clone(),
+        _ => vec![root],
+    };
+    check_stmts(arena, &stmts, HashMap::new())?;
+    check_loop_exits(arena, root, false)?;
+    check_types(arena, &stmts, Scope::default())
+}
+
+/// Checks that the tree rooted at `id` only uses `break` and `continue` where
+/// `in_loop` says there is a loop around them.
+fn check_loop_exits(arena: &ast::Arena, id: ast::Id, in_loop: bool) -> Result<()> {
+    let node = node(arena, id)?;
+    match &node.kind {
+        ast::NodeKind::Break | ast::NodeKind::Continue if !in_loop => {
+            let keyword = match node.kind {
+                ast::NodeKind::Break => "break",
+                _ => "continue",
+            };
+            return Err(
+                Diagnostic::error(format!("`{}` outside of a loop", keyword))
+                    .with_span(node.span.clone())
+                    .with_code("E0009")
+                    .into(),
+            );
+        }
+        // the bounds are evaluated before the loop starts
+        ast::NodeKind::For(_, start, end, body) => {
+            check_loop_exits(arena, *start, in_loop)?;
+            check_loop_exits(arena, *end, in_loop)?;
+            for &stmt in body {
+                check_loop_exits(arena, stmt, true)?;
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    for child in ast::children(&node.kind) {
+        check_loop_exits(arena, child, in_loop)?;
+    }
+    Ok(())
+}
+
+/// Checks `stmts` given the bindings of the names visible before them.
+fn check_stmts<'a>(
+    arena: &'a ast::Arena,
+    stmts: &[ast::Id],
+    mut bindings: HashMap<&'a str, Binding>,
+) -> Result<()> {
+    for &stmt in stmts {
+        let node = node(arena, stmt)?;
+        match &node.kind {
+            ast::NodeKind::Let(name, mutable, _) => {
+                bindings.insert(name, Binding::Let(node.span.clone(), *mutable));
+            }
+            ast::NodeKind::Assign(name, _) => {
+                let (binding, label) = match bindings.get(name.as_str()) {
+                    Some(Binding::Let(span, false)) => (
+                        span,
+                        format!("first assignment; make it `let mut {}`", name),
+                    ),
+                    Some(Binding::Loop(span)) => {
+                        (span, format!("'{}' is bound by this loop", name))
+                    }
+                    _ => continue,
+                };
+                let message = format!("cannot assign twice to immutable variable '{}'", name);
+                return Err(Diagnostic::error(message)
+                    .with_span(node.span.clone())
+                    .with_code("E0008")
+                    .with_label(binding.clone(), label)
+                    .into());
+            }
+            // the body's bindings end with it
+            ast::NodeKind::For(name, _, _, body) => {
+                let mut inner = bindings.clone();
+                inner.insert(name, Binding::Loop(node.span.clone()));
+                check_stmts(arena, body, inner)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks the types of `stmts` given the names visible before them.
+fn check_types<'a>(arena: &'a ast::Arena, stmts: &[ast::Id], mut scope: Scope<'a>) -> Result<()> {
+    for &stmt in stmts {
+        match &node(arena, stmt)?.kind {
+            ast::NodeKind::Let(name, _, value) => {
+                let ty = type_of(arena, *value, &scope)?;
+                scope.vars.insert(name, ty);
+            }
+            ast::NodeKind::Assign(name, value) => {
+                let ty = scope.vars.get(name.as_str()).copied().unwrap_or(Type::Any);
+                expect(arena, *value, ty, &scope)?;
+            }
+            ast::NodeKind::For(name, start, end, body) => {
+                expect(arena, *start, Type::Int, &scope)?;
+                expect(arena, *end, Type::Int, &scope)?;
+                let mut inner = scope.clone();
+                inner.vars.insert(name, Type::Int);
+                check_types(arena, body, inner)?;
+            }
+            // parameters are integers; recursive calls fit until the body's type is known
+            ast::NodeKind::Fn(name, params, body) => {
+                let mut inner = Scope {
+                    vars: params.iter().map(|p| (p.as_str(), Type::Int)).collect(),
+                    fns: scope.fns.clone(),
+                };
+                inner.fns.insert(name, Type::Any);
+                let ty = type_of(arena, *body, &inner)?;
+                scope.fns.insert(name, ty);
+            }
+            _ => {
+                type_of(arena, stmt, &scope)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the expression `id` has type `expected`, returning its type.
+fn expect(arena: &ast::Arena, id: ast::Id, expected: Type, scope: &Scope) -> Result<Type> {
+    let found = type_of(arena, id, scope)?;
+    agree(arena, id, expected, found)
+}
+
+/// Checks that the expression `id`, of type `found`, can be used as `expected`.
+fn agree(arena: &ast::Arena, id: ast::Id, expected: Type, found: Type) -> Result<Type> {
+    if found == expected || found == Type::Any || expected == Type::Any {
+        return Ok(found);
+    }
+    Err(
+        Diagnostic::error(format!("expected {}, found {}", expected, found))
+            .with_span(node(arena, id)?.span.clone())
+            .with_code("E0014")
+            .into(),
+    )
+}
+
+/// Returns the type of the expression `id`, checking its operands.
+fn type_of(arena: &ast::Arena, id: ast::Id, scope: &Scope) -> Result<Type> {
+    let ty = match &node(arena, id)?.kind {
+        ast::NodeKind::Lit(ast::LitKind::BoolLit(_)) => Type::Bool,
+        ast::NodeKind::Lit(_) => Type::Int,
+        ast::NodeKind::Paren(e) => type_of(arena, *e, scope)?,
+        ast::NodeKind::UnOp(ast::UnOpKind::Not, e) => {
+            expect(arena, *e, Type::Bool, scope)?;
+            Type::Bool
+        }
+        ast::NodeKind::UnOp(ast::UnOpKind::Neg, e) => {
+            expect(arena, *e, Type::Int, scope)?;
+            Type::Int
+        }
+        ast::NodeKind::BiOp(op, lhs, rhs) => {
+            let lhs_ty = type_of(arena, *lhs, scope)?;
+            let operand = match op {
+                // either type, as long as both sides agree
+                ast::BiOpKind::Eq | ast::BiOpKind::Ne => lhs_ty,
+                ast::BiOpKind::And | ast::BiOpKind::Or => Type::Bool,
+                _ => Type::Int,
+            };
+            agree(arena, *lhs, operand, lhs_ty)?;
+            expect(arena, *rhs, operand, scope)?;
+            match op {
+                ast::BiOpKind::Eq
+                | ast::BiOpKind::Ne
+                | ast::BiOpKind::Lt
+                | ast::BiOpKind::Le
+                | ast::BiOpKind::Gt
+                | ast::BiOpKind::Ge
+                | ast::BiOpKind::And
+                | ast::BiOpKind::Or => Type::Bool,
+                _ => Type::Int,
+            }
+        }
+        ast::NodeKind::Call(name, args) => {
+            let (param, result) = match (scope.fns.get(name.as_str()), name.as_str()) {
+                (Some(&result), _) => (Type::Int, result),
+                // the argument names a variable of the environment
+                (None, "env") => return Ok(Type::Int),
+                (None, "print" | "print_uint") => (Type::Any, Type::Int),
+                (None, "assert") => (Type::Bool, Type::Int),
+                (None, "debug_print") => match args.first() {
+                    Some(&arg) => return type_of(arena, arg, scope),
+                    None => return Ok(Type::Any),
+                },
+                (None, "read_int" | "abs" | "pow_mod") => (Type::Int, Type::Int),
+                (None, _) => (Type::Any, Type::Any),
+            };
+            for &arg in args {
+                expect(arena, arg, param, scope)?;
+            }
+            result
+        }
+        ast::NodeKind::Var(name) => scope.vars.get(name.as_str()).copied().unwrap_or(Type::Any),
+        ast::NodeKind::If(cond, then, otherwise) => {
+            expect(arena, *cond, Type::Bool, scope)?;
+            let ty = type_of(arena, *then, scope)?;
+            match otherwise {
+                // the other branch must agree unless this one never finishes
+                Some(otherwise) => match ty {
+                    Type::Any => type_of(arena, *otherwise, scope)?,
+                    _ => {
+                        expect(arena, *otherwise, ty, scope)?;
+                        ty
+                    }
+                },
+                // false or 0 when the condition does not hold
+                None => ty,
+            }
+        }
+        ast::NodeKind::Break | ast::NodeKind::Continue => Type::Any,
+        // statements, which check_types handles where they can appear
+        ast::NodeKind::Seq(_)
+        | ast::NodeKind::Let(..)
+        | ast::NodeKind::Assign(..)
+        | ast::NodeKind::Fn(..)
+        | ast::NodeKind::For(..) => Type::Any,
+    };
+    Ok(ty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn check_source(src: &str) -> Result<()> {
+        let (arena, root) = parser::parse(src)?;
+        check(&arena, root)
+    }
+
+    #[test]
+    fn check_should_allow_assigning_mutable_bindings() -> Result<()> {
+        check_source("let mut x = 1; x = x + 1; x")?;
+        // a later `let mut` shadows the immutable binding
+        check_source("let x = 1; let mut x = x; x = 2")?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_should_reject_assigning_immutable_bindings() -> Result<()> {
+        let err = check_source("let x = 1;\nx = 2").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(
+            diag.message,
+            "cannot assign twice to immutable variable 'x'"
+        );
+        assert_eq!(diag.span, Some(11..16));
+        assert_eq!(diag.code, Some("E0008"));
+        assert_eq!(diag.labels[0].span, 0..9);
+
+        // shadowing with an immutable binding takes mutability away
+        assert!(check_source("let mut x = 1; let x = x; x = 2").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_should_reject_assigning_loop_variables() -> Result<()> {
+        check_source("let mut x = 0; for i in 0..3 { x = x + i; let mut i = i; i = 0 }")?;
+        // the shadowing ends with the body
+        assert!(check_source("let x = 0; for i in 0..3 { let mut x = i; x = 1 }; x = 2").is_err());
+
+        let err = check_source("for i in 0..3 { i = 0 }").unwrap_err();
+        let diag = err
+            .downcast_ref::<Diagnostic>()
+            .ok_or(anyhow!("expected a diagnostic"))?;
+        assert_eq!(
+            diag.message,
+            "cannot assign twice to immutable variable 'i'"
+        );
+        assert_eq!(diag.span, Some(16..21));
+        assert_eq!(diag.labels[0].span, 0..23);
+        Ok(())
+    }
+
+    #[test]
+    fn check_should_only_allow_break_and_continue_in_loops() -> Result<()> {
+        check_source("for i in 0..3 { if i > 1 { break }; for j in 0..i { continue } }")?;
+        for (src, message, span) in [
+            ("1;\nbreak", "`break` outside of a loop", 3..8),
+            ("fn f() = continue", "`continue` outside of a loop", 9..17),
+            (
+                "for i in 0..if x { break } else { 1 } {}",
+                "`break` outside of a loop",
+                19..24,
+            ),
+        ] {
+            let err = check_source(src).unwrap_err();
+            let diag = err
+                .downcast_ref::<Diagnostic>()
+                .ok_or(anyhow!("expected a diagnostic"))?;
+            assert_eq!(diag.message, message, "source: {}", src);
+            assert_eq!(diag.span, Some(span), "source: {}", src);
+            assert_eq!(diag.code, Some("E0009"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_should_allow_bools_where_bools_are_expected() -> Result<()> {
+        check_source("let b = 1 < 2; if b && !false { 1 } else { 2 }")?;
+        check_source("true == (1 <= 1); let mut t = true; t = 2 > 1; print(t, 3)")?;
+        check_source("fn odd(n) = n > 0 && !odd(n - 1); if odd(7) { 1 }")?;
+        check_source("for i in 0..3 { if i > 1 { break } }; debug_print(true) || false")?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_should_reject_mixing_bools_and_integers() -> Result<()> {
+        let int_for_bool = "expected a bool, found an integer";
+        let bool_for_int = "expected an integer, found a bool";
+        for (src, message, span) in [
+            ("true + 1", bool_for_int, 0..4),
+            ("if 5 { 1 }", int_for_bool, 3..4),
+            ("1 && true", int_for_bool, 0..1),
+            ("!read_int()", int_for_bool, 1..11),
+            ("let b = 1 < 2; -b", bool_for_int, 16..17),
+            ("let mut x = 1; x = true", bool_for_int, 19..23),
+            ("1 == false", bool_for_int, 5..10),
+            ("if true { 1 } else { false }", bool_for_int, 21..26),
+            ("fn f(n) = n < 1; f(2) * 3", bool_for_int, 17..21),
+            ("fn f(n) = n; f(true)", bool_for_int, 15..19),
+            ("assert(1)", int_for_bool, 7..8),
+            ("for i in 0..true {}", bool_for_int, 12..16),
+        ] {
+            let err = check_source(src).unwrap_err();
+            let diag = err
+                .downcast_ref::<Diagnostic>()
+                .ok_or(anyhow!("expected a diagnostic"))?;
+            assert_eq!(diag.message, message, "source: {}", src);
+            assert_eq!(diag.span, Some(span), "source: {}", src);
+            assert_eq!(diag.code, Some("E0014"));
+        }
+        Ok(())
+    }
+}
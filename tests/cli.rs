@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: Unlicense
+use anyhow::Result;
+use std::process::{Command, Stdio};
+
+fn bonsaic(args: &[&str]) -> Result<std::process::Output> {
+    Ok(Command::new(env!("CARGO_BIN_EXE_bonsaic"))
+        .args(args)
+        .output()?)
+}
+
+#[test]
+fn eval_should_compile_and_run_its_argument() -> Result<()> {
+    let output = bonsaic(&["--eval", "6 * 7"])?;
+    assert_eq!(String::from_utf8(output.stdout)?, "result: 42\n");
+    // bonsaic exits with the program's status, which is its result
+    assert_eq!(output.status.code(), Some(42));
+
+    let output = bonsaic(&["--quiet-runtime", "--eval", "print(1, 2); 3"])?;
+    assert_eq!(String::from_utf8(output.stdout)?, "1 2\n3\n");
+    Ok(())
+}
+
+#[test]
+fn concurrent_evals_should_not_share_an_executable() -> Result<()> {
+    let children = (1..=8)
+        .map(|i| {
+            Command::new(env!("CARGO_BIN_EXE_bonsaic"))
+                .args(["--quiet-runtime", "--eval", &format!("{} * 7", i)])
+                .stdout(Stdio::piped())
+                .spawn()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (i, child) in (1..=8).zip(children) {
+        let output = child.wait_with_output()?;
+        assert_eq!(String::from_utf8(output.stdout)?, format!("{}\n", i * 7));
+    }
+    Ok(())
+}
+
+#[test]
+fn eval_should_report_errors_against_its_argument() -> Result<()> {
+    let output = bonsaic(&["--color=never", "--eval", "6 *"])?;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)?.starts_with("error[E0001]"));
+
+    let output = bonsaic(&["--eval"])?;
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr)?,
+        "--eval requires a program\n"
+    );
+    Ok(())
+}